@@ -1,45 +1,7 @@
-use davisjr::prelude::*;
-
-// We'll use authstate to (optionally) capture information about the token
-// being correct. if it is Some(true), the user was authed, if None, there was no
-// authentication performed.
-#[derive(Clone)]
-struct AuthedState {
-    authed: Option<bool>,
-}
-
-// All transient state structs must have an initial state, which will be
-// initialized internally in the router.
-impl TransientState for AuthedState {
-    fn initial() -> Self {
-        Self { authed: None }
-    }
-}
-
-// our authtoken validator, this queries the app state and the header
-// `X-AuthToken` and compares the two. If there are any discrepancies, it
-// returns `401 Unauthorized`.
-//
-// every handler & middleware takes and returns the same params and has the
-// same prototype.
-//
-async fn validate_authtoken(
-    req: Request<Body>,
-    resp: Option<Response<Body>>,
-    _params: Params,
-    app: App<State, AuthedState>,
-    mut authstate: AuthedState,
-) -> HTTPResult<AuthedState> {
-    if let (Some(token), Some(state)) = (req.headers().get("X-AuthToken"), app.state().await) {
-        authstate.authed = Some(state.clone().lock().await.authtoken == token);
-        Ok((req, resp, authstate))
-    } else {
-        Err(Error::StatusCode(
-            StatusCode::UNAUTHORIZED,
-            String::default(),
-        ))
-    }
-}
+use davisjr::{
+    auth::{constant_time_eq, BearerAuth},
+    prelude::*,
+};
 
 // our `hello` responder; it simply echoes the `name` parameter provided in the
 // route.
@@ -47,31 +9,16 @@ async fn hello(
     req: Request<Body>,
     _resp: Option<Response<Body>>,
     params: Params,
-    _app: App<State, AuthedState>,
-    authstate: AuthedState,
-) -> HTTPResult<AuthedState> {
+    _app: App<State, NoState>,
+    state: NoState,
+) -> HTTPResult<NoState> {
     let name = &params["name"];
     let bytes = Body::from(format!("hello, {}!\n", name));
 
-    if let Some(authed) = authstate.authed {
-        if authed {
-            return Ok((
-                req,
-                Some(Response::builder().status(200).body(bytes).unwrap()),
-                authstate,
-            ));
-        }
-    } else if authstate.authed.is_none() {
-        return Ok((
-            req,
-            Some(Response::builder().status(200).body(bytes).unwrap()),
-            authstate,
-        ));
-    }
-
-    Err(Error::StatusCode(
-        StatusCode::UNAUTHORIZED,
-        String::default(),
+    Ok((
+        req,
+        Some(Response::builder().status(200).body(bytes).unwrap()),
+        state,
     ))
 }
 
@@ -80,16 +27,16 @@ async fn wildcard(
     req: Request<Body>,
     _resp: Option<Response<Body>>,
     params: Params,
-    _app: App<State, AuthedState>,
-    state: AuthedState,
-) -> HTTPResult<AuthedState> {
+    _app: App<State, NoState>,
+    state: NoState,
+) -> HTTPResult<NoState> {
     let bytes = Body::from(format!("this route is: {}!\n", params["*"]));
 
-    return Ok((
+    Ok((
         req,
         Some(Response::builder().status(200).body(bytes).unwrap()),
         state,
-    ));
+    ))
 }
 
 // Our global application state; must be `Clone`.
@@ -102,12 +49,26 @@ struct State {
 // davisjr.
 #[tokio::main]
 async fn main() -> Result<(), ServerError> {
-    let mut app = App::with_state(State {
+    let state = State {
         authtoken: "867-5309",
-    });
+    };
+
+    // our authtoken validator compares the bearer token of the request, sent
+    // as `Authorization: Bearer <token>`, with the one in the application
+    // state. requests without it are answered with `401 Unauthorized` before
+    // reaching `hello`.
+    let auth = {
+        let state = state.clone();
+        BearerAuth::new("hello", move |token| {
+            constant_time_eq(state.authtoken.as_bytes(), token.as_bytes())
+                .then(|| "user".to_string())
+        })
+    };
+
+    let mut app = App::with_state(state);
 
     app.get("/wildcard/*", compose_handler!(wildcard))?;
-    app.get("/auth/:name", compose_handler!(validate_authtoken, hello))?;
+    app.get("/auth/:name", auth.wrap(compose_handler!(hello)))?;
     app.get("/:name", compose_handler!(hello))?;
 
     app.serve("127.0.0.1:3000").await?;
@@ -1,35 +1,18 @@
-use davisjr::prelude::*;
+use davisjr::{auth::BearerAuth, prelude::*};
 
 const DEFAULT_AUTHTOKEN: &str = "867-5309";
 const AUTHTOKEN_FILENAME: &str = "authtoken.secret";
 
-async fn validate_authtoken(
-    req: Request<Body>,
-    resp: Option<Response<Body>>,
-    _params: Params,
-    _app: App<(), NoState>,
-    _state: NoState,
-) -> HTTPResult<NoState> {
-    let token = req.headers().get("X-AuthToken");
-    if token.is_none() {
-        return Err(Error::StatusCode(StatusCode::UNAUTHORIZED, String::new()));
-    }
-
-    let token = token.unwrap();
-
-    let matches = match std::fs::metadata(AUTHTOKEN_FILENAME) {
-        Ok(_) => {
-            let s = std::fs::read_to_string(AUTHTOKEN_FILENAME)?;
-            s.as_str() == token
-        }
-        Err(_) => DEFAULT_AUTHTOKEN == token,
+// the token is re-read for every request, so that it can be changed without a restart. requests
+// must carry it as `Authorization: Bearer <token>`.
+fn validate_authtoken(token: &str) -> Option<String> {
+    let expected = match std::fs::read_to_string(AUTHTOKEN_FILENAME) {
+        Ok(s) => s.trim().to_string(),
+        Err(_) => DEFAULT_AUTHTOKEN.to_string(),
     };
 
-    if !matches {
-        return Err(Error::StatusCode(StatusCode::UNAUTHORIZED, String::new()));
-    }
-
-    return Ok((req, resp, NoState {}));
+    davisjr::auth::constant_time_eq(expected.as_bytes(), token.as_bytes())
+        .then(|| "disk".to_string())
 }
 
 async fn hello(
@@ -42,17 +25,20 @@ async fn hello(
     let name = &params["name"];
     let bytes = Body::from(format!("hello, {}!\n", name));
 
-    return Ok((
+    Ok((
         req,
         Some(Response::builder().status(200).body(bytes).unwrap()),
         NoState {},
-    ));
+    ))
 }
 
 #[tokio::main]
 async fn main() -> Result<(), ServerError> {
     let mut app = App::new();
-    app.get("/auth/:name", compose_handler!(validate_authtoken, hello))?;
+    app.get(
+        "/auth/:name",
+        BearerAuth::new("hello", validate_authtoken).wrap(compose_handler!(hello)),
+    )?;
     app.get("/:name", compose_handler!(hello))?;
 
     app.serve("127.0.0.1:3000").await?;
@@ -12,11 +12,11 @@ async fn hello(
     let name = &params["name"];
     let bytes = Body::from(format!("hello, {}!\n", name));
 
-    return Ok((
+    Ok((
         req,
         Some(Response::builder().status(200).body(bytes).unwrap()),
         NoState {},
-    ));
+    ))
 }
 
 #[tokio::main]
@@ -26,7 +26,6 @@ async fn main() -> Result<(), ServerError> {
 
     #[cfg(feature = "unix")]
     {
-        std::fs::remove_file("/tmp/server.sock").unwrap_or_default();
         eprintln!("Serving over /tmp/server.sock");
         app.serve_unix(PathBuf::from("/tmp/server.sock")).await?;
     }
@@ -37,11 +37,11 @@ async fn hello(
 
     let bytes = Body::from(format!("hello, {}!\n", name));
 
-    return Ok((
+    Ok((
         req,
         Some(Response::builder().status(200).body(bytes).unwrap()),
         NoState,
-    ));
+    ))
 }
 
 #[tokio::main]
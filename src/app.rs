@@ -1,15 +1,35 @@
-use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+use std::{convert::Infallible, net::SocketAddr, sync::Arc, time::Duration};
 
 use http::{HeaderMap, Method, Request, Response, StatusCode};
 use hyper::{server::conn::Http, service::service_fn, Body};
-use tokio::{net::TcpListener, sync::Mutex};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+};
 
 #[cfg(feature = "unix")]
 use std::path::PathBuf;
 #[cfg(feature = "unix")]
 use tokio::net::UnixListener;
 
-use crate::{errors::*, handler::Handler, router::Router, TransientState};
+use crate::{
+    connection::{ConnectionInfo, TlsInfo, Transport},
+    cookies::Key,
+    errors::*,
+    handler::{Handler, Terminal},
+    proxy::{is_trusted, Cidr, Forwarded, ProxyHeader},
+    router::Router,
+    testing::{
+        CookieJar, FixtureEntry, FixtureRequest, FixtureResponse, Fixtures, ReplayReport,
+        TestRequest,
+    },
+    timeout::Cancellation,
+    Params, TransientState,
+};
+
+// How long a connection may take to send its PROXY protocol header.
+const PROXY_HEADER_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
 
 /// App is used to define application-level functionality and initialize the server. Routes are
 /// typically programmed here.
@@ -75,6 +95,14 @@ pub struct App<S: Clone + Send, T: TransientState + 'static + Clone + Send> {
     log_level: Option<tracing::Level>,
     #[cfg(all(feature = "trace", feature = "logging"))]
     log_level: Option<tracing::Level>,
+    trusted_proxies: Arc<Vec<Cidr>>,
+    proxy_protocol: bool,
+    after_hook: Option<Handler<S, T>>,
+    cookie_key: Option<Arc<Key>>,
+    timeout: Option<Duration>,
+    timeout_status: StatusCode,
+    body_limit: Option<u64>,
+    shutdown: Option<Cancellation>,
 }
 
 impl<S: 'static + Clone + Send, T: TransientState + 'static + Clone + Send> Default for App<S, T> {
@@ -91,6 +119,14 @@ impl<S: 'static + Clone + Send, T: TransientState + 'static + Clone + Send> App<
             global_state: None,
             #[cfg(any(feature = "logging", feature = "trace"))]
             log_level: None,
+            trusted_proxies: Arc::new(Vec::new()),
+            proxy_protocol: false,
+            after_hook: None,
+            cookie_key: None,
+            timeout: None,
+            timeout_status: StatusCode::SERVICE_UNAVAILABLE,
+            body_limit: None,
+            shutdown: None,
         }
     }
 
@@ -105,6 +141,14 @@ impl<S: 'static + Clone + Send, T: TransientState + 'static + Clone + Send> App<
             global_state: Some(Arc::new(Mutex::new(state))),
             #[cfg(any(feature = "logging", feature = "trace"))]
             log_level: None,
+            trusted_proxies: Arc::new(Vec::new()),
+            proxy_protocol: false,
+            after_hook: None,
+            cookie_key: None,
+            timeout: None,
+            timeout_status: StatusCode::SERVICE_UNAVAILABLE,
+            body_limit: None,
+            shutdown: None,
         }
     }
 
@@ -130,6 +174,102 @@ impl<S: 'static + Clone + Send, T: TransientState + 'static + Clone + Send> App<
         }
     }
 
+    /// Trust the `Forwarded` and `X-Forwarded-*` headers on requests from peers within these
+    /// networks, provided in CIDR notation (e.g. `10.0.0.0/8`). For requests from a trusted
+    /// proxy, the client's address replaces the peer's [std::net::IpAddr] in the request
+    /// extensions, and the reported client address, scheme and host are available through
+    /// [crate::connection::ConnectionInfo]. Headers from any other peer are ignored.
+    pub fn with_trusted_proxies(&mut self, proxies: &[&str]) -> Result<(), ServerError> {
+        self.trusted_proxies = Arc::new(
+            proxies
+                .iter()
+                .map(|p| p.parse())
+                .collect::<Result<Vec<Cidr>, ServerError>>()?,
+        );
+        Ok(())
+    }
+
+    /// Require a HAProxy PROXY protocol (v1 or v2) header at the start of every connection
+    /// accepted by [crate::app::App::serve] and [crate::app::App::serve_tls], as sent by TCP load
+    /// balancers. The client address it carries is used as the peer address for the connection,
+    /// and the load balancer's address is kept in
+    /// [crate::connection::ConnectionInfo::proxy_addr]. Connections without a valid header are
+    /// closed, so the listener should only be reachable through the load balancer.
+    pub fn with_proxy_protocol(&mut self, enabled: bool) {
+        self.proxy_protocol = enabled;
+    }
+
+    /// Run the handler after every request, with the response the router produced, including
+    /// error responses and those for unknown routes. After hooks are how middleware such as
+    /// [crate::compression::Compression] applies to the whole application. Hooks must pass a
+    /// response along; errors they return are answered as they would be from a route. Calling
+    /// this again appends to the hook chain.
+    ///
+    /// The request given to the hook carries the method, URI, version and headers of the original
+    /// request, and its [crate::connection::ConnectionInfo] and client [std::net::IpAddr]
+    /// extensions, but not its body or other extensions. Route parameters are empty and the
+    /// transient state is [crate::TransientState::initial].
+    pub fn with_after_hook(&mut self, handler: Handler<S, T>) {
+        self.after_hook = Some(match self.after_hook.take() {
+            Some(existing) => existing.then(handler),
+            None => handler,
+        });
+    }
+
+    /// Sign and encrypt cookies with a key derived from the secret, which must be at least
+    /// [crate::cookies::MIN_SECRET_LEN] bytes. The key is available to handlers through
+    /// [crate::app::App::cookie_key], and is given to the jars parsed by the
+    /// [crate::cookies::cookies] middleware. Every instance of the application must share the
+    /// secret for cookies to be read across them.
+    pub fn with_cookie_secret(&mut self, secret: &[u8]) -> Result<(), ServerError> {
+        self.cookie_key = Some(Arc::new(Key::from_secret(secret)?));
+        Ok(())
+    }
+
+    /// The key set with [crate::app::App::with_cookie_secret], if any.
+    pub fn cookie_key(&self) -> Option<Arc<Key>> {
+        self.cookie_key.clone()
+    }
+
+    /// Limit the time a route's handlers may take to produce a response. Requests which take
+    /// longer are answered with 503 Service Unavailable, or the status set with
+    /// [crate::app::App::with_timeout_status], and their
+    /// [crate::timeout::Cancellation] is cancelled. Handlers may replace the timeout with their
+    /// own, set with [crate::handler::Handler::with_timeout]. After hooks are not limited.
+    pub fn with_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+    }
+
+    /// Set the status of responses to requests which time out, such as 504 Gateway Timeout for
+    /// applications which proxy to other servers.
+    pub fn with_timeout_status(&mut self, status: StatusCode) {
+        self.timeout_status = status;
+    }
+
+    pub(crate) fn timeout(&self) -> (Option<Duration>, StatusCode) {
+        (self.timeout, self.timeout_status)
+    }
+
+    /// Limit the size of request bodies given to route handlers, in bytes. Requests declaring a
+    /// larger `Content-Length` are refused with 413 Payload Too Large before the route's handlers
+    /// run; other bodies fail to read once they pass the limit, and handlers returning the error
+    /// with `?` answer 413 as well. Routes may replace the limit with their own, set with
+    /// [crate::handler::Handler::with_body_limit], which is then enforced in the same way.
+    pub fn with_body_limit(&mut self, limit: u64) {
+        self.body_limit = Some(limit);
+    }
+
+    pub(crate) fn body_limit(&self) -> Option<u64> {
+        self.body_limit
+    }
+
+    // End the connections being served once the cancellation is cancelled, such as when a
+    // [crate::testing::TestServer] is dropped. The serve loops themselves are ended by their
+    // callers.
+    pub(crate) fn with_shutdown(&mut self, shutdown: Cancellation) {
+        self.shutdown = Some(shutdown);
+    }
+
     fn log(&self, msg: String) {
         #[cfg(all(feature = "logging", not(feature = "trace")))]
         match self.log_level {
@@ -229,22 +369,35 @@ impl<S: 'static + Clone + Send, T: TransientState + 'static + Clone + Send> App<
     /// Dispatch a route based on the request. Returns a response based on the error status of the
     /// handler chain following the normal chain of responsibility rules described elsewhere. Only
     /// needed by server implementors.
-    pub async fn dispatch(&self, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    pub async fn dispatch(&self, mut req: Request<Body>) -> Result<Response<Body>, Infallible> {
         let uri = req.uri().clone();
         let method = req.method().clone();
 
+        // the request is cancelled if this future is dropped, as when the client disconnects.
+        let cancellation = match Cancellation::from_request(&req) {
+            Some(cancellation) => cancellation,
+            None => {
+                let cancellation = Cancellation::new();
+                req.extensions_mut().insert(cancellation.clone());
+                cancellation
+            }
+        };
+        let guard = cancellation.guard();
+
         self.log(format!("{} request to {}", method, uri));
 
-        match self.router.dispatch(req, self.clone()).await {
-            Ok(resp) => {
-                let status = resp.status();
+        let head = self.after_hook.as_ref().map(|_| request_head(&req));
 
+        let mut resp = match self.router.dispatch(req, self.clone()).await {
+            Ok(resp) => {
                 self.log(format!(
                     "{} request to {}: responding with status {}",
-                    method, uri, status
+                    method,
+                    uri,
+                    resp.status()
                 ));
 
-                Ok(resp)
+                resp
             }
             Err(e) => {
                 self.log(format!(
@@ -252,86 +405,137 @@ impl<S: 'static + Clone + Send, T: TransientState + 'static + Clone + Send> App<
                     method, uri, e
                 ));
 
-                match e.clone() {
-                    Error::StatusCode(sc, msg) => Ok(Response::builder()
-                        .status(sc)
-                        .body(Body::from(msg + "\n"))
-                        .unwrap()),
-                    Error::InternalServerError(e) => Ok(Response::builder()
-                        .status(StatusCode::INTERNAL_SERVER_ERROR)
-                        .body(Body::from(e.to_string() + "\n"))
-                        .unwrap()),
+                error_response(e)
+            }
+        };
+        // the chain has ended; after hooks are a chain of their own.
+        resp.extensions_mut().remove::<Terminal>();
+
+        let resp = match (&self.after_hook, head) {
+            (Some(hook), Some(head)) => {
+                match hook
+                    .perform(head, Some(resp), Params::new(), self.clone(), T::initial())
+                    .await
+                {
+                    Ok((_, Some(resp), _)) => resp,
+                    Ok((_, None, _)) => {
+                        error_response(Error::new("after hook produced no response"))
+                    }
+                    Err(e) => {
+                        self.log(format!(
+                            "{} request to {}: after hook responding with status {}",
+                            method, uri, e
+                        ));
+
+                        error_response(e)
+                    }
                 }
             }
-        }
+            _ => resp,
+        };
+
+        guard.disarm();
+        Ok(resp)
     }
 
+    /// Start a HTTP server over a unix socket with tokio, using the default
+    /// [crate::app::UnixSocketOptions]. See [crate::app::App::serve_unix_with_options].
     #[cfg(feature = "unix")]
-    pub async fn serve_unix(self, filename: PathBuf) -> Result<(), ServerError> {
-        let unix_listener = UnixListener::bind(filename)?;
-        loop {
-            let (stream, _) = unix_listener.accept().await?;
+    pub async fn serve_unix(&self, filename: PathBuf) -> Result<(), ServerError> {
+        self.serve_unix_with_options(filename, UnixSocketOptions::default())
+            .await
+    }
 
-            let s = self.clone();
-            let sfn = service_fn(move |req: Request<Body>| {
-                let s = s.clone();
-                async move { s.clone().dispatch(req).await }
-            });
+    /// Start a HTTP server over a unix socket with tokio. A stale socket file left behind by a
+    /// previous server is removed before binding; if another server is still accepting
+    /// connections on it, an error is returned instead. The socket file's mode and ownership are
+    /// applied from the options after binding, and the file is removed when the server stops.
+    ///
+    /// The credentials of the connecting process are inserted into each request's extensions as
+    /// [crate::app::PeerCredentials], and as part of the [crate::connection::ConnectionInfo].
+    #[cfg(feature = "unix")]
+    pub async fn serve_unix_with_options(
+        &self,
+        filename: PathBuf,
+        options: UnixSocketOptions,
+    ) -> Result<(), ServerError> {
+        remove_stale_socket(&filename)?;
 
-            let obj = self.clone();
+        let unix_listener = UnixListener::bind(&filename)?;
+        let _guard = SocketGuard(filename.clone());
 
-            tokio::task::spawn(async move {
-                if let Err(http_err) = Http::new()
-                    .http1_keep_alive(true)
-                    .serve_connection(stream, sfn)
-                    .await
-                {
-                    obj.log(format!(
-                        "ServerError while serving HTTP connection: {}",
-                        http_err
-                    ));
+        if let Some(mode) = options.mode {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&filename, std::fs::Permissions::from_mode(mode))?;
+        }
+
+        if options.uid.is_some() || options.gid.is_some() {
+            std::os::unix::fs::chown(&filename, options.uid, options.gid)?;
+        }
+
+        loop {
+            let (stream, _) = unix_listener.accept().await?;
+
+            let mut info = ConnectionInfo::new(Transport::Unix);
+            info.socket_path = Some(filename.clone());
+            info.peer_credentials = match stream.peer_cred() {
+                Ok(cred) => Some(PeerCredentials {
+                    uid: cred.uid(),
+                    gid: cred.gid(),
+                    pid: cred.pid(),
+                }),
+                Err(e) => {
+                    self.log(format!("Could not retrieve peer credentials: {}", e));
+                    None
                 }
-            });
+            };
+
+            self.spawn_connection(self.clone().serve_connection(stream, info));
         }
     }
 
     /// Start a TCP/HTTP server with tokio. Performs dispatch on an as-needed basis. This is a more
     /// common path for users to start a server.
+    ///
+    /// The peer's [std::net::IpAddr] and a [crate::connection::ConnectionInfo] are inserted into
+    /// each request's extensions.
     pub async fn serve(&self, addr: &str) -> Result<(), ServerError> {
         let socketaddr: SocketAddr = addr.parse()?;
 
-        let tcp_listener = TcpListener::bind(socketaddr).await?;
+        self.serve_listener(TcpListener::bind(socketaddr).await?)
+            .await
+    }
+
+    /// Serve HTTP on a TCP listener which is already bound, such as one bound to port 0 to have
+    /// the operating system choose a port. Otherwise identical to [crate::app::App::serve].
+    pub async fn serve_listener(&self, tcp_listener: TcpListener) -> Result<(), ServerError> {
         loop {
             let (tcp_stream, sa) = tcp_listener.accept().await?;
 
-            let s = self.clone();
-            let sfn = service_fn(move |mut req: Request<Body>| {
-                let ip = sa.ip();
-                req.extensions_mut().insert(ip);
-                let s = s.clone();
-                async move { s.clone().dispatch(req).await }
-            });
+            let mut info = ConnectionInfo::new(Transport::Tcp);
+            info.peer_addr = Some(sa);
+            info.local_addr = tcp_stream.local_addr().ok();
 
             self.log(format!("Request from {}", sa));
             let obj = self.clone();
 
-            tokio::task::spawn(async move {
-                if let Err(http_err) = Http::new()
-                    .http1_keep_alive(true)
-                    .serve_connection(tcp_stream, sfn)
-                    .await
-                {
-                    obj.log(format!(
-                        "ServerError while serving HTTP connection: {}",
-                        http_err
-                    ));
+            self.spawn_connection(async move {
+                let mut tcp_stream = tcp_stream;
+                if let Err(e) = obj.read_proxy_header(&mut tcp_stream, &mut info).await {
+                    obj.log(format!("ServerError while reading PROXY header: {}", e));
+                    return;
                 }
+
+                obj.serve_connection(tcp_stream, info).await
             });
         }
     }
 
     /// Start a TLS-backed TCP/HTTP server with tokio. Performs dispatch on an as-needed basis. This is a more
     /// common path for users to start a server.
+    ///
+    /// As with [crate::app::App::serve], connection information is inserted into each request's
+    /// extensions, including the details of the TLS session.
     #[cfg(feature = "tls")]
     pub async fn serve_tls(
         self,
@@ -340,36 +544,41 @@ impl<S: 'static + Clone + Send, T: TransientState + 'static + Clone + Send> App<
     ) -> Result<(), ServerError> {
         let socketaddr: SocketAddr = addr.parse()?;
 
+        self.serve_tls_listener(TcpListener::bind(socketaddr).await?, config)
+            .await
+    }
+
+    /// Serve HTTPS on a TCP listener which is already bound. Otherwise identical to
+    /// [crate::app::App::serve_tls].
+    #[cfg(feature = "tls")]
+    pub async fn serve_tls_listener(
+        &self,
+        tcp_listener: TcpListener,
+        config: tokio_rustls::rustls::ServerConfig,
+    ) -> Result<(), ServerError> {
         let config = tokio_rustls::TlsAcceptor::from(Arc::new(config));
-        let tcp_listener = TcpListener::bind(socketaddr).await?;
         loop {
             let (tcp_stream, sa) = tcp_listener.accept().await?;
 
-            let s = self.clone();
-            let sfn = service_fn(move |mut req: Request<Body>| {
-                let ip = sa.ip();
-                req.extensions_mut().insert(ip);
-                let s = s.clone();
-                async move { s.clone().dispatch(req).await }
-            });
+            let mut info = ConnectionInfo::new(Transport::Tls);
+            info.peer_addr = Some(sa);
+            info.local_addr = tcp_stream.local_addr().ok();
 
             self.log(format!("Request from {}", sa));
             let obj = self.clone();
 
             let config = config.clone();
-            tokio::task::spawn(async move {
+            self.spawn_connection(async move {
+                let mut tcp_stream = tcp_stream;
+                if let Err(e) = obj.read_proxy_header(&mut tcp_stream, &mut info).await {
+                    obj.log(format!("ServerError while reading PROXY header: {}", e));
+                    return;
+                }
+
                 match config.accept(tcp_stream).await {
-                    Ok(tcp_stream) => {
-                        if let Err(http_err) = Http::new()
-                            .http1_keep_alive(true)
-                            .serve_connection(tcp_stream, sfn)
-                            .await
-                        {
-                            obj.log(format!(
-                                "ServerError while serving HTTP connection: {}",
-                                http_err
-                            ));
-                        }
+                    Ok(tls_stream) => {
+                        info.tls = Some(TlsInfo::from(tls_stream.get_ref().1));
+                        obj.serve_connection(tls_stream, info).await
                     }
                     Err(e) => {
                         obj.log(format!("ServerError while serving TLS: {:?}", e));
@@ -378,42 +587,431 @@ impl<S: 'static + Clone + Send, T: TransientState + 'static + Clone + Send> App<
             });
         }
     }
+
+    /// Start a TLS-backed TCP/HTTP server with tokio, loading the certificate chain and private
+    /// key from PEM files. See [crate::tls::load_pem] for the supported key formats; any problem
+    /// reading or matching the files is returned as a [crate::errors::ServerError] before the
+    /// server starts.
+    #[cfg(feature = "tls")]
+    pub async fn serve_tls_pem(
+        &self,
+        addr: &str,
+        cert_path: impl AsRef<std::path::Path>,
+        key_path: impl AsRef<std::path::Path>,
+    ) -> Result<(), ServerError> {
+        let config = crate::tls::load_pem(cert_path, key_path)?;
+        self.clone().serve_tls(addr, config).await
+    }
+
+    // Consume the PROXY protocol header if it is enabled, and replace the connection's addresses
+    // with the ones it carries.
+    async fn read_proxy_header(
+        &self,
+        stream: &mut TcpStream,
+        info: &mut ConnectionInfo,
+    ) -> Result<(), ServerError> {
+        if !self.proxy_protocol {
+            return Ok(());
+        }
+
+        let header = tokio::time::timeout(PROXY_HEADER_TIMEOUT, ProxyHeader::read(stream))
+            .await
+            .map_err(|_| ServerError("timed out".to_string()))??;
+
+        if let Some(source) = header.source {
+            info.proxy_addr = info.peer_addr;
+            info.peer_addr = Some(source);
+            info.local_addr = header.destination;
+        }
+
+        Ok(())
+    }
+
+    // Insert the connection information, and for compatibility the client's IP address, into the
+    // request. Forwarded headers are interpreted here when the peer is a trusted proxy.
+    pub(crate) fn insert_connection_info(&self, req: &mut Request<Body>, mut info: ConnectionInfo) {
+        if let Some(peer) = info.peer_addr {
+            let mut ip = peer.ip();
+            if is_trusted(&self.trusted_proxies, &ip) {
+                info.forwarded = Forwarded::from_headers(req.headers(), &self.trusted_proxies);
+                if let Some(client_ip) = info.forwarded.as_ref().and_then(|f| f.client_ip) {
+                    ip = client_ip;
+                }
+            }
+            req.extensions_mut().insert(ip);
+        }
+        #[cfg(feature = "unix")]
+        if let Some(creds) = info.peer_credentials {
+            req.extensions_mut().insert(creds);
+        }
+        req.extensions_mut().insert(info);
+    }
+
+    // Spawn the task serving an accepted connection, which ends early at shutdown.
+    fn spawn_connection<F>(&self, connection: F)
+    where
+        F: std::future::Future<Output = ()> + Send + 'static,
+    {
+        let shutdown = self.shutdown.clone();
+        tokio::task::spawn(async move {
+            match shutdown {
+                Some(shutdown) => tokio::select! {
+                    _ = connection => {}
+                    _ = shutdown.cancelled() => {}
+                },
+                None => connection.await,
+            }
+        });
+    }
+
+    // Serve HTTP on an accepted connection.
+    async fn serve_connection<I>(self, stream: I, info: ConnectionInfo)
+    where
+        I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let s = self.clone();
+        let sfn = service_fn(move |mut req: Request<Body>| {
+            s.insert_connection_info(&mut req, info.clone());
+
+            let s = s.clone();
+            async move { s.clone().dispatch(req).await }
+        });
+
+        if let Err(http_err) = Http::new()
+            .http1_keep_alive(true)
+            .serve_connection(stream, sfn)
+            .await
+        {
+            self.log(format!(
+                "ServerError while serving HTTP connection: {}",
+                http_err
+            ));
+        }
+    }
+}
+
+/// Options for serving over a unix socket with [crate::app::App::serve_unix_with_options].
+#[cfg(feature = "unix")]
+#[derive(Clone, Debug, Default)]
+pub struct UnixSocketOptions {
+    /// Permission bits for the socket file, e.g. `0o660`. Left to the process umask if not
+    /// provided.
+    pub mode: Option<u32>,
+    /// User ID to own the socket file.
+    pub uid: Option<u32>,
+    /// Group ID to own the socket file.
+    pub gid: Option<u32>,
+}
+
+/// PeerCredentials are the credentials of the process connected over a unix socket. They are
+/// inserted into request extensions by [crate::app::App::serve_unix], and can be used for local
+/// authorization:
+///
+/// ```ignore
+///     if let Some(creds) = req.extensions().get::<PeerCredentials>() {
+///         if creds.uid != 0 {
+///             return Err(Error::new_status(StatusCode::FORBIDDEN, "root only"));
+///         }
+///     }
+/// ```
+#[cfg(feature = "unix")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: Option<i32>,
+}
+
+// Removes the socket file when the server stops, including when its future is dropped.
+#[cfg(feature = "unix")]
+struct SocketGuard(PathBuf);
+
+#[cfg(feature = "unix")]
+impl Drop for SocketGuard {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+// Remove a socket file left behind by a server that did not exit cleanly. Sockets with a live
+// listener, and files which are not sockets, are left alone.
+#[cfg(feature = "unix")]
+fn remove_stale_socket(filename: &PathBuf) -> Result<(), ServerError> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(filename) {
+        Ok(md) if md.file_type().is_socket() => {
+            if std::os::unix::net::UnixStream::connect(filename).is_ok() {
+                return Err(ServerError(format!(
+                    "{} is in use by another server",
+                    filename.display()
+                )));
+            }
+
+            std::fs::remove_file(filename)?;
+            Ok(())
+        }
+        Ok(_) => Err(ServerError(format!(
+            "{} exists and is not a socket",
+            filename.display()
+        ))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Convert a handler error into its response.
+fn error_response(e: Error) -> Response<Body> {
+    match e {
+        Error::StatusCode(sc, msg) => Response::builder()
+            .status(sc)
+            .body(Body::from(msg + "\n"))
+            .unwrap(),
+        Error::InternalServerError(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(e + "\n"))
+            .unwrap(),
+    }
+}
+
+// Copy what after hooks may see of the request before it is handed to the router.
+fn request_head(req: &Request<Body>) -> Request<Body> {
+    let mut head = Request::new(Body::default());
+    *head.method_mut() = req.method().clone();
+    *head.uri_mut() = req.uri().clone();
+    *head.version_mut() = req.version();
+    *head.headers_mut() = req.headers().clone();
+
+    if let Some(info) = req.extensions().get::<ConnectionInfo>() {
+        head.extensions_mut().insert(info.clone());
+    }
+    if let Some(ip) = req.extensions().get::<std::net::IpAddr>() {
+        head.extensions_mut().insert(*ip);
+    }
+
+    head
 }
 
 /// TestApp is a testing framework for davisjr applications. Given an App, it can issue mock
-/// requests to it without standing up a typical web server.
+/// requests to it without standing up a typical web server. Requests are given the same
+/// connection information a server would insert, simulating a peer on the loopback address; see
+/// [crate::app::TestApp::with_peer_addr].
 #[derive(Clone)]
 pub struct TestApp<S: Clone + Send + 'static, T: TransientState + 'static + Clone + Send> {
     app: App<S, T>,
     headers: Option<HeaderMap>,
+    cookies: Option<Arc<std::sync::Mutex<CookieJar>>>,
+    connection: ConnectionInfo,
+    extensions: Vec<Extension>,
 }
 
+// Inserts an extension into a request; see TestApp::with_extension.
+type Extension = Arc<dyn Fn(&mut http::Extensions) + Send + Sync>;
+
 impl<S: Clone + Send + 'static, T: TransientState + 'static + Clone + Send> TestApp<S, T> {
     /// Construct a new tested application.
     pub fn new(app: App<S, T>) -> Self {
-        Self { app, headers: None }
+        let mut connection = ConnectionInfo::new(Transport::Tcp);
+        connection.peer_addr = Some(SocketAddr::from(([127, 0, 0, 1], 49152)));
+        connection.local_addr = Some(SocketAddr::from(([127, 0, 0, 1], 80)));
+
+        Self {
+            app,
+            headers: None,
+            cookies: None,
+            connection,
+            extensions: Vec::new(),
+        }
     }
 
     /// with_headers applies the headers to any following request, and acts as an alternative
     /// constructor.
     pub fn with_headers(&self, headers: http::HeaderMap) -> Self {
         Self {
-            app: self.app.clone(),
             headers: Some(headers),
+            ..self.clone()
+        }
+    }
+
+    /// with_cookie_jar keeps a [crate::testing::CookieJar] for following requests: cookies set by
+    /// responses are stored, and sent back on requests to matching paths until they expire. The
+    /// jar is shared by clones of the returned TestApp, which is useful for testing login flows.
+    pub fn with_cookie_jar(&self) -> Self {
+        Self {
+            cookies: Some(Arc::new(std::sync::Mutex::new(CookieJar::default()))),
+            ..self.clone()
+        }
+    }
+
+    /// with_peer_addr simulates requests from the address. The peer's [std::net::IpAddr] and a
+    /// [crate::connection::ConnectionInfo] are inserted into requests as
+    /// [crate::app::App::serve] would, including the handling of trusted proxy headers. The
+    /// default peer is on `127.0.0.1`.
+    pub fn with_peer_addr(&self, addr: SocketAddr) -> Self {
+        let mut test_app = self.clone();
+        test_app.connection.peer_addr = Some(addr);
+        test_app
+    }
+
+    /// with_tls simulates requests received over TLS with the session details provided.
+    pub fn with_tls(&self, tls: TlsInfo) -> Self {
+        let mut test_app = self.clone();
+        test_app.connection.transport = Transport::Tls;
+        test_app.connection.local_addr = Some(SocketAddr::from(([127, 0, 0, 1], 443)));
+        test_app.connection.tls = Some(tls);
+        test_app
+    }
+
+    /// with_connection_info replaces the simulated connection entirely.
+    pub fn with_connection_info(&self, info: ConnectionInfo) -> Self {
+        Self {
+            connection: info,
+            ..self.clone()
         }
     }
 
+    /// with_extension inserts the value into the extensions of any following request which does
+    /// not already carry one of its type. Any type may be inserted, for example a principal
+    /// normally provided by earlier middleware.
+    pub fn with_extension<E: Clone + Send + Sync + 'static>(&self, ext: E) -> Self {
+        let mut test_app = self.clone();
+        test_app.extensions.push(Arc::new(move |extensions| {
+            if extensions.get::<E>().is_none() {
+                extensions.insert(ext.clone());
+            }
+        }));
+        test_app
+    }
+
+    /// The cookie jar, if one was enabled with [crate::app::TestApp::with_cookie_jar]. It can be
+    /// used to inspect cookies, or to add them before a request.
+    pub fn cookie_jar(&self) -> Option<std::sync::MutexGuard<'_, CookieJar>> {
+        self.cookies.as_ref().map(|jar| jar.lock().unwrap())
+    }
+
+    /// with_header adds a single header to any following request, keeping any headers already
+    /// provided.
+    pub fn with_header(&self, name: http::header::HeaderName, value: http::HeaderValue) -> Self {
+        let mut headers = self.default_headers();
+        headers.append(name, value);
+        self.with_headers(headers)
+    }
+
+    /// Start building a request against the path, which may be dispatched with
+    /// [crate::testing::TestRequest::send].
+    pub fn request(&self, method: Method, path: &str) -> TestRequest {
+        let app = self.clone();
+        TestRequest::new(
+            Box::new(move |req| Box::pin(async move { app.dispatch(req).await })),
+            self.default_headers(),
+            method,
+            path,
+        )
+    }
+
+    /// Replay the fixtures' requests against the application in order, comparing each response
+    /// with the recorded one. See [crate::testing::Fixtures] for the file format.
+    ///
+    /// ```ignore
+    ///     TestApp::new(app)
+    ///         .replay(&Fixtures::load("testdata/users.json")?)
+    ///         .await
+    ///         .assert_ok();
+    /// ```
+    pub async fn replay(&self, fixtures: &Fixtures) -> ReplayReport {
+        let mut report = ReplayReport::default();
+
+        for (index, entry) in fixtures.entries.iter().enumerate() {
+            let resp = entry
+                .request
+                .to_test_request(|method, path| self.request(method, path))
+                .send()
+                .await;
+            report.mismatches.append(&mut entry.diff(index, &resp));
+            report.replayed += 1;
+        }
+
+        report
+    }
+
+    /// Load fixtures from a file and replay them with [crate::app::TestApp::replay].
+    pub async fn replay_file(
+        &self,
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<ReplayReport, ServerError> {
+        Ok(self.replay(&Fixtures::load(path)?).await)
+    }
+
+    /// Perform the requests in order and record the responses as fixtures, keeping the named
+    /// response headers. Save the result with [crate::testing::Fixtures::save] to create or
+    /// update a golden file.
+    pub async fn record(&self, requests: Vec<FixtureRequest>, headers: &[&str]) -> Fixtures {
+        let mut fixtures = Fixtures::default();
+
+        for request in requests {
+            let resp = request
+                .to_test_request(|method, path| self.request(method, path))
+                .send()
+                .await;
+            fixtures.entries.push(FixtureEntry {
+                name: None,
+                response: FixtureResponse::record(&resp, headers),
+                request,
+            });
+        }
+
+        fixtures
+    }
+
+    fn default_headers(&self) -> HeaderMap {
+        self.headers.clone().unwrap_or_default()
+    }
+
     /// dispatch a request to the application, this allows for maximum flexibility.
-    pub async fn dispatch(&self, req: Request<Body>) -> Response<Body> {
-        self.app.dispatch(req).await.unwrap()
+    pub async fn dispatch(&self, mut req: Request<Body>) -> Response<Body> {
+        let path = req.uri().path().to_string();
+
+        if req.extensions().get::<ConnectionInfo>().is_none() {
+            self.app
+                .insert_connection_info(&mut req, self.connection.clone());
+        }
+
+        for ext in &self.extensions {
+            ext(req.extensions_mut());
+        }
+
+        // requests carry one Cookie header, so the jar's cookies are joined to any already set.
+        if let Some(cookies) = self.cookie_jar().and_then(|jar| jar.header(&path)) {
+            let mut value = Vec::new();
+            for existing in req.headers().get_all(http::header::COOKIE) {
+                value.extend_from_slice(existing.as_bytes());
+                value.extend_from_slice(b"; ");
+            }
+            value.extend_from_slice(cookies.as_bytes());
+
+            req.headers_mut().insert(
+                http::header::COOKIE,
+                http::HeaderValue::from_bytes(&value).unwrap(),
+            );
+        }
+
+        let resp = self.app.dispatch(req).await.unwrap();
+
+        if let Some(mut jar) = self.cookie_jar() {
+            for value in resp.headers().get_all(http::header::SET_COOKIE) {
+                if let Ok(value) = value.to_str() {
+                    jar.store(&path, value);
+                }
+            }
+        }
+
+        resp
     }
 
     fn populate_headers(&self, mut req: http::request::Builder) -> http::request::Builder {
-        if let Some(include_headers) = self.headers.clone() {
-            for (header, value) in include_headers.clone() {
-                if let Some(header) = header {
-                    req = req.header(header, value.clone());
-                }
+        if let Some(include_headers) = &self.headers {
+            for (header, value) in include_headers {
+                req = req.header(header, value.clone());
             }
         }
 
@@ -424,107 +1022,449 @@ impl<S: Clone + Send + 'static, T: TransientState + 'static + Clone + Send> Test
     pub async fn get(&self, path: &str) -> Response<Body> {
         let req = self.populate_headers(Request::builder());
 
-        self.app
-            .dispatch(req.uri(path).body(Body::default()).unwrap())
+        self.dispatch(req.uri(path).body(Body::default()).unwrap())
             .await
-            .unwrap()
     }
 
     /// Perform a POST request against the path.
     pub async fn post(&self, path: &str, body: Body) -> Response<Body> {
         let req = self.populate_headers(Request::builder());
 
-        self.app
-            .dispatch(req.method(Method::POST).uri(path).body(body).unwrap())
+        self.dispatch(req.method(Method::POST).uri(path).body(body).unwrap())
             .await
-            .unwrap()
     }
 
     /// Perform a DELETE request against the path.
     pub async fn delete(&self, path: &str) -> Response<Body> {
         let req = self.populate_headers(Request::builder());
-        self.app
-            .dispatch(
-                req.method(Method::DELETE)
-                    .uri(path)
-                    .body(Body::default())
-                    .unwrap(),
-            )
-            .await
-            .unwrap()
+        self.dispatch(
+            req.method(Method::DELETE)
+                .uri(path)
+                .body(Body::default())
+                .unwrap(),
+        )
+        .await
     }
 
     /// Perform a PUT request against the path.
     pub async fn put(&self, path: &str, body: Body) -> Response<Body> {
         let req = self.populate_headers(Request::builder());
-        self.app
-            .dispatch(req.method(Method::PUT).uri(path).body(body).unwrap())
+        self.dispatch(req.method(Method::PUT).uri(path).body(body).unwrap())
             .await
-            .unwrap()
     }
 
     /// Perform an OPTIONS request against the path.
     pub async fn options(&self, path: &str) -> Response<Body> {
         let req = self.populate_headers(Request::builder());
-        self.app
-            .dispatch(
-                req.method(Method::OPTIONS)
-                    .uri(path)
-                    .body(Body::default())
-                    .unwrap(),
-            )
-            .await
-            .unwrap()
+        self.dispatch(
+            req.method(Method::OPTIONS)
+                .uri(path)
+                .body(Body::default())
+                .unwrap(),
+        )
+        .await
     }
 
     /// Perform a PATCH request against the path.
     pub async fn patch(&self, path: &str, body: Body) -> Response<Body> {
         let req = self.populate_headers(Request::builder());
-        self.app
-            .dispatch(req.method(Method::PATCH).uri(path).body(body).unwrap())
+        self.dispatch(req.method(Method::PATCH).uri(path).body(body).unwrap())
             .await
-            .unwrap()
     }
 
     /// Perform a HEAD request against the path.
     pub async fn head(&self, path: &str) -> Response<Body> {
         let req = self.populate_headers(Request::builder());
-        self.app
-            .dispatch(
-                req.method(Method::HEAD)
-                    .uri(path)
-                    .body(Body::default())
-                    .unwrap(),
-            )
-            .await
-            .unwrap()
+        self.dispatch(
+            req.method(Method::HEAD)
+                .uri(path)
+                .body(Body::default())
+                .unwrap(),
+        )
+        .await
     }
 
     /// Perform a TRACE request against the path.
     pub async fn trace(&self, path: &str) -> Response<Body> {
         let req = self.populate_headers(Request::builder());
-        self.app
-            .dispatch(
-                req.method(Method::TRACE)
-                    .uri(path)
-                    .body(Body::default())
-                    .unwrap(),
-            )
-            .await
-            .unwrap()
+        self.dispatch(
+            req.method(Method::TRACE)
+                .uri(path)
+                .body(Body::default())
+                .unwrap(),
+        )
+        .await
     }
 
     /// Perform a CONNECT request against the path.
     pub async fn connect(&self, path: &str) -> Response<Body> {
         let req = self.populate_headers(Request::builder());
-        self.app
-            .dispatch(
-                req.method(Method::CONNECT)
-                    .uri(path)
-                    .body(Body::default())
-                    .unwrap(),
-            )
+        self.dispatch(
+            req.method(Method::CONNECT)
+                .uri(path)
+                .body(Body::default())
+                .unwrap(),
+        )
+        .await
+    }
+}
+
+mod tests {
+    #[tokio::test]
+    async fn test_after_hook() {
+        use super::{App, TestApp};
+        use crate::{
+            compose_handler, connection::ConnectionInfo, errors::Error, HTTPResult, NoState, Params,
+        };
+        use http::{HeaderValue, Method, Request, Response, StatusCode};
+        use hyper::Body;
+
+        async fn hello(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            _state: NoState,
+        ) -> HTTPResult<NoState> {
+            Ok((
+                req,
+                Some(Response::builder().status(200).body(Body::from("hello"))?),
+                NoState {},
+            ))
+        }
+
+        async fn stamp(
+            req: Request<Body>,
+            resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            _state: NoState,
+        ) -> HTTPResult<NoState> {
+            let mut resp = resp.unwrap();
+            let value = format!(
+                "{} {} {}",
+                req.method(),
+                req.uri().path(),
+                ConnectionInfo::from_request(&req).is_some()
+            );
+            resp.headers_mut()
+                .append("x-stamp", HeaderValue::from_str(&value)?);
+            Ok((req, Some(resp), NoState {}))
+        }
+
+        async fn refuse(
+            req: Request<Body>,
+            resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            _state: NoState,
+        ) -> HTTPResult<NoState> {
+            if req.headers().contains_key("x-refuse") {
+                return Err(Error::new_status(StatusCode::FORBIDDEN, "refused"));
+            }
+            Ok((req, resp, NoState {}))
+        }
+
+        let mut app = App::new();
+        app.get("/hello", compose_handler!(hello)).unwrap();
+        app.with_after_hook(compose_handler!(stamp));
+        app.with_after_hook(compose_handler!(refuse, stamp));
+        let test_app = TestApp::new(app);
+
+        let resp = test_app.request(Method::GET, "/hello").send().await;
+        resp.assert_status(StatusCode::OK);
+        assert_eq!(resp.text(), "hello");
+        let stamps: Vec<&HeaderValue> = resp.headers().get_all("x-stamp").iter().collect();
+        assert_eq!(stamps, vec!["GET /hello true", "GET /hello true"]);
+
+        test_app
+            .request(Method::POST, "/hello")
+            .send()
             .await
-            .unwrap()
+            .assert_status(StatusCode::METHOD_NOT_ALLOWED)
+            .assert_header("x-stamp", "POST /hello true");
+
+        let resp = test_app
+            .request(Method::GET, "/hello")
+            .header("x-refuse", "1")
+            .send()
+            .await;
+        resp.assert_status(StatusCode::FORBIDDEN);
+        assert_eq!(resp.text(), "refused\n");
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        use super::App;
+        use crate::{
+            compose_handler,
+            connection::{ConnectionInfo, Transport},
+            testing::{exchange, TestServer},
+            HTTPResult, NoState, Params,
+        };
+        use http::{Request, Response};
+        use hyper::Body;
+        use std::net::IpAddr;
+
+        async fn peer(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            _state: NoState,
+        ) -> HTTPResult<NoState> {
+            let info = ConnectionInfo::from_request(&req).unwrap();
+            assert_eq!(info.transport, Transport::Tcp);
+            assert!(info.tls.is_none());
+            assert_eq!(
+                info.peer_addr.unwrap().ip(),
+                *req.extensions().get::<IpAddr>().unwrap()
+            );
+
+            let body = Body::from(format!(
+                "{} {}",
+                info.peer_addr.unwrap(),
+                info.local_addr.unwrap()
+            ));
+
+            Ok((
+                req,
+                Some(Response::builder().status(200).body(body)?),
+                NoState {},
+            ))
+        }
+
+        let mut app = App::new();
+        app.get("/", compose_handler!(peer)).unwrap();
+        let server = TestServer::start(app).await.unwrap();
+        let addr = server.addr().unwrap();
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let buf = exchange(
+            &mut stream,
+            b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(buf.starts_with("HTTP/1.1 200 OK"), "{}", buf);
+        assert!(
+            buf.ends_with(&format!("{} {}", stream.local_addr().unwrap(), addr)),
+            "{}",
+            buf
+        );
+    }
+
+    #[tokio::test]
+    async fn test_trusted_proxies() {
+        use super::App;
+        use crate::{
+            compose_handler,
+            connection::ConnectionInfo,
+            testing::{exchange, TestServer},
+            HTTPResult, NoState, Params,
+        };
+        use http::{Request, Response};
+        use hyper::Body;
+        use std::net::IpAddr;
+
+        async fn client(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            _state: NoState,
+        ) -> HTTPResult<NoState> {
+            let info = ConnectionInfo::from_request(&req).unwrap();
+            let body = Body::from(format!(
+                "{} {} {}",
+                req.extensions().get::<IpAddr>().unwrap(),
+                info.scheme(),
+                info.peer_addr.unwrap().ip()
+            ));
+
+            Ok((
+                req,
+                Some(Response::builder().status(200).body(body)?),
+                NoState {},
+            ))
+        }
+
+        async fn request(app: App<(), NoState>) -> String {
+            let server = TestServer::start(app).await.unwrap();
+            let mut stream = tokio::net::TcpStream::connect(server.addr().unwrap())
+                .await
+                .unwrap();
+
+            let buf = exchange(
+                &mut stream,
+                b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Forwarded-For: 1.2.3.4, 5.6.7.8\r\nX-Forwarded-Proto: https\r\nConnection: close\r\n\r\n",
+            )
+            .await;
+            assert!(buf.starts_with("HTTP/1.1 200 OK"), "{}", buf);
+            buf.lines().last().unwrap().to_string()
+        }
+
+        let mut app = App::new();
+        app.get("/", compose_handler!(client)).unwrap();
+
+        // headers from untrusted peers are ignored.
+        assert_eq!(request(app.clone()).await, "127.0.0.1 http 127.0.0.1");
+
+        app.with_trusted_proxies(&["127.0.0.0/8", "5.6.7.8"])
+            .unwrap();
+        assert_eq!(request(app.clone()).await, "1.2.3.4 https 127.0.0.1");
+
+        assert!(app.with_trusted_proxies(&["not-a-network"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol() {
+        use super::App;
+        use crate::{
+            compose_handler,
+            connection::ConnectionInfo,
+            testing::{exchange, TestServer},
+            HTTPResult, NoState, Params,
+        };
+        use http::{Request, Response};
+        use hyper::Body;
+        use std::net::IpAddr;
+
+        async fn client(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            _state: NoState,
+        ) -> HTTPResult<NoState> {
+            let info = ConnectionInfo::from_request(&req).unwrap();
+            let body = Body::from(format!(
+                "{} {} {}",
+                req.extensions().get::<IpAddr>().unwrap(),
+                info.peer_addr.unwrap(),
+                info.proxy_addr.unwrap().ip()
+            ));
+
+            Ok((
+                req,
+                Some(Response::builder().status(200).body(body)?),
+                NoState {},
+            ))
+        }
+
+        let mut app = App::new();
+        app.with_proxy_protocol(true);
+        app.get("/", compose_handler!(client)).unwrap();
+        let server = TestServer::start(app).await.unwrap();
+        let connect = || tokio::net::TcpStream::connect(server.addr().unwrap());
+
+        let buf = exchange(
+            &mut connect().await.unwrap(),
+            b"PROXY TCP4 1.2.3.4 10.0.0.1 4711 80\r\nGET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(buf.starts_with("HTTP/1.1 200 OK"), "{}", buf);
+        assert!(buf.ends_with("1.2.3.4 1.2.3.4:4711 127.0.0.1"), "{}", buf);
+
+        // connections without a header are refused.
+        let buf = exchange(
+            &mut connect().await.unwrap(),
+            b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(buf.is_empty(), "{}", buf);
+    }
+
+    #[cfg(feature = "unix")]
+    #[tokio::test]
+    async fn test_serve_unix() {
+        use super::{App, PeerCredentials, UnixSocketOptions};
+        use crate::{
+            compose_handler,
+            connection::{ConnectionInfo, Transport},
+            testing::{exchange, wait_for_socket},
+            HTTPResult, NoState, Params,
+        };
+        use http::{Request, Response};
+        use hyper::Body;
+        use std::os::unix::fs::PermissionsExt;
+
+        async fn whoami(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            _state: NoState,
+        ) -> HTTPResult<NoState> {
+            let creds = req.extensions().get::<PeerCredentials>().unwrap();
+            let info = ConnectionInfo::from_request(&req).unwrap();
+            assert_eq!(info.transport, Transport::Unix);
+            assert_eq!(info.peer_credentials, Some(*creds));
+            assert!(info.socket_path.is_some());
+
+            let body = Body::from(format!("{} {}", creds.uid, creds.pid.is_some()));
+
+            Ok((
+                req,
+                Some(Response::builder().status(200).body(body)?),
+                NoState {},
+            ))
+        }
+
+        let dir = std::env::temp_dir().join(format!("davisjr-unix-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let filename = dir.join("server.sock");
+
+        // leave a stale socket behind, as a crashed server would.
+        drop(std::os::unix::net::UnixListener::bind(&filename).unwrap());
+        assert!(filename.exists());
+
+        let mut app = App::new();
+        app.get("/", compose_handler!(whoami)).unwrap();
+
+        let mut server = {
+            let (app, filename) = (app.clone(), filename.clone());
+            tokio::spawn(async move {
+                app.serve_unix_with_options(
+                    filename,
+                    UnixSocketOptions {
+                        mode: Some(0o600),
+                        ..Default::default()
+                    },
+                )
+                .await
+            })
+        };
+        wait_for_socket(&filename, &mut server).await.unwrap();
+
+        assert_eq!(
+            std::fs::metadata(&filename).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        // a second server may not steal a live socket.
+        assert!(app.serve_unix(filename.clone()).await.is_err());
+
+        let mut stream = tokio::net::UnixStream::connect(&filename).await.unwrap();
+        let buf = exchange(
+            &mut stream,
+            b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(buf.starts_with("HTTP/1.1 200 OK"), "{}", buf);
+        assert!(buf.ends_with(&format!("{} true", current_uid())), "{}", buf);
+
+        server.abort();
+        let _ = server.await;
+        assert!(!filename.exists());
+
+        std::fs::write(&filename, "not a socket").unwrap();
+        assert!(app.serve_unix(filename.clone()).await.is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+
+        fn current_uid() -> u32 {
+            use std::os::unix::fs::MetadataExt;
+            std::fs::metadata("/proc/self").unwrap().uid()
+        }
     }
 }
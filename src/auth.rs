@@ -0,0 +1,428 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use http::{
+    header::{AUTHORIZATION, WWW_AUTHENTICATE},
+    HeaderValue, Request, Response, StatusCode,
+};
+use hyper::Body;
+use ring::digest::{digest, SHA256};
+
+use crate::{
+    errors::Error,
+    handler::{Handler, Terminal},
+    TransientState,
+};
+
+/// Principal is the authenticated identity of a request. Authentication middleware inserts it in
+/// the request extensions for the handlers it wraps, which find it with
+/// [Principal::from_request].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Principal {
+    scheme: String,
+    name: String,
+}
+
+impl Principal {
+    /// A principal authenticated with the scheme, such as `Basic`.
+    pub fn new(scheme: &str, name: &str) -> Self {
+        Self {
+            scheme: scheme.to_string(),
+            name: name.to_string(),
+        }
+    }
+
+    /// The principal of the request, if it was authenticated.
+    pub fn from_request(req: &Request<Body>) -> Option<Self> {
+        req.extensions().get::<Self>().cloned()
+    }
+
+    /// The scheme the principal authenticated with.
+    pub fn scheme(&self) -> &str {
+        &self.scheme
+    }
+
+    /// The name of the principal: the user name for Basic authentication, or the name given by
+    /// the verifier for Bearer tokens.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Compare secrets in constant time. The secrets are hashed before they are compared, so that
+/// neither their contents nor their lengths can be learned from the time taken.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    ring::constant_time::verify_slices_are_equal(
+        digest(&SHA256, a).as_ref(),
+        digest(&SHA256, b).as_ref(),
+    )
+    .is_ok()
+}
+
+type BasicVerifier = Arc<dyn Fn(&str, &str) -> bool + Send + Sync>;
+
+/// BasicAuth requires HTTP Basic authentication for the handlers it wraps:
+///
+/// ```ignore
+///     let auth = BasicAuth::new("admin", |user, password| check_password(user, password));
+///     app.get("/admin/*", auth.wrap(compose_handler!(admin)))?;
+/// ```
+///
+/// The verifier is called with the user name and password of each request. Requests without
+/// credentials, with malformed credentials, or whose credentials the verifier rejects, are
+/// answered with 401 Unauthorized and a `WWW-Authenticate` challenge for the realm, without
+/// running the wrapped handlers. Otherwise the user is recorded as the request's [Principal].
+///
+/// Basic authentication sends the password with every request, so it should only be used over
+/// HTTPS.
+#[derive(Clone)]
+pub struct BasicAuth {
+    realm: String,
+    verifier: BasicVerifier,
+}
+
+impl BasicAuth {
+    /// Authenticate users in the realm with the verifier.
+    pub fn new<F>(realm: &str, verifier: F) -> Self
+    where
+        F: Fn(&str, &str) -> bool + Send + Sync + 'static,
+    {
+        Self {
+            realm: realm.to_string(),
+            verifier: Arc::new(verifier),
+        }
+    }
+
+    /// Authenticate a fixed set of users, given as user name and password pairs. Passwords are
+    /// compared with [constant_time_eq].
+    pub fn with_users(realm: &str, users: &[(&str, &str)]) -> Self {
+        let users: BTreeMap<String, String> = users
+            .iter()
+            .map(|(user, password)| (user.to_string(), password.to_string()))
+            .collect();
+
+        Self::new(realm, move |user, password| {
+            users
+                .get(user)
+                .is_some_and(|expected| constant_time_eq(expected.as_bytes(), password.as_bytes()))
+        })
+    }
+
+    /// Wrap the handler (or chain), so that it only runs for authenticated requests.
+    pub fn wrap<S: Clone + Send + 'static, T: TransientState + 'static>(
+        self,
+        inner: Handler<S, T>,
+    ) -> Handler<S, T> {
+        let auth = Arc::new(self);
+
+        inner.around(move |inner, mut req, resp, params, app, state| {
+            let auth = auth.clone();
+
+            Box::pin(async move {
+                match auth.authenticate(&req) {
+                    Some(principal) => {
+                        req.extensions_mut().insert(principal);
+                        inner.perform(req, resp, params, app, state).await
+                    }
+                    None => {
+                        let challenge =
+                            format!("Basic realm=\"{}\", charset=\"UTF-8\"", quote(&auth.realm));
+                        Ok((req, Some(unauthorized(&challenge)?), state))
+                    }
+                }
+            })
+        })
+    }
+
+    fn authenticate(&self, req: &Request<Body>) -> Option<Principal> {
+        let encoded = credentials(req, "Basic")?;
+        let decoded = String::from_utf8(STANDARD.decode(encoded).ok()?).ok()?;
+        let (user, password) = decoded.split_once(':')?;
+
+        (self.verifier)(user, password).then(|| Principal::new("Basic", user))
+    }
+}
+
+type BearerVerifier = Arc<dyn Fn(&str) -> Option<String> + Send + Sync>;
+
+/// BearerAuth requires a bearer token (RFC 6750) for the handlers it wraps:
+///
+/// ```ignore
+///     let auth = BearerAuth::with_tokens("api", &[(token.as_str(), "deploy-bot")]);
+///     app.post("/deploy", auth.wrap(compose_handler!(deploy)))?;
+/// ```
+///
+/// The verifier is called with the token from the `Authorization` header, and returns the name
+/// of the [Principal] it belongs to, or [std::option::Option::None] to reject it. Requests without
+/// a valid token are answered with 401 Unauthorized and a `WWW-Authenticate` challenge, which
+/// carries `error="invalid_token"` when a token was presented, without running the wrapped
+/// handlers.
+#[derive(Clone)]
+pub struct BearerAuth {
+    realm: String,
+    verifier: BearerVerifier,
+}
+
+impl BearerAuth {
+    /// Authenticate tokens in the realm with the verifier.
+    pub fn new<F>(realm: &str, verifier: F) -> Self
+    where
+        F: Fn(&str) -> Option<String> + Send + Sync + 'static,
+    {
+        Self {
+            realm: realm.to_string(),
+            verifier: Arc::new(verifier),
+        }
+    }
+
+    /// Authenticate a fixed set of tokens, given as token and principal name pairs. Tokens are
+    /// compared with [constant_time_eq].
+    pub fn with_tokens(realm: &str, tokens: &[(&str, &str)]) -> Self {
+        let tokens: Vec<(String, String)> = tokens
+            .iter()
+            .map(|(token, name)| (token.to_string(), name.to_string()))
+            .collect();
+
+        // every token is compared, so that the time taken does not depend on which matched.
+        Self::new(realm, move |presented| {
+            tokens.iter().fold(None, |found, (token, name)| {
+                if constant_time_eq(token.as_bytes(), presented.as_bytes()) {
+                    Some(name.clone())
+                } else {
+                    found
+                }
+            })
+        })
+    }
+
+    /// Wrap the handler (or chain), so that it only runs for authenticated requests.
+    pub fn wrap<S: Clone + Send + 'static, T: TransientState + 'static>(
+        self,
+        inner: Handler<S, T>,
+    ) -> Handler<S, T> {
+        let auth = Arc::new(self);
+
+        inner.around(move |inner, mut req, resp, params, app, state| {
+            let auth = auth.clone();
+
+            Box::pin(async move {
+                let token = credentials(&req, "Bearer").map(|t| t.to_string());
+                let principal = token
+                    .as_deref()
+                    .and_then(|t| (auth.verifier)(t))
+                    .map(|name| Principal::new("Bearer", &name));
+
+                match principal {
+                    Some(principal) => {
+                        req.extensions_mut().insert(principal);
+                        inner.perform(req, resp, params, app, state).await
+                    }
+                    None => {
+                        let mut challenge = format!("Bearer realm=\"{}\"", quote(&auth.realm));
+                        if token.is_some() {
+                            challenge += ", error=\"invalid_token\"";
+                        }
+
+                        Ok((req, Some(unauthorized(&challenge)?), state))
+                    }
+                }
+            })
+        })
+    }
+}
+
+// The credentials of the Authorization header, if it uses the scheme. Schemes are
+// case-insensitive.
+pub(crate) fn credentials<'a>(req: &'a Request<Body>, scheme: &str) -> Option<&'a str> {
+    let value = req.headers().get(AUTHORIZATION)?.to_str().ok()?;
+    let (presented, credentials) = value.trim().split_once(' ')?;
+    let credentials = credentials.trim();
+
+    (presented.eq_ignore_ascii_case(scheme) && !credentials.is_empty()).then_some(credentials)
+}
+
+// A 401 Unauthorized response with the challenge, which ends the chain.
+pub(crate) fn unauthorized(challenge: &str) -> Result<Response<Body>, Error> {
+    Ok(Terminal::mark(
+        Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .header(WWW_AUTHENTICATE, HeaderValue::from_str(challenge)?)
+            .body(Body::from("unauthorized\n"))?,
+    ))
+}
+
+// Escape a quoted-string's contents.
+pub(crate) fn quote(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+mod tests {
+    #[test]
+    fn test_constant_time_eq() {
+        use super::constant_time_eq;
+
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"Secret"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[tokio::test]
+    async fn test_auth() {
+        use super::{BasicAuth, BearerAuth, Principal};
+        use crate::{
+            app::{App, TestApp},
+            compose_handler, HTTPResult, NoState, Params,
+        };
+        use http::{Method, Request, Response, StatusCode};
+        use hyper::Body;
+
+        async fn whoami(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            state: NoState,
+        ) -> HTTPResult<NoState> {
+            let principal = Principal::from_request(&req).unwrap();
+            let body = format!("{} {}", principal.scheme(), principal.name());
+            Ok((
+                req,
+                Some(
+                    Response::builder()
+                        .status(StatusCode::OK)
+                        .body(Body::from(body))?,
+                ),
+                state,
+            ))
+        }
+
+        async fn replace(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            state: NoState,
+        ) -> HTTPResult<NoState> {
+            Ok((
+                req,
+                Some(
+                    Response::builder()
+                        .status(StatusCode::OK)
+                        .body(Body::from("replaced"))?,
+                ),
+                state,
+            ))
+        }
+
+        async fn pass(
+            req: Request<Body>,
+            resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            state: NoState,
+        ) -> HTTPResult<NoState> {
+            Ok((req, resp, state))
+        }
+
+        let mut app: App<(), NoState> = App::new();
+        app.get(
+            "/basic",
+            BasicAuth::with_users("admin \"area\"", &[("alice", "pa:ss"), ("bob", "hunter2")])
+                .wrap(compose_handler!(whoami)),
+        )
+        .unwrap();
+        app.get(
+            "/bearer",
+            BearerAuth::with_tokens("api", &[("t0k3n", "deploy-bot")])
+                .wrap(compose_handler!(whoami)),
+        )
+        .unwrap();
+        // rejections end the chain, so handlers after the wrapper cannot replace them.
+        app.get(
+            "/chained",
+            compose_handler!(pass)
+                .then(
+                    BasicAuth::with_users("admin", &[("alice", "pa:ss")])
+                        .wrap(compose_handler!(whoami)),
+                )
+                .then(compose_handler!(replace)),
+        )
+        .unwrap();
+        let test_app = TestApp::new(app);
+
+        test_app
+            .request(Method::GET, "/chained")
+            .send()
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED)
+            .assert_header(
+                "www-authenticate",
+                "Basic realm=\"admin\", charset=\"UTF-8\"",
+            );
+        let resp = test_app
+            .request(Method::GET, "/chained")
+            .header("authorization", "Basic YWxpY2U6cGE6c3M=")
+            .send()
+            .await;
+        resp.assert_status(StatusCode::OK);
+        assert_eq!(resp.text(), "replaced");
+
+        // alice:pa:ss
+        let resp = test_app
+            .request(Method::GET, "/basic")
+            .header("authorization", "Basic YWxpY2U6cGE6c3M=")
+            .send()
+            .await;
+        resp.assert_status(StatusCode::OK);
+        assert_eq!(resp.text(), "Basic alice");
+
+        for authorization in [
+            None,
+            // alice:wrong
+            Some("Basic YWxpY2U6d3Jvbmc="),
+            // carol:hunter2
+            Some("Basic Y2Fyb2w6aHVudGVyMg=="),
+            Some("Basic !!!"),
+            Some("Bearer t0k3n"),
+        ] {
+            let mut req = test_app.request(Method::GET, "/basic");
+            if let Some(authorization) = authorization {
+                req = req.header("authorization", authorization);
+            }
+
+            req.send()
+                .await
+                .assert_status(StatusCode::UNAUTHORIZED)
+                .assert_header(
+                    "www-authenticate",
+                    "Basic realm=\"admin \\\"area\\\"\", charset=\"UTF-8\"",
+                );
+        }
+
+        let resp = test_app
+            .request(Method::GET, "/bearer")
+            .header("authorization", "bearer t0k3n")
+            .send()
+            .await;
+        resp.assert_status(StatusCode::OK);
+        assert_eq!(resp.text(), "Bearer deploy-bot");
+
+        test_app
+            .request(Method::GET, "/bearer")
+            .send()
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED)
+            .assert_header("www-authenticate", "Bearer realm=\"api\"");
+        test_app
+            .request(Method::GET, "/bearer")
+            .header("authorization", "Bearer nope")
+            .send()
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED)
+            .assert_header(
+                "www-authenticate",
+                "Bearer realm=\"api\", error=\"invalid_token\"",
+            );
+    }
+}
@@ -0,0 +1,414 @@
+use std::{
+    error::Error as StdError,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use http::{header::CONTENT_LENGTH, Request, StatusCode};
+use hyper::{body::HttpBody, Body};
+
+use crate::errors::Error;
+
+#[derive(Debug)]
+struct Limit {
+    bytes: AtomicU64,
+    // whether the limit is the application's, which is replaced by the first limit a handler sets.
+    default: AtomicBool,
+}
+
+/// BodyLimit is the largest request body, in bytes, that handlers will be given. It is inserted in
+/// the request extensions by [limit_body], so that middleware which buffers or transforms bodies,
+/// such as [crate::compression::Decompression], can keep to it.
+#[derive(Clone, Debug)]
+pub struct BodyLimit(Arc<Limit>);
+
+impl BodyLimit {
+    /// The limit of the request, if it has one.
+    pub fn from_request(req: &Request<Body>) -> Option<u64> {
+        req.extensions().get::<Self>().map(|l| l.get())
+    }
+
+    fn new(bytes: u64, default: bool) -> Self {
+        Self(Arc::new(Limit {
+            bytes: AtomicU64::new(bytes),
+            default: AtomicBool::new(default),
+        }))
+    }
+
+    fn get(&self) -> u64 {
+        self.0.bytes.load(Ordering::SeqCst)
+    }
+
+    // Apply another limit, returning the limit which results.
+    fn restrict(&self, bytes: u64, default: bool) -> u64 {
+        if default {
+            return self.get();
+        }
+
+        if self.0.default.swap(false, Ordering::SeqCst) {
+            self.0.bytes.store(bytes, Ordering::SeqCst);
+            return bytes;
+        }
+
+        self.0.bytes.fetch_min(bytes, Ordering::SeqCst).min(bytes)
+    }
+}
+
+/// LengthLimitError is the error a limited body yields once more than its limit has been read, or
+/// when its declared length exceeds the limit. Errors caused by it are answered with 413 Payload
+/// Too Large when converted to a [crate::errors::Error], such as with `?`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LengthLimitError {
+    limit: u64,
+}
+
+impl LengthLimitError {
+    /// The limit which was exceeded.
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+}
+
+impl fmt::Display for LengthLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "request body exceeds the limit of {} bytes", self.limit)
+    }
+}
+
+impl StdError for LengthLimitError {}
+
+/// Limit the request's body to `limit` bytes. Requests whose `Content-Length` exceeds it are
+/// refused at once with 413 Payload Too Large; otherwise reading past the limit fails with a
+/// [LengthLimitError], and the limit is recorded as a [BodyLimit].
+///
+/// A limit replaces the application's (see [crate::app::App::with_body_limit]); otherwise, if
+/// the request already has a smaller limit, it is kept. Handlers usually set their limit with
+/// [crate::handler::Handler::with_body_limit], which calls this.
+pub fn limit_body(req: Request<Body>, limit: u64) -> Result<Request<Body>, Error> {
+    apply(req, limit, false)
+}
+
+// Limit the request's body to the application's limit. Unlike other limits, it may be raised by
+// the route's handlers, so requests are only refused before their bodies are read when the route
+// sets no limit of its own (`refuse`); otherwise a body whose declared length exceeds the limit
+// fails when it is first read.
+pub(crate) fn limit_body_default(
+    req: Request<Body>,
+    limit: u64,
+    refuse: bool,
+) -> Result<Request<Body>, Error> {
+    if refuse && known_length(&req).is_some_and(|length| length > limit) {
+        return Err(too_large());
+    }
+
+    apply(req, limit, true)
+}
+
+fn apply(req: Request<Body>, limit: u64, default: bool) -> Result<Request<Body>, Error> {
+    let length = known_length(&req);
+
+    // the body is already counted against the request's limit, so only the limit changes.
+    if let Some(existing) = req.extensions().get::<BodyLimit>() {
+        let limit = existing.restrict(limit, default);
+        if !default && length.is_some_and(|length| length > limit) {
+            return Err(too_large());
+        }
+
+        return Ok(req);
+    }
+
+    if !default && length.is_some_and(|length| length > limit) {
+        return Err(too_large());
+    }
+
+    let limit = BodyLimit::new(limit, default);
+    let (mut parts, body) = req.into_parts();
+    parts.extensions.insert(limit.clone());
+
+    // bodies of a known length within a final limit need not be counted.
+    if !default && length.is_some() {
+        return Ok(Request::from_parts(parts, body));
+    }
+
+    let body = futures_util::stream::unfold(Some((body, 0u64)), move |state| {
+        let limit = limit.get();
+
+        async move {
+            let (mut body, read) = state?;
+            let exceeded = || {
+                let e: Box<dyn StdError + Send + Sync> = Box::new(LengthLimitError { limit });
+                Some((Err(e), None))
+            };
+
+            if length.is_some_and(|length| length > limit) {
+                return exceeded();
+            }
+
+            match body.data().await? {
+                Ok(chunk) => {
+                    let read = read + chunk.len() as u64;
+                    if read > limit {
+                        return exceeded();
+                    }
+
+                    Some((Ok(chunk), Some((body, read))))
+                }
+                Err(e) => Some((Err(e.into()), None)),
+            }
+        }
+    });
+
+    Ok(Request::from_parts(parts, Body::wrap_stream(body)))
+}
+
+// The length of the body, if it is known before it is read.
+fn known_length(req: &Request<Body>) -> Option<u64> {
+    req.headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .or_else(|| req.body().size_hint().exact())
+}
+
+/// Whether the error, or any error it was caused by, is a [LengthLimitError].
+pub fn exceeds_limit(e: &(dyn StdError + 'static)) -> bool {
+    let mut next = Some(e);
+    while let Some(e) = next {
+        if e.is::<LengthLimitError>() {
+            return true;
+        }
+
+        // io::Error does not report the error it wraps as its source.
+        if let Some(inner) = e
+            .downcast_ref::<std::io::Error>()
+            .and_then(|io| io.get_ref())
+        {
+            if exceeds_limit(inner) {
+                return true;
+            }
+        }
+
+        next = e.source();
+    }
+
+    false
+}
+
+pub(crate) fn too_large() -> Error {
+    Error::new_status(StatusCode::PAYLOAD_TOO_LARGE, "request body too large")
+}
+
+mod tests {
+    #[tokio::test]
+    async fn test_limit_body() {
+        use super::{exceeds_limit, limit_body, limit_body_default, BodyLimit};
+        use crate::errors::Error;
+        use http::{Request, StatusCode};
+        use hyper::body::to_bytes;
+        use hyper::Body;
+
+        let stream = |chunks: Vec<&'static str>| {
+            Body::wrap_stream(futures_util::stream::iter(
+                chunks.into_iter().map(Ok::<_, std::convert::Infallible>),
+            ))
+        };
+
+        let req = limit_body(Request::new(stream(vec!["hello", " world"])), 11).unwrap();
+        assert_eq!(BodyLimit::from_request(&req), Some(11));
+        let bytes = to_bytes(req.into_body()).await.unwrap();
+        assert_eq!(bytes, "hello world");
+
+        let req = limit_body(Request::new(stream(vec!["hello", " world"])), 8).unwrap();
+        let err = to_bytes(req.into_body()).await.unwrap_err();
+        assert!(exceeds_limit(&err));
+        match Error::from(err) {
+            Error::StatusCode(status, _) => assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE),
+            _ => panic!("body was not limited"),
+        }
+
+        // the smaller limit is kept.
+        let req = limit_body(Request::new(stream(vec!["hello"])), 4).unwrap();
+        let req = limit_body(req, 100).unwrap();
+        assert_eq!(BodyLimit::from_request(&req), Some(4));
+        assert!(to_bytes(req.into_body()).await.is_err());
+
+        // full bodies within the limit are passed through.
+        let req = limit_body(Request::new(Body::from("hello")), 5).unwrap();
+        assert_eq!(to_bytes(req.into_body()).await.unwrap(), "hello");
+        let req = limit_body(Request::new(Body::from("hello")), 5).unwrap();
+        assert!(limit_body(req, 4).is_err());
+
+        let req = Request::builder()
+            .header("content-length", "100")
+            .body(Body::default())
+            .unwrap();
+        assert!(limit_body(req, 99).is_err());
+
+        // the application's limit is replaced by the first other limit, and then refuses declared
+        // lengths only when the body is read.
+        let req =
+            limit_body_default(Request::new(stream(vec!["hello", " world"])), 8, false).unwrap();
+        let req = limit_body(req, 16).unwrap();
+        assert_eq!(BodyLimit::from_request(&req), Some(16));
+        let req = limit_body(req, 32).unwrap();
+        assert_eq!(BodyLimit::from_request(&req), Some(16));
+        assert_eq!(to_bytes(req.into_body()).await.unwrap(), "hello world");
+
+        let req = Request::builder()
+            .header("content-length", "100")
+            .body(Body::from("too long for its length"))
+            .unwrap();
+        let req = limit_body_default(req, 99, false).unwrap();
+        assert!(to_bytes(req.into_body()).await.is_err());
+
+        // unless no other limit will replace it.
+        let req = Request::builder()
+            .header("content-length", "100")
+            .body(Body::default())
+            .unwrap();
+        assert!(limit_body_default(req, 99, true).is_err());
+
+        let io = std::io::Error::other(super::LengthLimitError { limit: 1 });
+        assert!(exceeds_limit(&io));
+        assert!(!exceeds_limit(&std::io::Error::other("other")));
+    }
+
+    #[tokio::test]
+    async fn test_body_limits() {
+        use crate::{
+            app::{App, TestApp},
+            auth::BearerAuth,
+            compose_handler,
+            csrf::Csrf,
+            HTTPResult, NoState, Params,
+        };
+        use http::{Method, Request, Response, StatusCode};
+        use hyper::Body;
+
+        async fn echo(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            state: NoState,
+        ) -> HTTPResult<NoState> {
+            let (parts, body) = req.into_parts();
+            let bytes = hyper::body::to_bytes(body).await?;
+            Ok((
+                Request::from_parts(parts, Body::default()),
+                Some(
+                    Response::builder()
+                        .status(StatusCode::OK)
+                        .body(Body::from(bytes))?,
+                ),
+                state,
+            ))
+        }
+
+        async fn ignore(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            state: NoState,
+        ) -> HTTPResult<NoState> {
+            Ok((
+                req,
+                Some(
+                    Response::builder()
+                        .status(StatusCode::OK)
+                        .body(Body::default())?,
+                ),
+                state,
+            ))
+        }
+
+        async fn pass(
+            req: Request<Body>,
+            resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            state: NoState,
+        ) -> HTTPResult<NoState> {
+            Ok((req, resp, state))
+        }
+
+        let mut app: App<(), NoState> = App::new();
+        app.with_body_limit(8);
+        app.post("/echo", compose_handler!(echo)).unwrap();
+        app.post("/large", compose_handler!(echo).with_body_limit(32))
+            .unwrap();
+        // limits are kept when the chain is wrapped or appended to another.
+        app.post(
+            "/wrapped",
+            BearerAuth::with_tokens("test", &[("t0k3n", "test")])
+                .wrap(compose_handler!(pass).then(compose_handler!(echo).with_body_limit(32))),
+        )
+        .unwrap();
+        app.post("/form", Csrf::new().wrap(compose_handler!(echo)))
+            .unwrap();
+        app.post("/ignore", compose_handler!(ignore)).unwrap();
+        let test_app = TestApp::new(app);
+
+        let stream = |s: &'static str| {
+            Body::wrap_stream(futures_util::stream::iter(
+                s.as_bytes()
+                    .chunks(3)
+                    .map(Ok::<_, std::convert::Infallible>)
+                    .collect::<Vec<_>>(),
+            ))
+        };
+
+        for (path, body, status) in [
+            ("/echo", "12345678", StatusCode::OK),
+            ("/echo", "123456789", StatusCode::PAYLOAD_TOO_LARGE),
+            ("/large", "123456789", StatusCode::OK),
+            (
+                "/large",
+                "123456789012345678901234567890123",
+                StatusCode::PAYLOAD_TOO_LARGE,
+            ),
+            ("/wrapped", "123456789", StatusCode::OK),
+            (
+                "/wrapped",
+                "123456789012345678901234567890123",
+                StatusCode::PAYLOAD_TOO_LARGE,
+            ),
+        ] {
+            let resp = test_app
+                .request(Method::POST, path)
+                .header("authorization", "Bearer t0k3n")
+                .body(stream(body))
+                .send()
+                .await;
+            resp.assert_status(status);
+            if status == StatusCode::OK {
+                assert_eq!(resp.text(), body);
+            }
+        }
+
+        // forms are read within the limit.
+        test_app
+            .request(Method::POST, "/form")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body(stream("csrf_token=abcdef"))
+            .send()
+            .await
+            .assert_status(StatusCode::PAYLOAD_TOO_LARGE);
+
+        // declared lengths are refused before the chain runs, whether or not it reads the body.
+        for path in ["/echo", "/large", "/wrapped", "/ignore"] {
+            test_app
+                .request(Method::POST, path)
+                .header("authorization", "Bearer t0k3n")
+                .header("content-length", "1000000")
+                .body("too long for its length")
+                .send()
+                .await
+                .assert_status(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+    }
+}
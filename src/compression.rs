@@ -0,0 +1,758 @@
+use std::{io, sync::Arc};
+
+use async_compression::tokio::bufread::{
+    BrotliDecoder, BrotliEncoder, DeflateDecoder, DeflateEncoder, GzipDecoder, GzipEncoder,
+};
+use futures_util::TryStreamExt;
+use http::{
+    header::{
+        ACCEPT_ENCODING, ACCEPT_RANGES, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH,
+        CONTENT_RANGE, CONTENT_TYPE, ETAG,
+    },
+    HeaderMap, HeaderValue, Method, Request, Response, StatusCode,
+};
+use hyper::{body::HttpBody, Body};
+use tokio::io::{AsyncBufRead, AsyncReadExt, BufReader};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::{
+    add_vary,
+    body::{exceeds_limit, too_large, BodyLimit},
+    errors::Error,
+    handler::Handler,
+    TransientState,
+};
+
+// Content types which are already compressed, or compress too poorly to be worth the effort.
+// Entries ending in `/` match every subtype.
+const SKIPPED_TYPES: [&str; 12] = [
+    "image/",
+    "audio/",
+    "video/",
+    "font/woff",
+    "font/woff2",
+    "application/zip",
+    "application/gzip",
+    "application/x-gzip",
+    "application/zstd",
+    "application/x-bzip2",
+    "application/x-7z-compressed",
+    "application/x-rar-compressed",
+];
+
+/// Encoding is a content coding supported by [Compression].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    /// The coding's name in `Accept-Encoding` and `Content-Encoding` headers.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+        }
+    }
+
+    /// Look up a coding by name, case-insensitively. `x-gzip` is accepted as an alias of `gzip`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "br" => Some(Self::Brotli),
+            "gzip" | "x-gzip" => Some(Self::Gzip),
+            "deflate" => Some(Self::Deflate),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Encoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Choose the coding for a response from an `Accept-Encoding` header value and the codings
+/// available, which are listed in order of preference. The coding with the highest quality value
+/// wins, with ties going to the earlier available coding; codings with a quality of zero, whether
+/// listed by name or through `*`, are never chosen. [std::option::Option::None] means the
+/// response should not be encoded.
+pub fn negotiate(accept_encoding: &str, available: &[Encoding]) -> Option<Encoding> {
+    let mut wildcard = None;
+    let mut qualities: Vec<(Encoding, f32)> = Vec::new();
+
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';');
+        let name = params.next().unwrap_or_default().trim();
+        if name.is_empty() {
+            continue;
+        }
+
+        let q = params
+            .filter_map(|p| p.trim().strip_prefix("q=").or(p.trim().strip_prefix("Q=")))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        if name == "*" {
+            wildcard = Some(q);
+        } else if let Some(encoding) = Encoding::from_name(name) {
+            qualities.push((encoding, q));
+        }
+    }
+
+    available
+        .iter()
+        .filter_map(|encoding| {
+            let q = qualities
+                .iter()
+                .find(|(e, _)| e == encoding)
+                .map(|(_, q)| *q)
+                .or(wildcard)?;
+            (q > 0.0).then_some((*encoding, q))
+        })
+        .fold(
+            None,
+            |best: Option<(Encoding, f32)>, (encoding, q)| match best {
+                Some((_, best_q)) if best_q >= q => best,
+                _ => Some((encoding, q)),
+            },
+        )
+        .map(|(encoding, _)| encoding)
+}
+
+/// Compression is middleware which compresses response bodies with the best coding the client
+/// accepts, streaming the body through the encoder rather than buffering it. It requires the
+/// `compression` feature, and may be appended to a route's chain or installed for the whole
+/// application with [crate::app::App::with_after_hook]:
+///
+/// ```ignore
+///     app.get("/report", compose_handler!(report).then(compression()))?;
+///     app.with_after_hook(Compression::new().with_min_size(4096).handler());
+/// ```
+///
+/// Responses are left alone if they are already encoded, are partial content, forbid
+/// transformation with `Cache-Control: no-transform`, have a content type which is already
+/// compressed (images, audio, video, archives and web fonts), or have a body smaller than the
+/// minimum size (1KiB by default). Otherwise `Vary: Accept-Encoding` is added, since the response
+/// depends on the client's header. Compressed responses lose their `Content-Length` and
+/// `Accept-Ranges` headers, and a strong `ETag` is weakened, as the bytes sent differ from the
+/// original representation.
+#[derive(Clone, Debug)]
+pub struct Compression {
+    encodings: Vec<Encoding>,
+    min_size: u64,
+    skipped_types: Vec<String>,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            encodings: vec![Encoding::Brotli, Encoding::Gzip, Encoding::Deflate],
+            min_size: 1024,
+            skipped_types: SKIPPED_TYPES.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
+impl Compression {
+    /// Construct the middleware with the default configuration.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the codings which may be used, in order of preference. The default is brotli, gzip
+    /// and deflate.
+    pub fn with_encodings(mut self, encodings: &[Encoding]) -> Self {
+        self.encodings = encodings.to_vec();
+        self
+    }
+
+    /// Set the smallest body, in bytes, which is compressed. Bodies of unknown size are always
+    /// compressed.
+    pub fn with_min_size(mut self, size: u64) -> Self {
+        self.min_size = size;
+        self
+    }
+
+    /// Never compress responses of this content type. A type ending in `/`, such as `image/`,
+    /// matches every subtype.
+    pub fn with_skipped_type(mut self, content_type: &str) -> Self {
+        self.skipped_types.push(content_type.to_ascii_lowercase());
+        self
+    }
+
+    /// Turn the configuration into a [crate::handler::Handler].
+    pub fn handler<S: Clone + Send + 'static, T: TransientState + 'static>(self) -> Handler<S, T> {
+        let compression = Arc::new(self);
+
+        Handler::from_fn(
+            move |req, resp, _params, _app, state| {
+                let compression = compression.clone();
+
+                Box::pin(async move {
+                    let resp = resp.map(|resp| compression.apply(&req, resp));
+                    Ok((req, resp, state))
+                })
+            },
+            None,
+        )
+    }
+
+    fn apply(&self, req: &Request<Body>, resp: Response<Body>) -> Response<Body> {
+        if !self.eligible(resp.headers(), resp.status()) {
+            return resp;
+        }
+
+        let (mut parts, body) = resp.into_parts();
+        add_vary(&mut parts.headers, "accept-encoding");
+
+        let small = body.size_hint().exact().is_some_and(|n| n < self.min_size);
+        let encoding = req
+            .headers()
+            .get(ACCEPT_ENCODING)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| negotiate(v, &self.encodings));
+
+        let encoding = match encoding {
+            Some(encoding) if !small && req.method() != Method::HEAD => encoding,
+            _ => return Response::from_parts(parts, body),
+        };
+
+        parts.headers.remove(CONTENT_LENGTH);
+        parts.headers.remove(ACCEPT_RANGES);
+        parts.headers.insert(
+            CONTENT_ENCODING,
+            HeaderValue::from_static(encoding.as_str()),
+        );
+        if let Some(etag) = parts.headers.get(ETAG).and_then(|v| v.to_str().ok()) {
+            if etag.starts_with('"') {
+                if let Ok(weak) = HeaderValue::from_str(&format!("W/{}", etag)) {
+                    parts.headers.insert(ETAG, weak);
+                }
+            }
+        }
+
+        Response::from_parts(parts, encode(body, encoding))
+    }
+
+    // Whether the response is a candidate for compression at all, regardless of the request.
+    fn eligible(&self, headers: &HeaderMap, status: StatusCode) -> bool {
+        if status.is_informational()
+            || status == StatusCode::NO_CONTENT
+            || status == StatusCode::NOT_MODIFIED
+            || status == StatusCode::PARTIAL_CONTENT
+            || headers.contains_key(CONTENT_ENCODING)
+            || headers.contains_key(CONTENT_RANGE)
+        {
+            return false;
+        }
+
+        let no_transform = headers.get_all(CACHE_CONTROL).iter().any(|v| {
+            v.to_str()
+                .unwrap_or_default()
+                .split(',')
+                .any(|d| d.trim().eq_ignore_ascii_case("no-transform"))
+        });
+        if no_transform {
+            return false;
+        }
+
+        let content_type = headers
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(';').next())
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+
+        // svg is an image format, but it is text.
+        content_type == "image/svg+xml"
+            || !self.skipped_types.iter().any(|skipped| {
+                if skipped.ends_with('/') {
+                    content_type.starts_with(skipped.as_str())
+                } else {
+                    &content_type == skipped
+                }
+            })
+    }
+}
+
+/// Compress responses for the handlers before it in the chain, with the default [Compression]
+/// configuration.
+pub fn compression<S: Clone + Send + 'static, T: TransientState + 'static>() -> Handler<S, T> {
+    Compression::default().handler()
+}
+
+/// Decompression is middleware which decodes request bodies sent with a `Content-Encoding` of
+/// `gzip`, `deflate` or `br` (or several, applied in order), so that the handlers after it in
+/// the chain see the original body. It requires the `compression` feature:
+///
+/// ```ignore
+///     app.post("/upload", decompression().then(compose_handler!(upload)))?;
+/// ```
+///
+/// The body is decoded into memory before the next handler runs, and decoding stops as soon as
+/// the output exceeds the size limit (10MiB by default), answering 413 Payload Too Large; this
+/// guards against small bodies which decompress to enormous ones. Corrupt bodies are answered
+/// with 400 Bad Request and unknown codings with 415 Unsupported Media Type. Decoded requests
+/// lose their `Content-Encoding` header and carry the decoded `Content-Length`.
+#[derive(Clone, Debug)]
+pub struct Decompression {
+    max_size: u64,
+}
+
+impl Default for Decompression {
+    fn default() -> Self {
+        Self {
+            max_size: 10 * 1024 * 1024,
+        }
+    }
+}
+
+impl Decompression {
+    /// Construct the middleware with the default configuration.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the largest decoded body, in bytes, which is accepted.
+    pub fn with_max_size(mut self, size: u64) -> Self {
+        self.max_size = size;
+        self
+    }
+
+    /// Turn the configuration into a [crate::handler::Handler].
+    pub fn handler<S: Clone + Send + 'static, T: TransientState + 'static>(self) -> Handler<S, T> {
+        let decompression = Arc::new(self);
+
+        Handler::from_fn(
+            move |req, resp, _params, _app, state| {
+                let decompression = decompression.clone();
+
+                Box::pin(async move {
+                    let req = decompression.apply(req).await?;
+                    Ok((req, resp, state))
+                })
+            },
+            None,
+        )
+    }
+
+    async fn apply(&self, req: Request<Body>) -> Result<Request<Body>, Error> {
+        let codings: Vec<String> = req
+            .headers()
+            .get_all(CONTENT_ENCODING)
+            .iter()
+            .flat_map(|v| v.to_str().unwrap_or_default().split(','))
+            .map(|c| c.trim().to_string())
+            .filter(|c| !c.is_empty() && !c.eq_ignore_ascii_case("identity"))
+            .collect();
+
+        if codings.is_empty() {
+            return Ok(req);
+        }
+
+        // the decoded body must also fit within the request's limit.
+        let max_size =
+            BodyLimit::from_request(&req).map_or(self.max_size, |l| l.min(self.max_size));
+        let (mut parts, body) = req.into_parts();
+
+        let mut reader: Box<dyn AsyncBufRead + Unpin + Send> = Box::new(StreamReader::new(
+            TryStreamExt::map_err(body, io::Error::other),
+        ));
+
+        // codings are listed in the order they were applied.
+        for coding in codings.iter().rev() {
+            reader = match Encoding::from_name(coding) {
+                Some(Encoding::Brotli) => Box::new(BufReader::new(BrotliDecoder::new(reader))),
+                Some(Encoding::Gzip) => Box::new(BufReader::new(GzipDecoder::new(reader))),
+                Some(Encoding::Deflate) => Box::new(BufReader::new(DeflateDecoder::new(reader))),
+                None => {
+                    return Err(Error::new_status(
+                        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                        format!("unsupported content encoding: {}", coding),
+                    ))
+                }
+            };
+        }
+
+        let mut decoded = Vec::new();
+        reader
+            .take(max_size + 1)
+            .read_to_end(&mut decoded)
+            .await
+            .map_err(|e| {
+                if exceeds_limit(&e) {
+                    return too_large();
+                }
+
+                Error::new_status(
+                    StatusCode::BAD_REQUEST,
+                    format!("could not decode request body: {}", e),
+                )
+            })?;
+
+        if decoded.len() as u64 > max_size {
+            return Err(Error::new_status(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "decoded request body is too large",
+            ));
+        }
+
+        parts.headers.remove(CONTENT_ENCODING);
+        parts
+            .headers
+            .insert(CONTENT_LENGTH, HeaderValue::from(decoded.len()));
+
+        Ok(Request::from_parts(parts, Body::from(decoded)))
+    }
+}
+
+/// Decode request bodies for the handlers after it in the chain, with the default
+/// [Decompression] configuration.
+pub fn decompression<S: Clone + Send + 'static, T: TransientState + 'static>() -> Handler<S, T> {
+    Decompression::default().handler()
+}
+
+// Stream the body through the encoder.
+fn encode(body: Body, encoding: Encoding) -> Body {
+    let reader = StreamReader::new(TryStreamExt::map_err(body, io::Error::other));
+
+    match encoding {
+        Encoding::Brotli => Body::wrap_stream(ReaderStream::new(BrotliEncoder::new(reader))),
+        Encoding::Gzip => Body::wrap_stream(ReaderStream::new(GzipEncoder::new(reader))),
+        Encoding::Deflate => Body::wrap_stream(ReaderStream::new(DeflateEncoder::new(reader))),
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_negotiate() {
+        use super::{negotiate, Encoding};
+
+        let all = [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate];
+
+        assert_eq!(negotiate("gzip, deflate, br", &all), Some(Encoding::Brotli));
+        assert_eq!(negotiate("gzip, deflate", &all), Some(Encoding::Gzip));
+        assert_eq!(
+            negotiate("deflate;q=0.5, gzip;q=0.8", &all),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(negotiate("br;q=0, *", &all), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*;q=0, deflate", &all), Some(Encoding::Deflate));
+        assert_eq!(negotiate("GZIP", &all), Some(Encoding::Gzip));
+        assert_eq!(negotiate("x-gzip", &all), Some(Encoding::Gzip));
+        assert_eq!(negotiate("identity", &all), None);
+        assert_eq!(negotiate("*;q=0", &all), None);
+        assert_eq!(negotiate("", &all), None);
+        assert_eq!(negotiate("br", &[Encoding::Gzip]), None);
+        assert_eq!(
+            negotiate("br, gzip", &[Encoding::Gzip, Encoding::Brotli]),
+            Some(Encoding::Gzip)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        use super::{compression, Compression, Encoding};
+        use crate::{
+            app::{App, TestApp},
+            compose_handler, HTTPResult, NoState, Params,
+        };
+        use async_compression::tokio::bufread::{BrotliDecoder, DeflateDecoder, GzipDecoder};
+        use http::{Method, Request, Response, StatusCode};
+        use hyper::Body;
+        use tokio::io::AsyncReadExt;
+
+        async fn text(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            params: Params,
+            _app: App<(), NoState>,
+            _state: NoState,
+        ) -> HTTPResult<NoState> {
+            let size: usize = params["size"].parse().unwrap();
+            Ok((
+                req,
+                Some(
+                    Response::builder()
+                        .status(200)
+                        .header("content-type", "text/plain; charset=utf-8")
+                        .header("etag", "\"text\"")
+                        .body(Body::from("davisjr ".repeat(size / 8)))?,
+                ),
+                NoState {},
+            ))
+        }
+
+        async fn image(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            _state: NoState,
+        ) -> HTTPResult<NoState> {
+            Ok((
+                req,
+                Some(
+                    Response::builder()
+                        .status(200)
+                        .header("content-type", "image/png")
+                        .body(Body::from(vec![0; 4096]))?,
+                ),
+                NoState {},
+            ))
+        }
+
+        async fn stream(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            _state: NoState,
+        ) -> HTTPResult<NoState> {
+            let (mut tx, body) = Body::channel();
+            tokio::spawn(async move {
+                for _ in 0..10 {
+                    tx.send_data("streamed ".into()).await.unwrap();
+                }
+            });
+
+            Ok((req, Some(Response::new(body)), NoState {}))
+        }
+
+        let mut app = App::new();
+        app.get("/text/:size", compose_handler!(text).then(compression()))
+            .unwrap();
+        app.get(
+            "/gzip/:size",
+            compose_handler!(text).then(
+                Compression::new()
+                    .with_encodings(&[Encoding::Gzip])
+                    .with_min_size(0)
+                    .handler(),
+            ),
+        )
+        .unwrap();
+        app.get("/image", compose_handler!(image)).unwrap();
+        app.get("/stream", compose_handler!(stream)).unwrap();
+        app.with_after_hook(compression());
+        let test_app = TestApp::new(app);
+
+        let expected = "davisjr ".repeat(512);
+
+        for (encoding, accept) in [
+            ("br", "gzip, br"),
+            ("gzip", "gzip;q=1, br;q=0.5"),
+            ("deflate", "deflate"),
+        ] {
+            let resp = test_app
+                .request(Method::GET, "/text/4096")
+                .header("accept-encoding", accept)
+                .send()
+                .await;
+            resp.assert_status(StatusCode::OK)
+                .assert_header("content-encoding", encoding)
+                .assert_header("vary", "accept-encoding")
+                .assert_header("etag", "W/\"text\"");
+            assert!(resp.header("content-length").is_none());
+            assert!(resp.bytes().len() < expected.len());
+
+            let compressed = resp.bytes();
+            let mut decoded = String::new();
+            match encoding {
+                "br" => BrotliDecoder::new(&compressed[..])
+                    .read_to_string(&mut decoded)
+                    .await
+                    .unwrap(),
+                "gzip" => GzipDecoder::new(&compressed[..])
+                    .read_to_string(&mut decoded)
+                    .await
+                    .unwrap(),
+                _ => DeflateDecoder::new(&compressed[..])
+                    .read_to_string(&mut decoded)
+                    .await
+                    .unwrap(),
+            };
+            assert_eq!(decoded, expected);
+        }
+
+        // the route's compression has applied, so the after hook leaves the response alone.
+        let resp = test_app
+            .request(Method::GET, "/gzip/16")
+            .header("accept-encoding", "br, gzip")
+            .send()
+            .await;
+        resp.assert_header("content-encoding", "gzip");
+        assert_eq!(resp.headers().get_all("vary").iter().count(), 1);
+
+        // small bodies and clients which do not ask are answered plainly, but still vary.
+        for (path, accept) in [("/text/64", "gzip"), ("/text/4096", "identity")] {
+            let resp = test_app
+                .request(Method::GET, path)
+                .header("accept-encoding", accept)
+                .send()
+                .await;
+            assert!(resp.header("content-encoding").is_none());
+            resp.assert_header("vary", "accept-encoding")
+                .assert_header("etag", "\"text\"");
+        }
+
+        let resp = test_app
+            .request(Method::GET, "/image")
+            .header("accept-encoding", "gzip")
+            .send()
+            .await;
+        assert!(resp.header("content-encoding").is_none());
+        assert!(resp.header("vary").is_none());
+        assert_eq!(resp.bytes().len(), 4096);
+
+        // bodies of unknown size are compressed as they stream.
+        let resp = test_app
+            .request(Method::GET, "/stream")
+            .header("accept-encoding", "gzip")
+            .send()
+            .await;
+        resp.assert_header("content-encoding", "gzip");
+        let mut decoded = String::new();
+        GzipDecoder::new(&resp.bytes()[..])
+            .read_to_string(&mut decoded)
+            .await
+            .unwrap();
+        assert_eq!(decoded, "streamed ".repeat(10));
+
+        // the after hook sees error responses too, which are small.
+        let resp = test_app
+            .request(Method::GET, "/missing")
+            .header("accept-encoding", "gzip")
+            .send()
+            .await;
+        resp.assert_status(StatusCode::METHOD_NOT_ALLOWED)
+            .assert_header("vary", "accept-encoding");
+    }
+
+    #[tokio::test]
+    async fn test_decompression() {
+        use super::{decompression, Decompression};
+        use crate::{
+            app::{App, TestApp},
+            compose_handler, HTTPResult, NoState, Params,
+        };
+        use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder};
+        use http::{Method, Request, Response, StatusCode};
+        use hyper::Body;
+        use tokio::io::AsyncReadExt;
+
+        async fn upload(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            _state: NoState,
+        ) -> HTTPResult<NoState> {
+            let (parts, body) = req.into_parts();
+            let value: serde_json::Value =
+                serde_json::from_slice(&hyper::body::to_bytes(body).await?)?;
+            let body = format!(
+                "{} {:?} {:?}",
+                value["name"].as_str().unwrap(),
+                parts.headers.get("content-encoding"),
+                parts.headers.get("content-length"),
+            );
+
+            Ok((
+                Request::from_parts(parts, Body::default()),
+                Some(Response::builder().status(200).body(Body::from(body))?),
+                NoState {},
+            ))
+        }
+
+        let mut app = App::new();
+        app.post("/upload", decompression().then(compose_handler!(upload)))
+            .unwrap();
+        app.post(
+            "/small",
+            Decompression::new()
+                .with_max_size(64)
+                .handler()
+                .then(compose_handler!(upload)),
+        )
+        .unwrap();
+        let test_app = TestApp::new(app);
+
+        let json = format!(r#"{{"name":"erik","padding":"{}"}}"#, " ".repeat(1000));
+
+        let mut gzipped = Vec::new();
+        GzipEncoder::new(json.as_bytes())
+            .read_to_end(&mut gzipped)
+            .await
+            .unwrap();
+        // brotli, then gzip on top.
+        let mut brotli = Vec::new();
+        BrotliEncoder::new(json.as_bytes())
+            .read_to_end(&mut brotli)
+            .await
+            .unwrap();
+        let mut layered = Vec::new();
+        GzipEncoder::new(&brotli[..])
+            .read_to_end(&mut layered)
+            .await
+            .unwrap();
+
+        let expected = format!("erik None Some({:?})", json.len().to_string());
+
+        for (encoding, body) in [
+            ("gzip", gzipped.clone()),
+            ("br, gzip", layered),
+            ("identity", json.clone().into_bytes()),
+        ] {
+            let resp = test_app
+                .request(Method::POST, "/upload")
+                .header("content-encoding", encoding)
+                .body(body)
+                .send()
+                .await;
+            resp.assert_status(StatusCode::OK);
+            if encoding == "identity" {
+                assert_eq!(resp.text(), "erik Some(\"identity\") None");
+            } else {
+                assert_eq!(resp.text(), expected);
+            }
+        }
+
+        let resp = test_app
+            .request(Method::POST, "/upload")
+            .body(json.clone())
+            .header("content-length", json.len().to_string())
+            .send()
+            .await;
+        assert_eq!(resp.text(), expected);
+
+        test_app
+            .request(Method::POST, "/small")
+            .header("content-encoding", "gzip")
+            .body(gzipped)
+            .send()
+            .await
+            .assert_status(StatusCode::PAYLOAD_TOO_LARGE);
+
+        test_app
+            .request(Method::POST, "/upload")
+            .header("content-encoding", "gzip")
+            .body(json.clone())
+            .send()
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+
+        test_app
+            .request(Method::POST, "/upload")
+            .header("content-encoding", "zstd")
+            .body(json)
+            .send()
+            .await
+            .assert_status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+}
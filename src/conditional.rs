@@ -0,0 +1,579 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use http::{
+    header::{
+        HeaderName, CACHE_CONTROL, CONTENT_LOCATION, DATE, ETAG, EXPIRES, IF_MATCH,
+        IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_UNMODIFIED_SINCE, LAST_MODIFIED, VARY,
+    },
+    HeaderMap, HeaderValue, Method, Request, Response, StatusCode,
+};
+use hyper::{body::HttpBody, Body};
+
+use crate::{errors::Error, handler::Handler, TransientState};
+
+// Headers a 304 Not Modified response keeps from the response it replaces.
+const NOT_MODIFIED_HEADERS: [HeaderName; 7] = [
+    CACHE_CONTROL,
+    CONTENT_LOCATION,
+    DATE,
+    ETAG,
+    EXPIRES,
+    LAST_MODIFIED,
+    VARY,
+];
+
+/// EntityTag is an HTTP entity tag, as found in `ETag`, `If-Match` and `If-None-Match` headers.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct EntityTag {
+    /// Weak tags (`W/"..."`) only indicate semantic equivalence, and never satisfy `If-Match`.
+    pub weak: bool,
+    /// The opaque tag, without quotes.
+    pub tag: String,
+}
+
+impl EntityTag {
+    /// Construct a strong entity tag. Panics if the tag contains a double quote.
+    pub fn strong(tag: &str) -> Self {
+        assert!(!tag.contains('"'), "entity tags may not contain quotes");
+        Self {
+            weak: false,
+            tag: tag.to_string(),
+        }
+    }
+
+    /// Construct a weak entity tag. Panics if the tag contains a double quote.
+    pub fn weak(tag: &str) -> Self {
+        Self {
+            weak: true,
+            ..Self::strong(tag)
+        }
+    }
+
+    /// Compute a strong entity tag from the content of a response body.
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        // FNV-1a; stable across builds, which matters for tags handed to caches.
+        let hash = bytes.iter().fold(0xcbf29ce484222325u64, |hash, b| {
+            (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+        });
+        Self::strong(&format!("{:016x}-{:x}", hash, bytes.len()))
+    }
+
+    /// Compute a strong entity tag from a file's size and modification time, so that the file
+    /// need not be read.
+    pub fn from_metadata(len: u64, modified: Option<SystemTime>) -> Self {
+        let modified = modified
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        Self::strong(&format!("{:x}-{:x}", modified.as_secs(), len))
+    }
+
+    /// Compare tags with the strong comparison function: both must be strong and identical.
+    pub fn strong_eq(&self, other: &Self) -> bool {
+        !self.weak && !other.weak && self.tag == other.tag
+    }
+
+    /// Compare tags with the weak comparison function: the opaque tags must be identical.
+    pub fn weak_eq(&self, other: &Self) -> bool {
+        self.tag == other.tag
+    }
+
+    /// Parse a comma-separated list of entity tags, as found in `If-Match` and `If-None-Match`.
+    /// Returns [std::option::Option::None] if the list is malformed.
+    pub fn parse_list(s: &str) -> Option<Vec<Self>> {
+        let mut tags = Vec::new();
+        let mut rest = s.trim_start_matches([' ', '\t', ',']);
+
+        while !rest.is_empty() {
+            let weak = rest.starts_with("W/");
+            if weak {
+                rest = &rest[2..];
+            }
+
+            rest = rest.strip_prefix('"')?;
+            let end = rest.find('"')?;
+            tags.push(Self {
+                weak,
+                tag: rest[..end].to_string(),
+            });
+
+            rest = rest[end + 1..].trim_start_matches([' ', '\t']);
+            if !rest.is_empty() {
+                rest = rest.strip_prefix(',')?.trim_start_matches([' ', '\t', ',']);
+            }
+        }
+
+        Some(tags)
+    }
+}
+
+impl std::str::FromStr for EntityTag {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match Self::parse_list(s) {
+            Some(mut tags) if tags.len() == 1 => Ok(tags.remove(0)),
+            _ => Err(Error::new(format!("invalid entity tag: {}", s))),
+        }
+    }
+}
+
+impl std::fmt::Display for EntityTag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.weak {
+            f.write_str("W/")?;
+        }
+        write!(f, "\"{}\"", self.tag)
+    }
+}
+
+/// Evaluate the request's preconditions (`If-Match`, `If-Unmodified-Since`, `If-None-Match` and
+/// `If-Modified-Since`, in the order of RFC 9110) against the current validators of the target
+/// resource. Returns the status to answer with instead of the usual response: `304 Not Modified`
+/// or `412 Precondition Failed`. [std::option::Option::None] means the request should proceed.
+///
+/// Handlers for unsafe methods should call this before making changes, so that a conflicting
+/// update is refused rather than merely reported:
+///
+/// ```ignore
+///     if let Some(status) = check_preconditions(&req, Some(&document.etag()), None) {
+///         return Err(Error::new_status(status, "document has changed"));
+///     }
+/// ```
+pub fn check_preconditions<B>(
+    req: &Request<B>,
+    etag: Option<&EntityTag>,
+    last_modified: Option<SystemTime>,
+) -> Option<StatusCode> {
+    let headers = req.headers();
+    let last_modified = last_modified.map(truncate_to_seconds);
+
+    if let Some(value) = header_str(headers, &IF_MATCH) {
+        if !matches_any(value, etag, EntityTag::strong_eq) {
+            return Some(StatusCode::PRECONDITION_FAILED);
+        }
+    } else if let (Some(since), Some(modified)) =
+        (header_date(headers, &IF_UNMODIFIED_SINCE), last_modified)
+    {
+        if modified > since {
+            return Some(StatusCode::PRECONDITION_FAILED);
+        }
+    }
+
+    let safe = req.method() == Method::GET || req.method() == Method::HEAD;
+
+    if let Some(value) = header_str(headers, &IF_NONE_MATCH) {
+        if matches_any(value, etag, EntityTag::weak_eq) {
+            return Some(if safe {
+                StatusCode::NOT_MODIFIED
+            } else {
+                StatusCode::PRECONDITION_FAILED
+            });
+        }
+    } else if let (true, Some(since), Some(modified)) = (
+        safe,
+        header_date(headers, &IF_MODIFIED_SINCE),
+        last_modified,
+    ) {
+        if modified <= since {
+            return Some(StatusCode::NOT_MODIFIED);
+        }
+    }
+
+    None
+}
+
+/// Build the response for a status returned by [check_preconditions]. A `304 Not Modified`
+/// response keeps the validators and caching headers of the response it replaces.
+pub fn precondition_response(status: StatusCode, headers: &HeaderMap) -> Response<Body> {
+    let mut resp = Response::new(Body::default());
+    *resp.status_mut() = status;
+
+    if status == StatusCode::NOT_MODIFIED {
+        for name in NOT_MODIFIED_HEADERS.iter() {
+            for value in headers.get_all(name) {
+                resp.headers_mut().append(name, value.clone());
+            }
+        }
+    }
+
+    resp
+}
+
+/// Format a time for the `Last-Modified` header.
+pub fn last_modified_value(time: SystemTime) -> HeaderValue {
+    HeaderValue::from_str(&httpdate::fmt_http_date(time)).unwrap()
+}
+
+/// Conditional is middleware which answers conditional requests on behalf of the handlers before
+/// it in the chain. It reads the `ETag` and `Last-Modified` headers of their successful
+/// responses, computing an `ETag` from the body for responses without one, and replaces the
+/// response with `304 Not Modified` or `412 Precondition Failed` as the request's preconditions
+/// require:
+///
+/// ```ignore
+///     app.get("/report", compose_handler!(report).then(conditional()))?;
+/// ```
+///
+/// Bodies are only buffered to compute an `ETag` when their size is known and no larger than the
+/// limit (1MiB by default), so streamed responses pass through untouched.
+///
+/// As it runs after the response has been produced, Conditional cannot stop an unsafe request
+/// from taking effect; handlers which modify resources should use [check_preconditions] first.
+#[derive(Clone, Debug)]
+pub struct Conditional {
+    compute_etags: bool,
+    max_etag_size: u64,
+}
+
+impl Default for Conditional {
+    fn default() -> Self {
+        Self {
+            compute_etags: true,
+            max_etag_size: 1024 * 1024,
+        }
+    }
+}
+
+impl Conditional {
+    /// Construct the middleware with the default configuration.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether to compute an `ETag` for responses without one.
+    pub fn with_computed_etags(mut self, enabled: bool) -> Self {
+        self.compute_etags = enabled;
+        self
+    }
+
+    /// The largest body, in bytes, which is buffered to compute an `ETag`.
+    pub fn with_max_etag_size(mut self, size: u64) -> Self {
+        self.max_etag_size = size;
+        self
+    }
+
+    /// Turn the configuration into a [crate::handler::Handler], to be appended to a chain with
+    /// [crate::handler::Handler::then].
+    pub fn handler<S: Clone + Send + 'static, T: TransientState + 'static>(self) -> Handler<S, T> {
+        let conditional = Arc::new(self);
+
+        Handler::from_fn(
+            move |req, resp, _params, _app, state| {
+                let conditional = conditional.clone();
+
+                Box::pin(async move {
+                    let resp = match resp {
+                        Some(resp) => Some(conditional.apply(&req, resp).await?),
+                        None => None,
+                    };
+
+                    Ok((req, resp, state))
+                })
+            },
+            None,
+        )
+    }
+
+    async fn apply(
+        &self,
+        req: &Request<Body>,
+        resp: Response<Body>,
+    ) -> Result<Response<Body>, Error> {
+        if !resp.status().is_success() {
+            return Ok(resp);
+        }
+
+        let (mut parts, mut body) = resp.into_parts();
+
+        if self.compute_etags && !parts.headers.contains_key(ETAG) {
+            if let Some(size) = body.size_hint().exact() {
+                if size <= self.max_etag_size {
+                    let bytes = hyper::body::to_bytes(body).await?;
+                    parts.headers.insert(
+                        ETAG,
+                        HeaderValue::from_str(&EntityTag::from_bytes(&bytes).to_string())?,
+                    );
+                    body = Body::from(bytes);
+                }
+            }
+        }
+
+        let etag = header_str(&parts.headers, &ETAG).and_then(|s| s.parse().ok());
+        let last_modified = header_date(&parts.headers, &LAST_MODIFIED);
+
+        Ok(
+            match check_preconditions(req, etag.as_ref(), last_modified) {
+                Some(status) => precondition_response(status, &parts.headers),
+                None => Response::from_parts(parts, body),
+            },
+        )
+    }
+}
+
+/// Answer conditional requests for the handlers before it in the chain, with the default
+/// [Conditional] configuration.
+pub fn conditional<S: Clone + Send + 'static, T: TransientState + 'static>() -> Handler<S, T> {
+    Conditional::default().handler()
+}
+
+// Whether an If-Match or If-None-Match value matches the current entity tag. `*` matches any
+// current representation.
+fn matches_any(
+    value: &str,
+    etag: Option<&EntityTag>,
+    eq: fn(&EntityTag, &EntityTag) -> bool,
+) -> bool {
+    match etag {
+        None => false,
+        Some(_) if value.trim() == "*" => true,
+        Some(etag) => EntityTag::parse_list(value)
+            .unwrap_or_default()
+            .iter()
+            .any(|tag| eq(tag, etag)),
+    }
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &HeaderName) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+// Invalid dates are ignored, as RFC 9110 requires.
+pub(crate) fn header_date(headers: &HeaderMap, name: &HeaderName) -> Option<SystemTime> {
+    header_str(headers, name).and_then(|s| httpdate::parse_http_date(s).ok())
+}
+
+// HTTP dates have a resolution of one second.
+fn truncate_to_seconds(time: SystemTime) -> SystemTime {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => UNIX_EPOCH + Duration::from_secs(d.as_secs()),
+        Err(_) => time,
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_entity_tags() {
+        use super::EntityTag;
+
+        assert_eq!(
+            EntityTag::parse_list(r#""a", W/"b",,"c,d" "#),
+            Some(vec![
+                EntityTag::strong("a"),
+                EntityTag::weak("b"),
+                EntityTag::strong("c,d")
+            ])
+        );
+        assert_eq!(EntityTag::parse_list(""), Some(vec![]));
+        assert_eq!(EntityTag::parse_list("a"), None);
+        assert_eq!(EntityTag::parse_list(r#""a" "b""#), None);
+        assert_eq!(EntityTag::parse_list(r#""a"#), None);
+
+        assert_eq!(
+            "W/\"x\"".parse::<EntityTag>().unwrap(),
+            EntityTag::weak("x")
+        );
+        assert!("\"x\", \"y\"".parse::<EntityTag>().is_err());
+        assert_eq!(EntityTag::weak("x").to_string(), "W/\"x\"");
+
+        assert!(EntityTag::strong("x").strong_eq(&EntityTag::strong("x")));
+        assert!(!EntityTag::weak("x").strong_eq(&EntityTag::strong("x")));
+        assert!(EntityTag::weak("x").weak_eq(&EntityTag::strong("x")));
+        assert!(!EntityTag::weak("x").weak_eq(&EntityTag::weak("y")));
+
+        assert_eq!(EntityTag::from_bytes(b"one"), EntityTag::from_bytes(b"one"));
+        assert_ne!(EntityTag::from_bytes(b"one"), EntityTag::from_bytes(b"two"));
+    }
+
+    #[test]
+    fn test_check_preconditions() {
+        use super::{check_preconditions, EntityTag};
+        use http::{Method, Request, StatusCode};
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let etag = EntityTag::strong("v2");
+        let modified = UNIX_EPOCH + Duration::from_millis(1_700_000_000_500);
+        let before = "Tue, 14 Nov 2023 22:13:19 GMT";
+        let exact = "Tue, 14 Nov 2023 22:13:20 GMT";
+
+        let check = |method: Method, headers: &[(&str, &str)]| {
+            let mut req = Request::builder().method(method);
+            for (name, value) in headers {
+                req = req.header(*name, *value);
+            }
+            check_preconditions(&req.body(()).unwrap(), Some(&etag), Some(modified))
+        };
+
+        assert_eq!(check(Method::GET, &[]), None);
+
+        let not_modified = Some(StatusCode::NOT_MODIFIED);
+        let failed = Some(StatusCode::PRECONDITION_FAILED);
+
+        assert_eq!(
+            check(Method::GET, &[("if-none-match", "\"v2\"")]),
+            not_modified
+        );
+        assert_eq!(
+            check(Method::HEAD, &[("if-none-match", "W/\"v2\"")]),
+            not_modified
+        );
+        assert_eq!(check(Method::GET, &[("if-none-match", "\"v1\", *")]), None);
+        assert_eq!(check(Method::GET, &[("if-none-match", "*")]), not_modified);
+        assert_eq!(check(Method::PUT, &[("if-none-match", "*")]), failed);
+        assert_eq!(check(Method::GET, &[("if-none-match", "\"v1\"")]), None);
+
+        assert_eq!(
+            check(Method::GET, &[("if-modified-since", exact)]),
+            not_modified
+        );
+        assert_eq!(check(Method::GET, &[("if-modified-since", before)]), None);
+        assert_eq!(check(Method::POST, &[("if-modified-since", exact)]), None);
+        assert_eq!(
+            check(Method::GET, &[("if-modified-since", "yesterday")]),
+            None
+        );
+        // If-None-Match takes precedence.
+        assert_eq!(
+            check(
+                Method::GET,
+                &[("if-none-match", "\"v1\""), ("if-modified-since", exact)]
+            ),
+            None
+        );
+
+        assert_eq!(check(Method::PUT, &[("if-match", "\"v2\"")]), None);
+        assert_eq!(check(Method::PUT, &[("if-match", "*")]), None);
+        assert_eq!(check(Method::PUT, &[("if-match", "\"v1\"")]), failed);
+        assert_eq!(check(Method::PUT, &[("if-match", "W/\"v2\"")]), failed);
+        assert_eq!(check(Method::PUT, &[("if-unmodified-since", exact)]), None);
+        assert_eq!(
+            check(Method::PUT, &[("if-unmodified-since", before)]),
+            failed
+        );
+        // If-Match takes precedence.
+        assert_eq!(
+            check(
+                Method::PUT,
+                &[("if-match", "\"v2\""), ("if-unmodified-since", before)]
+            ),
+            None
+        );
+
+        // without a current representation, only wildcards and If-Match are affected.
+        let req = Request::builder().header("if-match", "*").body(()).unwrap();
+        assert_eq!(check_preconditions(&req, None, None), failed);
+        let req = Request::builder()
+            .header("if-none-match", "*")
+            .body(())
+            .unwrap();
+        assert_eq!(check_preconditions(&req, None, None), None);
+    }
+
+    #[tokio::test]
+    async fn test_conditional() {
+        use super::{conditional, Conditional};
+        use crate::{
+            app::{App, TestApp},
+            compose_handler, HTTPResult, NoState, Params,
+        };
+        use http::{Method, Request, Response, StatusCode};
+        use hyper::Body;
+
+        async fn report(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            _state: NoState,
+        ) -> HTTPResult<NoState> {
+            Ok((
+                req,
+                Some(
+                    Response::builder()
+                        .status(200)
+                        .header("cache-control", "max-age=60")
+                        .header("content-type", "text/plain")
+                        .body(Body::from("quarterly numbers"))?,
+                ),
+                NoState {},
+            ))
+        }
+
+        async fn document(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            _state: NoState,
+        ) -> HTTPResult<NoState> {
+            Ok((
+                req,
+                Some(
+                    Response::builder()
+                        .status(200)
+                        .header("etag", "\"doc-1\"")
+                        .header("last-modified", "Tue, 14 Nov 2023 22:13:20 GMT")
+                        .body(Body::from("document"))?,
+                ),
+                NoState {},
+            ))
+        }
+
+        let mut app = App::new();
+        app.get("/report", compose_handler!(report).then(conditional()))
+            .unwrap();
+        app.get(
+            "/uncached",
+            compose_handler!(report).then(Conditional::new().with_computed_etags(false).handler()),
+        )
+        .unwrap();
+        app.get("/document", compose_handler!(document).then(conditional()))
+            .unwrap();
+        app.put("/document", compose_handler!(document).then(conditional()))
+            .unwrap();
+        let test_app = TestApp::new(app);
+
+        let resp = test_app.request(Method::GET, "/report").send().await;
+        resp.assert_status(StatusCode::OK);
+        assert_eq!(resp.text(), "quarterly numbers");
+        let etag = resp.header("etag").unwrap().to_string();
+
+        let resp = test_app
+            .request(Method::GET, "/report")
+            .header("if-none-match", etag.as_str())
+            .send()
+            .await;
+        resp.assert_status(StatusCode::NOT_MODIFIED)
+            .assert_header("etag", &etag)
+            .assert_header("cache-control", "max-age=60");
+        assert!(resp.header("content-type").is_none());
+        assert!(resp.bytes().is_empty());
+
+        let resp = test_app.request(Method::GET, "/uncached").send().await;
+        assert!(resp.header("etag").is_none());
+
+        test_app
+            .request(Method::GET, "/document")
+            .header("if-modified-since", "Wed, 15 Nov 2023 00:00:00 GMT")
+            .send()
+            .await
+            .assert_status(StatusCode::NOT_MODIFIED)
+            .assert_header("last-modified", "Tue, 14 Nov 2023 22:13:20 GMT");
+
+        test_app
+            .request(Method::PUT, "/document")
+            .header("if-match", "\"doc-0\"")
+            .send()
+            .await
+            .assert_status(StatusCode::PRECONDITION_FAILED);
+
+        test_app
+            .request(Method::PUT, "/document")
+            .header("if-match", "\"doc-1\"")
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+    }
+}
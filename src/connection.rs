@@ -0,0 +1,158 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use http::Request;
+
+#[cfg(feature = "unix")]
+use crate::app::PeerCredentials;
+use crate::proxy::Forwarded;
+
+static CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Transport describes how a connection reached the server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    Tcp,
+    Tls,
+    Unix,
+}
+
+/// TlsInfo carries details about the TLS session a request was received on.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// The server name the client requested through SNI, if any.
+    pub server_name: Option<String>,
+    /// The protocol negotiated through ALPN, e.g. `h2`.
+    pub alpn_protocol: Option<Vec<u8>>,
+    /// The TLS protocol version, e.g. `TLSv1_3`.
+    pub protocol_version: Option<String>,
+    /// The negotiated cipher suite.
+    pub cipher_suite: Option<String>,
+}
+
+/// ConnectionInfo describes the connection a request arrived on. It is inserted into the
+/// extensions of every request by all of the [crate::app::App] serve methods, and can be retrieved
+/// with [ConnectionInfo::from_request]:
+///
+/// ```ignore
+///     if let Some(info) = ConnectionInfo::from_request(&req) {
+///         println!("request {} from {:?}", info.id, info.peer_addr);
+///     }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// A process-unique identifier for the connection. Requests sharing a keep-alive connection
+    /// share an ID.
+    pub id: u64,
+    /// The transport the connection was accepted on.
+    pub transport: Transport,
+    /// The peer's address, including its port. [std::option::Option::None] for unix sockets.
+    pub peer_addr: Option<SocketAddr>,
+    /// The local address the connection was accepted on. [std::option::Option::None] for unix
+    /// sockets.
+    pub local_addr: Option<SocketAddr>,
+    /// The path of the unix socket the connection was accepted on.
+    pub socket_path: Option<PathBuf>,
+    /// TLS session details, when the transport is [Transport::Tls].
+    pub tls: Option<TlsInfo>,
+    /// Credentials of the peer process, when the transport is [Transport::Unix].
+    #[cfg(feature = "unix")]
+    pub peer_credentials: Option<PeerCredentials>,
+    /// The address of the load balancer which relayed the connection using the PROXY protocol.
+    /// See [crate::app::App::with_proxy_protocol].
+    pub proxy_addr: Option<SocketAddr>,
+    /// Client information reported by a trusted reverse proxy. See
+    /// [crate::app::App::with_trusted_proxies].
+    pub forwarded: Option<Forwarded>,
+}
+
+impl ConnectionInfo {
+    /// Construct connection information with a new connection ID. All addressing information is
+    /// empty.
+    pub fn new(transport: Transport) -> Self {
+        Self {
+            id: CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            transport,
+            peer_addr: None,
+            local_addr: None,
+            socket_path: None,
+            tls: None,
+            #[cfg(feature = "unix")]
+            peer_credentials: None,
+            proxy_addr: None,
+            forwarded: None,
+        }
+    }
+
+    /// The address of the client: the one reported by a trusted proxy if there is one, otherwise
+    /// the peer's address.
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        self.forwarded
+            .as_ref()
+            .and_then(|f| f.client_ip)
+            .or_else(|| self.peer_addr.map(|sa| sa.ip()))
+    }
+
+    /// The scheme the client used: the one reported by a trusted proxy if there is one, otherwise
+    /// derived from the transport.
+    pub fn scheme(&self) -> String {
+        match self.forwarded.as_ref().and_then(|f| f.proto.clone()) {
+            Some(proto) => proto,
+            None if self.transport == Transport::Tls => "https".to_string(),
+            None => "http".to_string(),
+        }
+    }
+
+    /// Retrieve the connection information inserted into the request by the server.
+    pub fn from_request<B>(req: &Request<B>) -> Option<&Self> {
+        req.extensions().get::<Self>()
+    }
+}
+
+#[cfg(feature = "tls")]
+impl From<&tokio_rustls::rustls::ServerConnection> for TlsInfo {
+    fn from(conn: &tokio_rustls::rustls::ServerConnection) -> Self {
+        Self {
+            server_name: conn.sni_hostname().map(|s| s.to_string()),
+            alpn_protocol: conn.alpn_protocol().map(|p| p.to_vec()),
+            protocol_version: conn.protocol_version().map(|v| format!("{:?}", v)),
+            cipher_suite: conn
+                .negotiated_cipher_suite()
+                .map(|s| format!("{:?}", s.suite())),
+        }
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_connection_ids() {
+        use super::{ConnectionInfo, Transport};
+
+        let one = ConnectionInfo::new(Transport::Tcp);
+        let two = ConnectionInfo::new(Transport::Tcp);
+        assert_ne!(one.id, two.id);
+
+        let mut req = http::Request::new(());
+        assert!(ConnectionInfo::from_request(&req).is_none());
+        req.extensions_mut().insert(two.clone());
+        assert_eq!(ConnectionInfo::from_request(&req), Some(&two));
+
+        let mut info = ConnectionInfo::new(Transport::Tls);
+        assert_eq!(info.client_ip(), None);
+        assert_eq!(info.scheme(), "https");
+
+        info.peer_addr = Some("10.0.0.1:1234".parse().unwrap());
+        assert_eq!(info.client_ip(), Some("10.0.0.1".parse().unwrap()));
+
+        info.forwarded = Some(crate::proxy::Forwarded {
+            client_ip: Some("1.2.3.4".parse().unwrap()),
+            proto: Some("http".to_string()),
+            host: None,
+        });
+        assert_eq!(info.client_ip(), Some("1.2.3.4".parse().unwrap()));
+        assert_eq!(info.scheme(), "http");
+    }
+}
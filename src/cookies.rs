@@ -0,0 +1,692 @@
+use std::{
+    fmt,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use http::{
+    header::{COOKIE, SET_COOKIE},
+    HeaderMap, HeaderValue, Request,
+};
+use hyper::Body;
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    hkdf, hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+
+use crate::{
+    errors::{Error, ServerError},
+    handler::Handler,
+    TransientState,
+};
+
+/// The shortest secret accepted by [Key::from_secret].
+pub const MIN_SECRET_LEN: usize = 32;
+
+/// Key signs and encrypts cookie values. Keys are usually configured on the application with
+/// [crate::app::App::with_cookie_secret], from which the [cookies] middleware hands them to each
+/// request's [Cookies] jar.
+///
+/// Two independent keys are derived from the secret with HKDF-SHA256: one for HMAC-SHA256
+/// signatures and one for AES-256-GCM encryption.
+pub struct Key {
+    signing: hmac::Key,
+    encryption: LessSafeKey,
+}
+
+impl Key {
+    /// Derive the keys from a secret of at least [MIN_SECRET_LEN] bytes. The secret should be
+    /// random and must be kept private; anyone holding it can forge cookies.
+    pub fn from_secret(secret: &[u8]) -> Result<Self, ServerError> {
+        if secret.len() < MIN_SECRET_LEN {
+            return Err(ServerError(format!(
+                "cookie secrets must be at least {} bytes",
+                MIN_SECRET_LEN
+            )));
+        }
+
+        let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, b"davisjr cookies").extract(secret);
+        let signing = prk
+            .expand(&[b"signing"], hmac::HMAC_SHA256)
+            .map_err(|_| ServerError("could not derive cookie signing key".to_string()))?
+            .into();
+        let encryption: UnboundKey = prk
+            .expand(&[b"encryption"], &AES_256_GCM)
+            .map_err(|_| ServerError("could not derive cookie encryption key".to_string()))?
+            .into();
+
+        Ok(Self {
+            signing,
+            encryption: LessSafeKey::new(encryption),
+        })
+    }
+
+    /// Generate a key from a random secret. Cookies protected with it cannot be read after the
+    /// process exits, or by other processes serving the same application.
+    pub fn generate() -> Result<Self, ServerError> {
+        let mut secret = [0u8; 64];
+        SystemRandom::new()
+            .fill(&mut secret)
+            .map_err(|_| ServerError("could not generate cookie secret".to_string()))?;
+        Self::from_secret(&secret)
+    }
+
+    // The signature covers the name, so that a signed value cannot be moved to another cookie.
+    fn sign(&self, name: &str, value: &str) -> String {
+        let tag = hmac::sign(&self.signing, format!("{}={}", name, value).as_bytes());
+        format!("{}.{}", value, URL_SAFE_NO_PAD.encode(tag.as_ref()))
+    }
+
+    fn verify(&self, name: &str, signed: &str) -> Option<String> {
+        let (value, tag) = signed.rsplit_once('.')?;
+        let tag = URL_SAFE_NO_PAD.decode(tag).ok()?;
+        hmac::verify(
+            &self.signing,
+            format!("{}={}", name, value).as_bytes(),
+            &tag,
+        )
+        .ok()?;
+
+        Some(value.to_string())
+    }
+
+    // The value is sealed with the name as associated data, for the same reason.
+    fn encrypt(&self, name: &str, value: &str) -> Result<String, Error> {
+        let mut nonce = [0u8; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| Error::new("could not generate nonce"))?;
+
+        let mut sealed = value.as_bytes().to_vec();
+        self.encryption
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(name.as_bytes()),
+                &mut sealed,
+            )
+            .map_err(|_| Error::new("could not encrypt cookie"))?;
+
+        let mut out = nonce.to_vec();
+        out.append(&mut sealed);
+        Ok(URL_SAFE_NO_PAD.encode(out))
+    }
+
+    fn decrypt(&self, name: &str, encrypted: &str) -> Option<String> {
+        let mut data = URL_SAFE_NO_PAD.decode(encrypted).ok()?;
+        if data.len() < NONCE_LEN + AES_256_GCM.tag_len() {
+            return None;
+        }
+
+        let mut sealed = data.split_off(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(&data).ok()?;
+        let plain = self
+            .encryption
+            .open_in_place(nonce, Aad::from(name.as_bytes()), &mut sealed)
+            .ok()?;
+
+        String::from_utf8(plain.to_vec()).ok()
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Key { .. }")
+    }
+}
+
+/// Cookies is the jar of cookies sent with a request in its `Cookie` headers. The [cookies]
+/// middleware parses it into the request extensions for later handlers:
+///
+/// ```ignore
+///     app.with_cookie_secret(&secret)?;
+///     app.get("/", cookies().then(compose_handler!(index)))?;
+///
+///     // and in index:
+///     let user = Cookies::from_request(&req).private("user");
+/// ```
+///
+/// Values are kept as they were sent, less any surrounding double quotes. A name may be sent more
+/// than once, in which case [Cookies::get] returns the first, which browsers send for the most
+/// specific path.
+#[derive(Clone, Debug, Default)]
+pub struct Cookies {
+    cookies: Vec<(String, String)>,
+    key: Option<Arc<Key>>,
+}
+
+impl Cookies {
+    /// Parse the `Cookie` headers. Pairs without a `=` are skipped.
+    pub fn parse(headers: &HeaderMap) -> Self {
+        let cookies = headers
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(';'))
+            .filter_map(|pair| {
+                let (name, value) = pair.split_once('=')?;
+                let name = name.trim();
+                if name.is_empty() {
+                    return None;
+                }
+
+                let value = value.trim();
+                let value = value
+                    .strip_prefix('"')
+                    .and_then(|v| v.strip_suffix('"'))
+                    .unwrap_or(value);
+                Some((name.to_string(), value.to_string()))
+            })
+            .collect();
+
+        Self { cookies, key: None }
+    }
+
+    /// The jar from the request extensions, as inserted by the [cookies] middleware, or one
+    /// parsed from the request's headers without a key.
+    pub fn from_request(req: &Request<Body>) -> Self {
+        req.extensions()
+            .get::<Self>()
+            .cloned()
+            .unwrap_or_else(|| Self::parse(req.headers()))
+    }
+
+    /// Use the key to read signed and private cookies.
+    pub fn with_key(mut self, key: Arc<Key>) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// The key used to read signed and private cookies, if any.
+    pub fn key(&self) -> Option<Arc<Key>> {
+        self.key.clone()
+    }
+
+    /// The value of the first cookie with the name.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.cookies
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The values of all cookies with the name, in the order they were sent.
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.cookies
+            .iter()
+            .filter(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+            .collect()
+    }
+
+    /// All cookies as name, value pairs, in the order they were sent.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.cookies.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    /// The number of cookies in the jar.
+    pub fn len(&self) -> usize {
+        self.cookies.len()
+    }
+
+    /// Whether the request carried no cookies.
+    pub fn is_empty(&self) -> bool {
+        self.cookies.is_empty()
+    }
+
+    /// The value of a cookie set with [SetCookie::signed], if its signature is valid. Cookies are
+    /// not trusted without a key.
+    pub fn signed(&self, name: &str) -> Option<String> {
+        let key = self.key.as_ref()?;
+        self.get_all(name)
+            .into_iter()
+            .find_map(|v| key.verify(name, v))
+    }
+
+    /// The decrypted value of a cookie set with [SetCookie::private], if it can be authenticated.
+    pub fn private(&self, name: &str) -> Option<String> {
+        let key = self.key.as_ref()?;
+        self.get_all(name)
+            .into_iter()
+            .find_map(|v| key.decrypt(name, v))
+    }
+}
+
+/// SameSite restricts when a cookie is sent with cross-site requests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SameSite {
+    /// Only sent with same-site requests.
+    Strict,
+    /// Also sent with top-level cross-site navigations.
+    Lax,
+    /// Sent with all requests; the cookie must also be `Secure`.
+    None,
+}
+
+impl fmt::Display for SameSite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Strict => "Strict",
+            Self::Lax => "Lax",
+            Self::None => "None",
+        })
+    }
+}
+
+/// SetCookie builds a `Set-Cookie` header:
+///
+/// ```ignore
+///     SetCookie::new("theme", "dark")
+///         .with_path("/")
+///         .with_max_age(Duration::from_secs(86400))
+///         .with_same_site(SameSite::Lax)
+///         .append_to(resp.headers_mut())?;
+/// ```
+///
+/// Names must be HTTP tokens, and values may not contain whitespace, double quotes, commas,
+/// semicolons or backslashes; invalid cookies are reported when the header is built.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SetCookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    max_age: Option<Duration>,
+    expires: Option<SystemTime>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+impl SetCookie {
+    /// A cookie with the name and value and no attributes, which browsers keep until the session
+    /// ends.
+    pub fn new(name: &str, value: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            value: value.to_string(),
+            path: None,
+            domain: None,
+            max_age: None,
+            expires: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+
+    /// A cookie which removes any cookie with the name. The path and domain must match those it
+    /// was set with.
+    pub fn removal(name: &str) -> Self {
+        Self::new(name, "")
+            .with_max_age(Duration::ZERO)
+            .with_expires(SystemTime::UNIX_EPOCH)
+    }
+
+    /// A cookie whose value is signed with the key, so that it can be read but not altered by the
+    /// client. Read it with [Cookies::signed].
+    pub fn signed(name: &str, value: &str, key: &Key) -> Self {
+        Self::new(name, &key.sign(name, value))
+    }
+
+    /// A cookie whose value is encrypted with the key, so that it can be neither read nor altered
+    /// by the client. Read it with [Cookies::private].
+    pub fn private(name: &str, value: &str, key: &Key) -> Result<Self, Error> {
+        Ok(Self::new(name, &key.encrypt(name, value)?))
+    }
+
+    /// Set the `Path` attribute.
+    pub fn with_path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    /// Set the `Domain` attribute.
+    pub fn with_domain(mut self, domain: &str) -> Self {
+        self.domain = Some(domain.to_string());
+        self
+    }
+
+    /// Set the `Max-Age` attribute, in whole seconds.
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Set the `Expires` attribute. `Max-Age` takes precedence where both are set.
+    pub fn with_expires(mut self, expires: SystemTime) -> Self {
+        self.expires = Some(expires);
+        self
+    }
+
+    /// Set the `Secure` attribute, so that the cookie is only sent over HTTPS.
+    pub fn with_secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// Set the `HttpOnly` attribute, hiding the cookie from scripts.
+    pub fn with_http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+
+    /// Set the `SameSite` attribute. [SameSite::None] also sets `Secure`, as browsers require.
+    pub fn with_same_site(mut self, same_site: SameSite) -> Self {
+        if same_site == SameSite::None {
+            self.secure = true;
+        }
+
+        self.same_site = Some(same_site);
+        self
+    }
+
+    /// The name of the cookie.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The value of the cookie, as it will be sent.
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    /// Build the header value, checking the name, value and attributes.
+    pub fn to_header_value(&self) -> Result<HeaderValue, Error> {
+        if self.name.is_empty() || !self.name.bytes().all(is_token) {
+            return Err(Error::new(format!("invalid cookie name: {:?}", self.name)));
+        }
+
+        if !self.value.bytes().all(is_cookie_octet) {
+            return Err(Error::new(format!(
+                "invalid value for cookie {}",
+                self.name
+            )));
+        }
+
+        for attr in self.path.iter().chain(self.domain.iter()) {
+            if attr.bytes().any(|b| b == b';' || b.is_ascii_control()) {
+                return Err(Error::new(format!(
+                    "invalid attribute for cookie {}",
+                    self.name
+                )));
+            }
+        }
+
+        Ok(HeaderValue::from_str(&self.to_string())?)
+    }
+
+    /// Append the `Set-Cookie` header to the headers, usually those of a response.
+    pub fn append_to(&self, headers: &mut HeaderMap) -> Result<(), Error> {
+        headers.append(SET_COOKIE, self.to_header_value()?);
+        Ok(())
+    }
+}
+
+impl fmt::Display for SetCookie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", path)?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", domain)?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if let Some(expires) = self.expires {
+            write!(f, "; Expires={}", httpdate::fmt_http_date(expires))?;
+        }
+        if self.secure {
+            f.write_str("; Secure")?;
+        }
+        if self.http_only {
+            f.write_str("; HttpOnly")?;
+        }
+        if let Some(same_site) = self.same_site {
+            write!(f, "; SameSite={}", same_site)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn is_token(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+// RFC 6265 cookie-octet: printable ASCII less whitespace, DQUOTE, comma, semicolon and backslash.
+fn is_cookie_octet(b: u8) -> bool {
+    matches!(b, 0x21 | 0x23..=0x2b | 0x2d..=0x3a | 0x3c..=0x5b | 0x5d..=0x7e)
+}
+
+/// Parse the request's cookies into a [Cookies] jar in its extensions, keyed with the
+/// application's secret when one was set with [crate::app::App::with_cookie_secret]. Later
+/// handlers read it with [Cookies::from_request].
+pub fn cookies<S: Clone + Send + 'static, T: TransientState + 'static>() -> Handler<S, T> {
+    Handler::from_fn(
+        |mut req, resp, _params, app, state| {
+            Box::pin(async move {
+                let mut jar = Cookies::parse(req.headers());
+                if let Some(key) = app.cookie_key() {
+                    jar = jar.with_key(key);
+                }
+
+                req.extensions_mut().insert(jar);
+                Ok((req, resp, state))
+            })
+        },
+        None,
+    )
+}
+
+mod tests {
+    #[test]
+    fn test_cookies() {
+        use super::Cookies;
+        use http::{HeaderMap, HeaderValue};
+
+        let mut headers = HeaderMap::new();
+        headers.append(
+            "cookie",
+            HeaderValue::from_static("a=1; b=\"two\";c=; junk; =x"),
+        );
+        headers.append("cookie", HeaderValue::from_static("a=3"));
+
+        let jar = Cookies::parse(&headers);
+        assert_eq!(jar.len(), 4);
+        assert_eq!(jar.get("a"), Some("1"));
+        assert_eq!(jar.get_all("a"), vec!["1", "3"]);
+        assert_eq!(jar.get("b"), Some("two"));
+        assert_eq!(jar.get("c"), Some(""));
+        assert_eq!(jar.get("junk"), None);
+        // nothing is trusted without a key.
+        assert_eq!(jar.signed("a"), None);
+
+        assert!(Cookies::parse(&HeaderMap::new()).is_empty());
+    }
+
+    #[test]
+    fn test_set_cookie() {
+        use super::{SameSite, SetCookie};
+        use std::time::{Duration, SystemTime};
+
+        assert_eq!(SetCookie::new("a", "1").to_string(), "a=1");
+        assert_eq!(
+            SetCookie::new("session", "abc")
+                .with_path("/")
+                .with_domain("example.com")
+                .with_max_age(Duration::from_secs(3600))
+                .with_expires(SystemTime::UNIX_EPOCH + Duration::from_secs(784111777))
+                .with_secure(true)
+                .with_http_only(true)
+                .with_same_site(SameSite::Strict)
+                .to_header_value()
+                .unwrap(),
+            "session=abc; Path=/; Domain=example.com; Max-Age=3600; \
+             Expires=Sun, 06 Nov 1994 08:49:37 GMT; Secure; HttpOnly; SameSite=Strict"
+        );
+        assert_eq!(
+            SetCookie::new("a", "1")
+                .with_same_site(SameSite::None)
+                .to_string(),
+            "a=1; Secure; SameSite=None"
+        );
+        assert_eq!(
+            SetCookie::removal("a").with_path("/").to_string(),
+            "a=; Path=/; Max-Age=0; Expires=Thu, 01 Jan 1970 00:00:00 GMT"
+        );
+
+        for cookie in [
+            SetCookie::new("", "1"),
+            SetCookie::new("a b", "1"),
+            SetCookie::new("a", "1;2"),
+            SetCookie::new("a", "x y"),
+            SetCookie::new("a", "\"q\""),
+            SetCookie::new("a", "1").with_path("/; Secure"),
+        ] {
+            assert!(cookie.to_header_value().is_err(), "{}", cookie);
+        }
+    }
+
+    #[test]
+    fn test_signed_and_private() {
+        use super::{Cookies, Key, SetCookie};
+        use http::{HeaderMap, HeaderValue};
+        use std::sync::Arc;
+
+        assert!(Key::from_secret(b"too short").is_err());
+        let key = Arc::new(Key::from_secret(&[7u8; 32]).unwrap());
+        let other = Arc::new(Key::generate().unwrap());
+
+        let signed = SetCookie::signed("user", "alice", &key);
+        assert!(signed.value().starts_with("alice."));
+        let private = SetCookie::private("user", "alice", &key).unwrap();
+        assert!(!private.value().contains("alice"));
+        // every encryption uses a fresh nonce.
+        assert_ne!(
+            private.value(),
+            SetCookie::private("user", "alice", &key).unwrap().value()
+        );
+
+        let jar = |cookie: String| {
+            let mut headers = HeaderMap::new();
+            headers.insert("cookie", HeaderValue::from_str(&cookie).unwrap());
+            Cookies::parse(&headers)
+        };
+
+        let signed_jar = jar(signed.to_string()).with_key(key.clone());
+        assert_eq!(signed_jar.signed("user").as_deref(), Some("alice"));
+        assert_eq!(signed_jar.private("user"), None);
+        assert_eq!(
+            signed_jar.clone().with_key(other.clone()).signed("user"),
+            None
+        );
+
+        let tampered = signed.value().replacen("alice", "mallory", 1);
+        assert_eq!(
+            jar(format!("user={}", tampered))
+                .with_key(key.clone())
+                .signed("user"),
+            None
+        );
+        // values are bound to their names.
+        assert_eq!(
+            jar(format!("admin={}", signed.value()))
+                .with_key(key.clone())
+                .signed("admin"),
+            None
+        );
+
+        let private_jar = jar(private.to_string()).with_key(key.clone());
+        assert_eq!(private_jar.private("user").as_deref(), Some("alice"));
+        assert_eq!(private_jar.clone().with_key(other).private("user"), None);
+        assert_eq!(
+            jar(format!("other={}", private.value()))
+                .with_key(key.clone())
+                .private("other"),
+            None
+        );
+        assert_eq!(
+            jar("user=AAAA".to_string()).with_key(key).private("user"),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cookies_middleware() {
+        use super::{cookies, Cookies, SetCookie};
+        use crate::{
+            app::{App, TestApp},
+            compose_handler, HTTPResult, NoState, Params,
+        };
+        use http::{Method, Request, Response, StatusCode};
+        use hyper::Body;
+
+        async fn login(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            app: App<(), NoState>,
+            state: NoState,
+        ) -> HTTPResult<NoState> {
+            let key = app.cookie_key().unwrap();
+            let mut resp = Response::builder()
+                .status(StatusCode::OK)
+                .body(Body::default())?;
+            SetCookie::signed("user", "alice", &key)
+                .with_path("/")
+                .append_to(resp.headers_mut())?;
+            SetCookie::private("secret", "42", &key)?
+                .with_path("/")
+                .with_http_only(true)
+                .append_to(resp.headers_mut())?;
+            Ok((req, Some(resp), state))
+        }
+
+        async fn whoami(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            state: NoState,
+        ) -> HTTPResult<NoState> {
+            let jar = Cookies::from_request(&req);
+            let body = format!(
+                "{} {}",
+                jar.signed("user").unwrap_or_default(),
+                jar.private("secret").unwrap_or_default()
+            );
+            Ok((
+                req,
+                Some(
+                    Response::builder()
+                        .status(StatusCode::OK)
+                        .body(Body::from(body))?,
+                ),
+                state,
+            ))
+        }
+
+        let mut app: App<(), NoState> = App::new();
+        app.with_cookie_secret(&[1u8; 48]).unwrap();
+        app.get("/login", compose_handler!(login)).unwrap();
+        app.get("/whoami", cookies().then(compose_handler!(whoami)))
+            .unwrap();
+
+        let test_app = TestApp::new(app).with_cookie_jar();
+        assert_eq!(
+            test_app.request(Method::GET, "/whoami").send().await.text(),
+            " "
+        );
+
+        test_app.request(Method::GET, "/login").send().await;
+        assert_eq!(
+            test_app.request(Method::GET, "/whoami").send().await.text(),
+            "alice 42"
+        );
+    }
+}
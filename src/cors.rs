@@ -0,0 +1,434 @@
+use std::{sync::Arc, time::Duration};
+
+use http::{
+    header::{
+        ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+        ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS,
+        ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD,
+        ORIGIN,
+    },
+    HeaderMap, HeaderValue, Method, Request, Response, StatusCode,
+};
+use hyper::Body;
+
+use crate::{add_vary, errors::Error, handler::Handler, TransientState};
+
+/// Cors is middleware implementing Cross-Origin Resource Sharing. It answers preflight requests
+/// (`OPTIONS` requests carrying `Access-Control-Request-Method`) itself, and adds the
+/// `Access-Control-*` headers to responses for allowed origins. Installed as an after hook, it
+/// covers every route without registering `OPTIONS` handlers for each:
+///
+/// ```ignore
+///     app.with_after_hook(
+///         Cors::new()
+///             .with_origin("https://app.example.com")
+///             .with_origin("https://*.example.org")
+///             .with_methods(&[Method::GET, Method::POST, Method::DELETE])
+///             .with_allowed_headers(&["content-type", "x-authtoken"])
+///             .with_exposed_headers(&["x-request-id"])
+///             .with_credentials(true)
+///             .with_max_age(Duration::from_secs(600))
+///             .handler(),
+///     );
+/// ```
+///
+/// Preflight requests from origins which are not allowed, or asking for methods or headers which
+/// are not allowed, are answered with 403 Forbidden. Other requests are passed through, but only
+/// responses for allowed origins are given `Access-Control-*` headers, so browsers refuse to
+/// expose the rest to scripts.
+#[derive(Clone, Debug, Default)]
+pub struct Cors {
+    any_origin: bool,
+    origins: Vec<String>,
+    methods: Option<Vec<Method>>,
+    allowed_headers: Option<Vec<String>>,
+    exposed_headers: Vec<String>,
+    credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl Cors {
+    /// Construct the middleware allowing no origins, the `GET`, `HEAD`, `POST`, `PUT`, `PATCH`
+    /// and `DELETE` methods and any requested headers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow an origin, such as `https://example.com`. A `*` in the pattern matches any run of
+    /// characters other than `/`, so `https://*.example.com` allows every subdomain; a pattern of
+    /// `*` alone allows every origin.
+    pub fn with_origin(mut self, pattern: &str) -> Self {
+        if pattern == "*" {
+            self.any_origin = true;
+        } else {
+            self.origins.push(pattern.to_ascii_lowercase());
+        }
+        self
+    }
+
+    /// Set the methods cross-origin requests may use.
+    pub fn with_methods(mut self, methods: &[Method]) -> Self {
+        self.methods = Some(methods.to_vec());
+        self
+    }
+
+    /// Set the request headers cross-origin requests may send. By default, any headers the
+    /// preflight request asks for are allowed.
+    pub fn with_allowed_headers(mut self, headers: &[&str]) -> Self {
+        self.allowed_headers = Some(headers.iter().map(|h| h.to_ascii_lowercase()).collect());
+        self
+    }
+
+    /// Set the response headers, beyond the CORS-safelisted ones, which scripts may read.
+    pub fn with_exposed_headers(mut self, headers: &[&str]) -> Self {
+        self.exposed_headers = headers.iter().map(|h| h.to_ascii_lowercase()).collect();
+        self
+    }
+
+    /// Allow requests with credentials (cookies and HTTP authentication). The request's origin
+    /// is then always echoed, as browsers refuse a wildcard origin with credentials.
+    pub fn with_credentials(mut self, credentials: bool) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    /// Set how long browsers may cache the result of a preflight request.
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Whether the origin, as sent in the `Origin` header, is allowed.
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        let origin = origin.to_ascii_lowercase();
+        self.any_origin
+            || self
+                .origins
+                .iter()
+                .any(|pattern| glob_match(pattern.as_bytes(), origin.as_bytes()))
+    }
+
+    /// Turn the configuration into a [crate::handler::Handler], to be installed with
+    /// [crate::app::App::with_after_hook] or appended to a chain with
+    /// [crate::handler::Handler::then]. In a chain, preflight requests are only answered for
+    /// routes registered for `OPTIONS`.
+    pub fn handler<S: Clone + Send + 'static, T: TransientState + 'static>(self) -> Handler<S, T> {
+        let cors = Arc::new(self);
+
+        Handler::from_fn(
+            move |req, resp, _params, _app, state| {
+                let cors = cors.clone();
+
+                Box::pin(async move {
+                    let resp = if is_preflight(&req) {
+                        Some(cors.preflight(&req)?)
+                    } else {
+                        resp.map(|resp| cors.decorate(&req, resp))
+                    };
+
+                    Ok((req, resp, state))
+                })
+            },
+            None,
+        )
+    }
+
+    fn methods(&self) -> Vec<Method> {
+        self.methods.clone().unwrap_or_else(|| {
+            vec![
+                Method::GET,
+                Method::HEAD,
+                Method::POST,
+                Method::PUT,
+                Method::PATCH,
+                Method::DELETE,
+            ]
+        })
+    }
+
+    fn preflight(&self, req: &Request<Body>) -> Result<Response<Body>, Error> {
+        let origin = header_str(req.headers(), &ORIGIN).unwrap_or_default();
+        let method = header_str(req.headers(), &ACCESS_CONTROL_REQUEST_METHOD).unwrap_or_default();
+        let requested: Vec<String> = req
+            .headers()
+            .get_all(ACCESS_CONTROL_REQUEST_HEADERS)
+            .iter()
+            .flat_map(|v| v.to_str().unwrap_or_default().split(','))
+            .map(|h| h.trim().to_ascii_lowercase())
+            .filter(|h| !h.is_empty())
+            .collect();
+
+        if !self.allows_origin(origin) {
+            return Err(forbidden("origin not allowed"));
+        }
+
+        let methods = self.methods();
+        if !methods.iter().any(|m| m.as_str() == method) {
+            return Err(forbidden("method not allowed"));
+        }
+
+        let allowed_headers = match &self.allowed_headers {
+            Some(allowed) => {
+                if let Some(header) = requested.iter().find(|h| !allowed.contains(h)) {
+                    return Err(forbidden(format!("header not allowed: {}", header)));
+                }
+                allowed.clone()
+            }
+            None => requested,
+        };
+
+        let mut resp = Response::new(Body::default());
+        *resp.status_mut() = StatusCode::NO_CONTENT;
+
+        let headers = resp.headers_mut();
+        self.allow_origin(headers, origin)?;
+        headers.insert(
+            ACCESS_CONTROL_ALLOW_METHODS,
+            HeaderValue::from_str(
+                &methods
+                    .iter()
+                    .map(|m| m.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(", "),
+            )?,
+        );
+        if !allowed_headers.is_empty() {
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_HEADERS,
+                HeaderValue::from_str(&allowed_headers.join(", "))?,
+            );
+        }
+        if let Some(max_age) = self.max_age {
+            headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age.as_secs()));
+        }
+        add_vary(headers, "access-control-request-method");
+        add_vary(headers, "access-control-request-headers");
+
+        Ok(resp)
+    }
+
+    fn decorate(&self, req: &Request<Body>, mut resp: Response<Body>) -> Response<Body> {
+        let origin = match header_str(req.headers(), &ORIGIN) {
+            Some(origin) if self.allows_origin(origin) => origin,
+            // the answer depends on the origin, even when it is refused.
+            _ => {
+                if !self.any_origin {
+                    add_vary(resp.headers_mut(), "origin");
+                }
+                return resp;
+            }
+        };
+
+        let headers = resp.headers_mut();
+        // origins were validated as header values on the way in.
+        let _ = self.allow_origin(headers, origin);
+        if !self.exposed_headers.is_empty() {
+            if let Ok(exposed) = HeaderValue::from_str(&self.exposed_headers.join(", ")) {
+                headers.insert(ACCESS_CONTROL_EXPOSE_HEADERS, exposed);
+            }
+        }
+
+        resp
+    }
+
+    fn allow_origin(&self, headers: &mut HeaderMap, origin: &str) -> Result<(), Error> {
+        if self.any_origin && !self.credentials {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+        } else {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_str(origin)?);
+            add_vary(headers, "origin");
+        }
+
+        if self.credentials {
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+
+        Ok(())
+    }
+}
+
+fn is_preflight<B>(req: &Request<B>) -> bool {
+    req.method() == Method::OPTIONS
+        && req.headers().contains_key(ORIGIN)
+        && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+fn forbidden(msg: impl ToString) -> Error {
+    Error::new_status(StatusCode::FORBIDDEN, msg)
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &http::header::HeaderName) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+// Match a pattern where `*` stands for one or more characters other than `/`.
+fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => s.is_empty(),
+        Some((b'*', rest)) => (1..=s.len())
+            .take_while(|i| s[i - 1] != b'/')
+            .any(|i| glob_match(rest, &s[i..])),
+        Some((c, rest)) => s.first() == Some(c) && glob_match(rest, &s[1..]),
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_origins() {
+        use super::Cors;
+
+        let cors = Cors::new()
+            .with_origin("https://app.example.com")
+            .with_origin("https://*.example.org");
+
+        assert!(cors.allows_origin("https://app.example.com"));
+        assert!(cors.allows_origin("HTTPS://APP.EXAMPLE.COM"));
+        assert!(cors.allows_origin("https://a.example.org"));
+        assert!(cors.allows_origin("https://a.b.example.org"));
+        assert!(!cors.allows_origin("https://example.org"));
+        assert!(!cors.allows_origin("https://.example.org"));
+        assert!(!cors.allows_origin("https://evil.com/.example.org"));
+        assert!(!cors.allows_origin("https://example.org.evil.com"));
+        assert!(!cors.allows_origin("http://app.example.com"));
+        assert!(!cors.allows_origin("null"));
+
+        assert!(Cors::new().with_origin("*").allows_origin("null"));
+        assert!(!Cors::new().allows_origin("https://app.example.com"));
+    }
+
+    #[tokio::test]
+    async fn test_cors() {
+        use super::Cors;
+        use crate::{
+            app::{App, TestApp},
+            compose_handler, HTTPResult, NoState, Params,
+        };
+        use http::{HeaderValue, Method, Request, Response, StatusCode};
+        use hyper::Body;
+        use std::time::Duration;
+
+        async fn item(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            _state: NoState,
+        ) -> HTTPResult<NoState> {
+            Ok((
+                req,
+                Some(
+                    Response::builder()
+                        .status(200)
+                        .header("x-request-id", "1")
+                        .body(Body::from("item"))?,
+                ),
+                NoState {},
+            ))
+        }
+
+        let mut app = App::new();
+        app.get("/item/:id", compose_handler!(item)).unwrap();
+        app.delete("/item/:id", compose_handler!(item)).unwrap();
+        app.with_after_hook(
+            Cors::new()
+                .with_origin("https://*.example.com")
+                .with_methods(&[Method::GET, Method::DELETE])
+                .with_allowed_headers(&["content-type", "x-authtoken"])
+                .with_exposed_headers(&["x-request-id"])
+                .with_credentials(true)
+                .with_max_age(Duration::from_secs(600))
+                .handler(),
+        );
+        let test_app = TestApp::new(app);
+
+        let resp = test_app
+            .request(Method::OPTIONS, "/item/1")
+            .header("origin", "https://app.example.com")
+            .header("access-control-request-method", "DELETE")
+            .header(
+                "access-control-request-headers",
+                "X-AuthToken, Content-Type",
+            )
+            .send()
+            .await;
+        resp.assert_status(StatusCode::NO_CONTENT)
+            .assert_header("access-control-allow-origin", "https://app.example.com")
+            .assert_header("access-control-allow-methods", "GET, DELETE")
+            .assert_header("access-control-allow-headers", "content-type, x-authtoken")
+            .assert_header("access-control-allow-credentials", "true")
+            .assert_header("access-control-max-age", "600");
+        let vary: Vec<&HeaderValue> = resp.headers().get_all("vary").iter().collect();
+        assert_eq!(
+            vary,
+            vec![
+                "origin",
+                "access-control-request-method",
+                "access-control-request-headers"
+            ]
+        );
+
+        for (origin, method, headers) in [
+            ("https://evil.com", "DELETE", "x-authtoken"),
+            ("https://app.example.com", "PUT", "x-authtoken"),
+            ("https://app.example.com", "GET", "x-forwarded-for"),
+        ] {
+            let resp = test_app
+                .request(Method::OPTIONS, "/item/1")
+                .header("origin", origin)
+                .header("access-control-request-method", method)
+                .header("access-control-request-headers", headers)
+                .send()
+                .await;
+            resp.assert_status(StatusCode::FORBIDDEN);
+            assert!(resp.header("access-control-allow-origin").is_none());
+        }
+
+        let resp = test_app
+            .request(Method::GET, "/item/1")
+            .header("origin", "https://app.example.com")
+            .send()
+            .await;
+        resp.assert_status(StatusCode::OK)
+            .assert_header("access-control-allow-origin", "https://app.example.com")
+            .assert_header("access-control-expose-headers", "x-request-id")
+            .assert_header("access-control-allow-credentials", "true")
+            .assert_header("vary", "origin");
+        assert_eq!(resp.text(), "item");
+
+        let resp = test_app
+            .request(Method::GET, "/item/1")
+            .header("origin", "https://evil.com")
+            .send()
+            .await;
+        resp.assert_status(StatusCode::OK)
+            .assert_header("vary", "origin");
+        assert!(resp.header("access-control-allow-origin").is_none());
+
+        // a plain OPTIONS request is not a preflight.
+        test_app
+            .request(Method::OPTIONS, "/item/1")
+            .header("origin", "https://app.example.com")
+            .send()
+            .await
+            .assert_status(StatusCode::METHOD_NOT_ALLOWED)
+            .assert_header("access-control-allow-origin", "https://app.example.com");
+
+        // any origin without credentials is answered with a wildcard.
+        let mut app = App::new();
+        app.get(
+            "/item/:id",
+            compose_handler!(item).then(Cors::new().with_origin("*").handler()),
+        )
+        .unwrap();
+        let resp = TestApp::new(app)
+            .request(Method::GET, "/item/1")
+            .header("origin", "https://anywhere.net")
+            .send()
+            .await;
+        resp.assert_header("access-control-allow-origin", "*");
+        assert!(resp.header("vary").is_none());
+    }
+}
@@ -0,0 +1,500 @@
+use std::{sync::Arc, time::Duration};
+
+use http::{header::CONTENT_TYPE, HeaderName, Method, Request, StatusCode};
+use hyper::{body::HttpBody, Body};
+
+use crate::{
+    auth::constant_time_eq,
+    cookies::{Cookies, SameSite, SetCookie},
+    errors::Error,
+    files::percent_decode,
+    handler::Handler,
+    sessions::{generate_id, Session},
+    TransientState,
+};
+
+/// CsrfStorage is where [Csrf] keeps the token it expects requests to present.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CsrfStorage {
+    /// Double-submit: the token is kept in a cookie, and requests must present the cookie's
+    /// value. Nothing is kept on the server.
+    Cookie,
+    /// Synchronizer token: the token is kept in the request's [crate::sessions::Session], so
+    /// [Csrf] must be wrapped by [crate::sessions::Sessions].
+    Session,
+}
+
+/// CsrfToken is the token requests must present, which [Csrf] inserts in the request extensions
+/// for rendering into forms and pages.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CsrfToken {
+    token: String,
+    field: String,
+}
+
+impl CsrfToken {
+    /// The token of the request, if it is handled within [Csrf].
+    pub fn from_request(req: &Request<Body>) -> Option<Self> {
+        req.extensions().get::<Self>().cloned()
+    }
+
+    /// The token.
+    pub fn value(&self) -> &str {
+        &self.token
+    }
+
+    /// The name of the form field the token is read from.
+    pub fn field_name(&self) -> &str {
+        &self.field
+    }
+
+    /// A hidden form field carrying the token, to render into forms.
+    pub fn form_field(&self) -> String {
+        format!(
+            "<input type=\"hidden\" name=\"{}\" value=\"{}\">",
+            self.field, self.token
+        )
+    }
+}
+
+/// Csrf protects the handlers it wraps from cross-site request forgery:
+///
+/// ```ignore
+///     app.get("/profile", Csrf::new().wrap(compose_handler!(profile_form)))?;
+///     app.post("/profile", Csrf::new().wrap(compose_handler!(update_profile)))?;
+///
+///     // and in profile_form:
+///     let field = CsrfToken::from_request(&req).unwrap().form_field();
+/// ```
+///
+/// Every request is given a token, which handlers find with [CsrfToken::from_request]. Requests
+/// with unsafe methods (all but GET, HEAD, OPTIONS and TRACE) must present the token, in the
+/// `X-CSRF-Token` header or the `csrf_token` field of a `application/x-www-form-urlencoded` body,
+/// or they are answered with 403 Forbidden without running the wrapped handlers. Form bodies are
+/// read to find the field, and are given to the wrapped handlers unchanged; bodies larger than
+/// the form size limit (1MiB by default) are answered with 413 Payload Too Large. Other bodies,
+/// such as multipart forms, must use the header.
+///
+/// By default the token is kept in a `csrf_token` cookie ([CsrfStorage::Cookie]), which scripts
+/// may read to set the header; an attacker's site can neither read it nor set the header. Tokens
+/// kept in sessions ([CsrfStorage::Session]) are also safe from attackers able to set cookies for
+/// the site, such as from a sibling subdomain.
+#[derive(Clone, Debug)]
+pub struct Csrf {
+    storage: CsrfStorage,
+    cookie_name: String,
+    header: HeaderName,
+    field: String,
+    secure: bool,
+    max_form_size: u64,
+}
+
+impl Default for Csrf {
+    fn default() -> Self {
+        Self {
+            storage: CsrfStorage::Cookie,
+            cookie_name: "csrf_token".to_string(),
+            header: HeaderName::from_static("x-csrf-token"),
+            field: "csrf_token".to_string(),
+            secure: false,
+            max_form_size: 1024 * 1024,
+        }
+    }
+}
+
+impl Csrf {
+    /// Protect handlers with double-submit cookies.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set where the token is kept.
+    pub fn with_storage(mut self, storage: CsrfStorage) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// Set the name of the cookie the token is kept in, with [CsrfStorage::Cookie], or of the
+    /// session key, with [CsrfStorage::Session].
+    pub fn with_cookie_name(mut self, name: &str) -> Self {
+        self.cookie_name = name.to_string();
+        self
+    }
+
+    /// Set the header the token is read from.
+    pub fn with_header(mut self, header: HeaderName) -> Self {
+        self.header = header;
+        self
+    }
+
+    /// Set the form field the token is read from.
+    pub fn with_field(mut self, field: &str) -> Self {
+        self.field = field.to_string();
+        self
+    }
+
+    /// Set the `Secure` attribute of the token cookie.
+    pub fn with_secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// Set the largest form body read to find the token.
+    pub fn with_max_form_size(mut self, size: u64) -> Self {
+        self.max_form_size = size;
+        self
+    }
+
+    /// Wrap the handler (or chain), so that it only runs for requests presenting the token.
+    pub fn wrap<S: Clone + Send + 'static, T: TransientState + 'static>(
+        self,
+        inner: Handler<S, T>,
+    ) -> Handler<S, T> {
+        let csrf = Arc::new(self);
+
+        inner.around(move |inner, req, resp, params, app, state| {
+            let csrf = csrf.clone();
+
+            Box::pin(async move {
+                let (expected, new) = csrf.expected(&req)?;
+
+                let mut req = if is_safe(req.method()) {
+                    req
+                } else {
+                    let (req, presented) = csrf.presented(req).await?;
+                    match (&expected, presented) {
+                        (Some(expected), Some(presented))
+                            if constant_time_eq(expected.as_bytes(), presented.as_bytes()) =>
+                        {
+                            req
+                        }
+                        _ => {
+                            return Err(Error::new_status(
+                                StatusCode::FORBIDDEN,
+                                "invalid csrf token",
+                            ))
+                        }
+                    }
+                };
+
+                let token = match expected {
+                    Some(token) => token,
+                    None => generate_id()?,
+                };
+                req.extensions_mut().insert(CsrfToken {
+                    token: token.clone(),
+                    field: csrf.field.clone(),
+                });
+
+                if new && csrf.storage == CsrfStorage::Session {
+                    csrf.session(&req)?.insert(&csrf.cookie_name, &token)?;
+                }
+
+                let (req, mut resp, state) = inner.perform(req, resp, params, app, state).await?;
+
+                if let (true, CsrfStorage::Cookie, Some(resp)) = (new, csrf.storage, resp.as_mut())
+                {
+                    SetCookie::new(&csrf.cookie_name, &token)
+                        .with_path("/")
+                        .with_max_age(Duration::from_secs(365 * 24 * 60 * 60))
+                        .with_secure(csrf.secure)
+                        .with_same_site(SameSite::Lax)
+                        .append_to(resp.headers_mut())?;
+                }
+
+                Ok((req, resp, state))
+            })
+        })
+    }
+
+    // The token the request must present, if one has been issued, and whether a new one must be
+    // issued.
+    fn expected(&self, req: &Request<Body>) -> Result<(Option<String>, bool), Error> {
+        let token = match self.storage {
+            CsrfStorage::Cookie => Cookies::from_request(req)
+                .get(&self.cookie_name)
+                .map(|t| t.to_string()),
+            CsrfStorage::Session => self.session(req)?.get::<String>(&self.cookie_name),
+        }
+        .filter(|t| !t.is_empty());
+
+        let new = token.is_none();
+        Ok((token, new))
+    }
+
+    fn session(&self, req: &Request<Body>) -> Result<Session, Error> {
+        Session::from_request(req).ok_or_else(|| {
+            Error::new("csrf tokens kept in sessions require the sessions middleware")
+        })
+    }
+
+    // The token presented by the request, from the header or a form body. The body is read and
+    // replaced.
+    async fn presented(
+        &self,
+        req: Request<Body>,
+    ) -> Result<(Request<Body>, Option<String>), Error> {
+        if let Some(token) = req.headers().get(&self.header) {
+            let token = token.to_str().ok().map(|t| t.trim().to_string());
+            return Ok((req, token));
+        }
+
+        let is_form = req
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|ct| ct.to_str().ok())
+            .and_then(|ct| ct.split(';').next())
+            .is_some_and(|ct| {
+                ct.trim()
+                    .eq_ignore_ascii_case("application/x-www-form-urlencoded")
+            });
+        if !is_form {
+            return Ok((req, None));
+        }
+
+        let (parts, mut body) = req.into_parts();
+        let mut form = Vec::new();
+        while let Some(chunk) = body.data().await {
+            form.extend_from_slice(&chunk?);
+            if form.len() as u64 > self.max_form_size {
+                return Err(Error::new_status(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "form too large",
+                ));
+            }
+        }
+
+        let token = form
+            .split(|b| *b == b'&')
+            .filter_map(|pair| {
+                let pair = std::str::from_utf8(pair).ok()?.replace('+', " ");
+                let (name, value) = pair.split_once('=')?;
+                let name = String::from_utf8(percent_decode(name)?).ok()?;
+                (name == self.field)
+                    .then(|| String::from_utf8(percent_decode(value)?).ok())
+                    .flatten()
+            })
+            .next();
+
+        Ok((Request::from_parts(parts, Body::from(form)), token))
+    }
+}
+
+fn is_safe(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    )
+}
+
+mod tests {
+    #[tokio::test]
+    async fn test_csrf() {
+        use super::{Csrf, CsrfToken};
+        use crate::{
+            app::{App, TestApp},
+            compose_handler, HTTPResult, NoState, Params,
+        };
+        use http::{Method, Request, Response, StatusCode};
+        use hyper::Body;
+
+        async fn form(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            state: NoState,
+        ) -> HTTPResult<NoState> {
+            let field = CsrfToken::from_request(&req).unwrap().form_field();
+            Ok((
+                req,
+                Some(
+                    Response::builder()
+                        .status(StatusCode::OK)
+                        .body(Body::from(field))?,
+                ),
+                state,
+            ))
+        }
+
+        async fn submit(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            state: NoState,
+        ) -> HTTPResult<NoState> {
+            // the form body is passed along.
+            let body = hyper::body::to_bytes(req.into_body()).await?;
+            Ok((
+                Request::default(),
+                Some(
+                    Response::builder()
+                        .status(StatusCode::OK)
+                        .body(Body::from(body))?,
+                ),
+                state,
+            ))
+        }
+
+        let mut app: App<(), NoState> = App::new();
+        app.get("/form", Csrf::new().wrap(compose_handler!(form)))
+            .unwrap();
+        app.post(
+            "/form",
+            Csrf::new()
+                .with_max_form_size(256)
+                .wrap(compose_handler!(submit)),
+        )
+        .unwrap();
+        let test_app = TestApp::new(app).with_cookie_jar();
+
+        // no token has been issued yet.
+        test_app
+            .request(Method::POST, "/form")
+            .header("x-csrf-token", "guess")
+            .send()
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+
+        let resp = test_app.request(Method::GET, "/form").send().await;
+        resp.assert_status(StatusCode::OK);
+        let token = test_app.cookie_jar().unwrap().get("csrf_token").unwrap();
+        assert_eq!(
+            resp.text(),
+            format!(
+                "<input type=\"hidden\" name=\"csrf_token\" value=\"{}\">",
+                token
+            )
+        );
+
+        // the token is kept.
+        let resp = test_app.request(Method::GET, "/form").send().await;
+        assert!(resp.header("set-cookie").is_none());
+        assert!(resp.text().contains(&token));
+
+        let resp = test_app
+            .request(Method::POST, "/form")
+            .header("x-csrf-token", token.as_str())
+            .body("data")
+            .send()
+            .await;
+        resp.assert_status(StatusCode::OK);
+        assert_eq!(resp.text(), "data");
+
+        let form = format!("name=alice&csrf_token={}", token);
+        let resp = test_app
+            .request(Method::POST, "/form")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body(form.clone())
+            .send()
+            .await;
+        resp.assert_status(StatusCode::OK);
+        assert_eq!(resp.text(), form);
+
+        for (content_type, body) in [
+            (
+                "application/x-www-form-urlencoded",
+                "name=alice&csrf_token=wrong",
+            ),
+            ("application/x-www-form-urlencoded", "name=alice"),
+            ("text/plain", form.as_str()),
+        ] {
+            test_app
+                .request(Method::POST, "/form")
+                .header("content-type", content_type)
+                .body(body.to_string())
+                .send()
+                .await
+                .assert_status(StatusCode::FORBIDDEN);
+        }
+
+        test_app
+            .request(Method::POST, "/form")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body("x".repeat(300))
+            .send()
+            .await
+            .assert_status(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn test_csrf_sessions() {
+        use super::{Csrf, CsrfStorage, CsrfToken};
+        use crate::{
+            app::{App, TestApp},
+            compose_handler,
+            sessions::{MemoryStore, Sessions},
+            HTTPResult, NoState, Params,
+        };
+        use http::{Method, Request, Response, StatusCode};
+        use hyper::Body;
+
+        async fn token(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            state: NoState,
+        ) -> HTTPResult<NoState> {
+            let token = CsrfToken::from_request(&req).unwrap().value().to_string();
+            Ok((
+                req,
+                Some(
+                    Response::builder()
+                        .status(StatusCode::OK)
+                        .body(Body::from(token))?,
+                ),
+                state,
+            ))
+        }
+
+        let sessions = Sessions::new(MemoryStore::new());
+        let csrf = Csrf::new().with_storage(CsrfStorage::Session);
+        let mut app: App<(), NoState> = App::new();
+        app.get(
+            "/token",
+            sessions
+                .clone()
+                .wrap(csrf.clone().wrap(compose_handler!(token))),
+        )
+        .unwrap();
+        app.post(
+            "/token",
+            sessions.wrap(csrf.clone().wrap(compose_handler!(token))),
+        )
+        .unwrap();
+        // without sessions, there is nowhere to keep the token.
+        app.put("/token", csrf.wrap(compose_handler!(token)))
+            .unwrap();
+        let test_app = TestApp::new(app).with_cookie_jar();
+
+        let issued = test_app.request(Method::GET, "/token").send().await.text();
+        assert!(test_app.cookie_jar().unwrap().get("csrf_token").is_none());
+        assert_eq!(
+            test_app.request(Method::GET, "/token").send().await.text(),
+            issued
+        );
+
+        let resp = test_app
+            .request(Method::POST, "/token")
+            .header("x-csrf-token", issued.as_str())
+            .send()
+            .await;
+        resp.assert_status(StatusCode::OK);
+        assert_eq!(resp.text(), issued);
+
+        test_app
+            .request(Method::POST, "/token")
+            .header("x-csrf-token", "wrong")
+            .send()
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+        test_app
+            .request(Method::PUT, "/token")
+            .header("x-csrf-token", issued.as_str())
+            .send()
+            .await
+            .assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
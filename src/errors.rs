@@ -77,6 +77,11 @@ where
     T: std::error::Error,
 {
     fn from(value: T) -> Self {
+        // bodies read past their limit are refused, however they are read.
+        if value.source().is_some_and(crate::body::exceeds_limit) {
+            return crate::body::too_large();
+        }
+
         Self::new(value.to_string())
     }
 }
@@ -0,0 +1,386 @@
+use std::{
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use http::{
+    header::{CONTENT_TYPE, ETAG, LAST_MODIFIED, LOCATION},
+    HeaderMap, HeaderValue, Request, Response, StatusCode,
+};
+use hyper::Body;
+use tokio::fs::File;
+
+use crate::{
+    conditional::{check_preconditions, last_modified_value, precondition_response, EntityTag},
+    errors::Error,
+    handler::Handler,
+    range::serve_range,
+    Params, TransientState,
+};
+
+/// StaticDir serves the files beneath a directory. It is mounted on a wildcard route, and the
+/// wildcard parameter (`*`) names the file to serve relative to the directory:
+///
+/// ```ignore
+///     app.get("/static/*", StaticDir::new("./public").with_index(None).handler())?;
+///     app.head("/static/*", static_dir("./public"))?;
+/// ```
+///
+/// Requests for a directory are answered with its index file (`index.html` by default); a request
+/// for a directory without a trailing slash is redirected to one, so that relative links in the
+/// index resolve. Paths which would leave the directory, whether through `..` components or
+/// symbolic links, are answered with 404 Not Found. Responses carry `ETag` and `Last-Modified`
+/// validators derived from the file's metadata, and conditional requests are answered as described
+/// in [crate::conditional::check_preconditions]. Files are served with [crate::range::serve_range],
+/// so `Range` requests are honored and bodies are streamed rather than read into memory.
+///
+/// If an earlier handler in the chain has already produced a response, it is passed along
+/// unchanged.
+#[derive(Clone, Debug)]
+pub struct StaticDir {
+    root: PathBuf,
+    index: Option<String>,
+}
+
+impl StaticDir {
+    /// Serve files beneath the directory.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            index: Some("index.html".to_string()),
+        }
+    }
+
+    /// Set the name of the file served for directory requests, or disable index files with
+    /// [std::option::Option::None].
+    pub fn with_index(mut self, index: Option<&str>) -> Self {
+        self.index = index.map(|s| s.to_string());
+        self
+    }
+
+    /// Turn the configuration into a [crate::handler::Handler], which may be routed directly or
+    /// appended to a chain with [crate::handler::Handler::then].
+    pub fn handler<S: Clone + Send + 'static, T: TransientState + 'static>(self) -> Handler<S, T> {
+        let dir = Arc::new(self);
+
+        Handler::from_fn(
+            move |req, resp, params, _app, state| {
+                let dir = dir.clone();
+
+                Box::pin(async move {
+                    if resp.is_some() {
+                        return Ok((req, resp, state));
+                    }
+
+                    let resp = dir.serve(&req, &params).await?;
+                    Ok((req, Some(resp), state))
+                })
+            },
+            None,
+        )
+    }
+
+    async fn serve(&self, req: &Request<Body>, params: &Params) -> Result<Response<Body>, Error> {
+        let path = self
+            .resolve(params.get("*").map_or("", |s| s.as_str()))
+            .await?;
+        let metadata = tokio::fs::metadata(&path).await.map_err(io_error)?;
+
+        let path = if metadata.is_dir() {
+            if !req.uri().path().ends_with('/') {
+                let mut location = req.uri().path().to_string() + "/";
+                if let Some(query) = req.uri().query() {
+                    location += &format!("?{}", query);
+                }
+
+                return Ok(Response::builder()
+                    .status(StatusCode::MOVED_PERMANENTLY)
+                    .header(LOCATION, location)
+                    .body(Body::default())?);
+            }
+
+            match &self.index {
+                Some(index) => self.contain(&path.join(index)).await?,
+                None => return Err(not_found()),
+            }
+        } else {
+            path
+        };
+
+        let file = File::open(&path).await.map_err(io_error)?;
+        let metadata = file.metadata().await.map_err(io_error)?;
+        if !metadata.is_file() {
+            return Err(not_found());
+        }
+
+        let modified = metadata.modified().ok();
+        let etag = EntityTag::from_metadata(metadata.len(), modified);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(ETAG, HeaderValue::from_str(&etag.to_string())?);
+        if let Some(modified) = modified {
+            headers.insert(LAST_MODIFIED, last_modified_value(modified));
+        }
+
+        if let Some(status) = check_preconditions(req, Some(&etag), modified) {
+            return Ok(precondition_response(status, &headers));
+        }
+
+        headers.insert(CONTENT_TYPE, content_type(&path));
+        serve_range(req, headers, file, metadata.len()).await
+    }
+
+    // Map the wildcard parameter to a path beneath the root, refusing anything that would escape
+    // it.
+    async fn resolve(&self, wildcard: &str) -> Result<PathBuf, Error> {
+        let decoded = percent_decode(wildcard)
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .ok_or_else(not_found)?;
+
+        let mut path = self.root.clone();
+        for segment in decoded.split('/').filter(|s| !s.is_empty()) {
+            let mut components = Path::new(segment).components();
+            match (components.next(), components.next()) {
+                (Some(Component::Normal(_)), None)
+                    if !segment.contains('\\') && !segment.contains('\0') =>
+                {
+                    path.push(segment)
+                }
+                _ => return Err(not_found()),
+            }
+        }
+
+        self.contain(&path).await
+    }
+
+    // Resolve symbolic links in the path, which may still point outside the root.
+    async fn contain(&self, path: &Path) -> Result<PathBuf, Error> {
+        let root = tokio::fs::canonicalize(&self.root)
+            .await
+            .map_err(io_error)?;
+        let canonical = tokio::fs::canonicalize(path).await.map_err(io_error)?;
+        if !canonical.starts_with(&root) {
+            return Err(not_found());
+        }
+
+        Ok(canonical)
+    }
+}
+
+/// Serve files beneath the directory with the default [StaticDir] configuration.
+pub fn static_dir<S: Clone + Send + 'static, T: TransientState + 'static>(
+    root: impl Into<PathBuf>,
+) -> Handler<S, T> {
+    StaticDir::new(root).handler()
+}
+
+/// Determine the Content-Type of a file from its extension, defaulting to
+/// `application/octet-stream`.
+pub fn content_type(path: &Path) -> HeaderValue {
+    HeaderValue::from_str(mime_guess::from_path(path).first_or_octet_stream().as_ref())
+        .unwrap_or_else(|_| HeaderValue::from_static("application/octet-stream"))
+}
+
+fn not_found() -> Error {
+    Error::new_status(StatusCode::NOT_FOUND, "not found")
+}
+
+fn io_error(e: std::io::Error) -> Error {
+    match e.kind() {
+        ErrorKind::NotFound => not_found(),
+        ErrorKind::PermissionDenied => Error::new_status(StatusCode::FORBIDDEN, "forbidden"),
+        _ => Error::new(e),
+    }
+}
+
+// Decode %XX escapes; invalid escapes yield None.
+pub(crate) fn percent_decode(s: &str) -> Option<Vec<u8>> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    Some(out)
+}
+
+mod tests {
+    #[tokio::test]
+    async fn test_static_dir() {
+        use super::{static_dir, StaticDir};
+        use crate::{
+            app::{App, TestApp},
+            NoState,
+        };
+        use http::{Method, StatusCode};
+
+        let mut app: App<(), NoState> = App::new();
+        app.get("/static/*", static_dir("testdata/public")).unwrap();
+        app.head("/static/*", static_dir("testdata/public"))
+            .unwrap();
+        app.get(
+            "/bare/*",
+            StaticDir::new("testdata/public").with_index(None).handler(),
+        )
+        .unwrap();
+        let test_app = TestApp::new(app);
+
+        let resp = test_app
+            .request(Method::GET, "/static/css/site.css")
+            .send()
+            .await;
+        resp.assert_status(StatusCode::OK)
+            .assert_header("content-type", "text/css")
+            .assert_header("content-length", "25");
+        assert_eq!(resp.text(), "body { font-size: 12pt; }");
+
+        let resp = test_app
+            .request(Method::HEAD, "/static/css/site.css")
+            .send()
+            .await;
+        resp.assert_status(StatusCode::OK)
+            .assert_header("content-length", "25");
+        assert!(resp.bytes().is_empty());
+
+        let etag = resp.header("etag").unwrap().to_string();
+        let last_modified = resp.header("last-modified").unwrap().to_string();
+        test_app
+            .request(Method::GET, "/static/css/site.css")
+            .header("if-none-match", etag.as_str())
+            .send()
+            .await
+            .assert_status(StatusCode::NOT_MODIFIED)
+            .assert_header("etag", &etag);
+        test_app
+            .request(Method::GET, "/static/css/site.css")
+            .header("if-modified-since", last_modified.as_str())
+            .send()
+            .await
+            .assert_status(StatusCode::NOT_MODIFIED);
+        test_app
+            .request(Method::GET, "/static/css/site.css")
+            .header("if-match", "\"stale\"")
+            .send()
+            .await
+            .assert_status(StatusCode::PRECONDITION_FAILED);
+
+        let resp = test_app
+            .request(Method::GET, "/static/css/site.css")
+            .header("range", "bytes=0-3")
+            .header("if-range", etag.as_str())
+            .send()
+            .await;
+        resp.assert_status(StatusCode::PARTIAL_CONTENT)
+            .assert_header("content-range", "bytes 0-3/25");
+        assert_eq!(resp.text(), "body");
+
+        // percent-encoded names are decoded.
+        let resp = test_app
+            .request(Method::GET, "/static/hello%20world.txt")
+            .send()
+            .await;
+        resp.assert_status(StatusCode::OK)
+            .assert_header("content-type", "text/plain");
+        assert_eq!(resp.text(), "hello, world\n");
+
+        let resp = test_app.request(Method::GET, "/static/").send().await;
+        resp.assert_status(StatusCode::OK)
+            .assert_header("content-type", "text/html");
+        assert!(resp.text().contains("<h1>davisjr</h1>"));
+
+        test_app
+            .request(Method::GET, "/static")
+            .send()
+            .await
+            .assert_status(StatusCode::MOVED_PERMANENTLY)
+            .assert_header("location", "/static/");
+
+        let resp = test_app
+            .request(Method::GET, "/static/css?v=1")
+            .send()
+            .await;
+        resp.assert_status(StatusCode::MOVED_PERMANENTLY)
+            .assert_header("location", "/static/css/?v=1");
+
+        // css/ has no index file, and indexes may be disabled.
+        for path in ["/static/css/", "/bare/", "/static/missing.txt"] {
+            test_app
+                .request(Method::GET, path)
+                .send()
+                .await
+                .assert_status(StatusCode::NOT_FOUND);
+        }
+
+        for path in [
+            "/static/../Cargo.toml",
+            "/static/css/../../Cargo.toml",
+            "/static/%2e%2e/Cargo.toml",
+            "/static/..%2fCargo.toml",
+            "/static/%2fetc%2fpasswd",
+            "/static/css%5c..%5c..%5cCargo.toml",
+            "/static/%zz",
+        ] {
+            test_app
+                .request(Method::GET, path)
+                .send()
+                .await
+                .assert_status(StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_static_dir_streaming() {
+        use super::static_dir;
+        use crate::{
+            app::{App, TestApp},
+            NoState,
+        };
+        use http::{Method, StatusCode};
+
+        let dir = std::env::temp_dir().join(format!("davisjr-static-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let data: Vec<u8> = (0..300_000).map(|i| (i % 251) as u8).collect();
+        std::fs::write(dir.join("data.bin"), &data).unwrap();
+
+        // links out of the directory are refused.
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(
+            std::fs::canonicalize("Cargo.toml").unwrap(),
+            dir.join("escape"),
+        )
+        .unwrap();
+
+        let mut app: App<(), NoState> = App::new();
+        app.get("/files/*", static_dir(&dir)).unwrap();
+        let test_app = TestApp::new(app);
+
+        let resp = test_app
+            .request(Method::GET, "/files/data.bin")
+            .send()
+            .await;
+        resp.assert_status(StatusCode::OK)
+            .assert_header("content-type", "application/octet-stream")
+            .assert_header("content-length", "300000");
+        assert_eq!(resp.bytes(), data);
+
+        #[cfg(unix)]
+        test_app
+            .request(Method::GET, "/files/escape")
+            .send()
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
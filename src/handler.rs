@@ -1,6 +1,11 @@
-use std::future::Future;
+use std::{future::Future, sync::Arc, time::Duration};
 
-use crate::{app::App, HTTPResult, PinBox, TransientState};
+use crate::{
+    app::App,
+    body::limit_body,
+    timeout::{timed_out, Cancellation, TimeoutOverride},
+    HTTPResult, PinBox, TransientState,
+};
 use async_recursion::async_recursion;
 
 use http::{Request, Response};
@@ -40,17 +45,54 @@ pub type HandlerFunc<S, T> = fn(
     state: T,
 ) -> PinBox<dyn Future<Output = HTTPResult<T>> + Send>;
 
+// The boxed form of a handler function, allowing handlers to capture configuration; see
+// [Handler::from_fn].
+type BoxedHandlerFunc<S, T> = Arc<
+    dyn Fn(
+            Request<Body>,
+            Option<Response<Body>>,
+            crate::Params,
+            App<S, T>,
+            T,
+        ) -> PinBox<dyn Future<Output = HTTPResult<T>> + Send>
+        + Send
+        + Sync,
+>;
+
+/// Terminal marks a response which ends its chain: the handlers after the one which produced it
+/// are not run, so they cannot replace it. Middleware marks its rejections, such as the 401
+/// Unauthorized of [crate::auth::BasicAuth], so that handlers appended with
+/// [crate::handler::Handler::then] do not run for rejected requests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Terminal;
+
+impl Terminal {
+    /// Mark the response as ending its chain.
+    pub fn mark(mut resp: Response<Body>) -> Response<Body> {
+        resp.extensions_mut().insert(Self);
+        resp
+    }
+
+    /// Whether the response ends its chain.
+    pub fn is_marked(resp: &Response<Body>) -> bool {
+        resp.extensions().get::<Self>().is_some()
+    }
+}
+
 /// Handler is the structure of the handler. Typically, you will not use this directly, and instead
 /// interact with the [crate::compose_handler!] macro. That said, if you wanted to define your own
 /// macros or otherwise compose more complicated structures for your handlers, this is available to
 /// you.
 #[derive(Clone)]
 pub struct Handler<S: Clone + Send, T: TransientState + 'static> {
-    handler: HandlerFunc<S, T>,
+    handler: BoxedHandlerFunc<S, T>,
     next: Box<Option<Handler<S, T>>>,
+    // the body limit set by this handler with [Handler::with_body_limit], including those of the
+    // chains it wraps.
+    body_limit: Option<u64>,
 }
 
-impl<S: Clone + Send, T: TransientState> Handler<S, T>
+impl<S: Clone + Send + 'static, T: TransientState> Handler<S, T>
 where
     Self: Send,
     S: Clone + Send,
@@ -58,13 +100,139 @@ where
     /// Construct a new handler composed of a HandlerFunc with state, and an optional next handler
     /// in the chain.
     pub fn new(handler: HandlerFunc<S, T>, next: Option<Handler<S, T>>) -> Self {
+        Self::from_fn(handler, next)
+    }
+
+    /// Construct a new handler from a closure, and an optional next handler in the chain. Unlike
+    /// a [crate::handler::HandlerFunc], the closure may capture configuration, which is how
+    /// built-in handlers such as [crate::files::static_dir] are made.
+    pub fn from_fn<F>(handler: F, next: Option<Handler<S, T>>) -> Self
+    where
+        F: Fn(
+                Request<Body>,
+                Option<Response<Body>>,
+                crate::Params,
+                App<S, T>,
+                T,
+            ) -> PinBox<dyn Future<Output = HTTPResult<T>> + Send>
+            + Send
+            + Sync
+            + 'static,
+    {
         Self {
-            handler,
+            handler: Arc::new(handler),
             next: Box::new(next),
+            body_limit: None,
+        }
+    }
+
+    /// Wrap the chain in a new handler, which is given the chain to perform along with the
+    /// request. This is how middleware such as [crate::auth::BasicAuth] wraps handlers; unlike
+    /// wrapping with [crate::handler::Handler::from_fn], the limits set on the chain with
+    /// [crate::handler::Handler::with_body_limit] are kept, so that the router can refuse
+    /// requests against them before the chain runs:
+    ///
+    /// ```ignore
+    ///     let logged = compose_handler!(hello).around(|inner, req, resp, params, app, state| {
+    ///         Box::pin(async move {
+    ///             eprintln!("{}", req.uri());
+    ///             inner.perform(req, resp, params, app, state).await
+    ///         })
+    ///     });
+    /// ```
+    pub fn around<F>(self, handler: F) -> Self
+    where
+        F: Fn(
+                Handler<S, T>,
+                Request<Body>,
+                Option<Response<Body>>,
+                crate::Params,
+                App<S, T>,
+                T,
+            ) -> PinBox<dyn Future<Output = HTTPResult<T>> + Send>
+            + Send
+            + Sync
+            + 'static,
+    {
+        let body_limit = self.body_limit();
+        let mut wrapper = Self::from_fn(
+            move |req, resp, params, app, state| {
+                handler(self.clone(), req, resp, params, app, state)
+            },
+            None,
+        );
+        wrapper.body_limit = body_limit;
+        wrapper
+    }
+
+    /// Append another handler (or chain) to the end of this chain, so that it runs after every
+    /// handler already in it:
+    ///
+    /// ```ignore
+    ///     app.get("/static/*", compose_handler!(authenticate).then(static_dir("./public")))?;
+    /// ```
+    pub fn then(mut self, next: Handler<S, T>) -> Self {
+        self.next = Box::new(Some(match *self.next {
+            Some(existing) => existing.then(next),
+            None => next,
+        }));
+        self
+    }
+
+    /// Limit the time the chain may take, overriding the application's timeout (see
+    /// [crate::app::App::with_timeout]). The chain is wrapped in a new handler which enforces the
+    /// limit, so it is kept when the result is wrapped by middleware or appended to another chain
+    /// with [crate::handler::Handler::then]. Once the wrapper runs, the application's timeout no
+    /// longer applies to the request; handlers appended to the wrapper are not limited by it.
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        self.around(move |inner, req, resp, params, app, state| {
+            Box::pin(async move {
+                if let Some(overridden) = req.extensions().get::<TimeoutOverride>() {
+                    overridden.set();
+                }
+
+                let cancellation = Cancellation::from_request(&req);
+                let (_, status) = app.timeout();
+                match tokio::time::timeout(timeout, inner.perform(req, resp, params, app, state))
+                    .await
+                {
+                    Ok(result) => result,
+                    Err(_) => Err(timed_out(cancellation, status)),
+                }
+            })
+        })
+    }
+
+    /// Limit the size of request bodies given to the chain, overriding the application's limit
+    /// (see [crate::app::App::with_body_limit]). As with [crate::handler::Handler::with_timeout],
+    /// the chain is wrapped in a new handler which applies the limit with
+    /// [crate::body::limit_body], so it is kept when the result is wrapped or appended to another
+    /// chain. Routes with a limit of their own are not refused by the application's limit before
+    /// they run.
+    pub fn with_body_limit(self, limit: u64) -> Self {
+        let mut wrapper = self.around(move |inner, req, resp, params, app, state| {
+            Box::pin(async move {
+                let req = limit_body(req, limit)?;
+                inner.perform(req, resp, params, app, state).await
+            })
+        });
+        wrapper.body_limit = Some(wrapper.body_limit.map_or(limit, |inner| inner.min(limit)));
+        wrapper
+    }
+
+    // The smallest body limit set by the handlers of the chain, if any set one.
+    pub(crate) fn body_limit(&self) -> Option<u64> {
+        match (
+            self.body_limit,
+            self.next.as_ref().as_ref().and_then(Self::body_limit),
+        ) {
+            (Some(limit), Some(next)) => Some(limit.min(next)),
+            (limit, next) => limit.or(next),
         }
     }
 
-    /// Perform the function, this will recursively execute all handlers in the chain.
+    /// Perform the function, this will recursively execute all handlers in the chain, stopping
+    /// early at a response marked with [crate::handler::Terminal].
     #[async_recursion]
     pub async fn perform(
         &self,
@@ -76,7 +244,7 @@ where
     ) -> HTTPResult<T> {
         let (req, response, state) =
             (self.handler)(req, response, params.clone(), app.clone(), state).await?;
-        if self.next.is_some() {
+        if self.next.is_some() && !response.as_ref().is_some_and(Terminal::is_marked) {
             return (*self.clone().next)
                 .unwrap()
                 .perform(req, response, params, app, state)
@@ -97,9 +265,6 @@ mod tests {
         #[derive(Clone)]
         struct State;
 
-        #[derive(Clone)]
-        struct TransientState;
-
         // this method adds a header:
         // wakka: wakka wakka
         // to the request. that's it!
@@ -186,4 +351,55 @@ mod tests {
 
         drop(bh)
     }
+
+    #[tokio::test]
+    async fn test_handler_closures() {
+        use crate::{app::App, NoState, Params};
+        use http::{HeaderValue, Request, Response};
+        use hyper::Body;
+
+        fn tag(value: &'static str) -> super::Handler<(), NoState> {
+            super::Handler::from_fn(
+                move |mut req: Request<Body>, resp, _params, _app, state| {
+                    req.headers_mut()
+                        .append("tag", HeaderValue::from_static(value));
+                    Box::pin(async move { Ok((req, resp, state)) })
+                },
+                None,
+            )
+        }
+
+        let respond = super::Handler::new(
+            |req, _resp, _params, _app, state| {
+                Box::pin(async move {
+                    let tags: Vec<&str> = req
+                        .headers()
+                        .get_all("tag")
+                        .iter()
+                        .map(|v| v.to_str().unwrap())
+                        .collect();
+                    let resp = Response::new(Body::from(tags.join(",")));
+                    Ok((req, Some(resp), state))
+                })
+            },
+            None,
+        );
+
+        let handler = tag("one").then(tag("two").then(tag("three"))).then(respond);
+        let (_, response, _) = handler
+            .perform(
+                Request::default(),
+                None,
+                Params::new(),
+                App::new(),
+                NoState {},
+            )
+            .await
+            .unwrap();
+
+        let body = hyper::body::to_bytes(response.unwrap().into_body())
+            .await
+            .unwrap();
+        assert_eq!(body, "one,two,three");
+    }
 }
@@ -0,0 +1,854 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use http::Request;
+use hyper::Body;
+use ring::{hmac, signature};
+use serde::{de::DeserializeOwned, Deserialize};
+
+use crate::{
+    auth::{credentials, quote, unauthorized, Principal},
+    cookies::Cookies,
+    errors::{Error, ServerError},
+    handler::Handler,
+    TransientState,
+};
+
+/// Algorithm is a JWS signature algorithm accepted by [Jwt]. Tokens using any other algorithm,
+/// including `none`, are rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    /// HMAC with SHA-256, keyed with a shared secret.
+    HS256,
+    /// RSASSA-PKCS1-v1_5 with SHA-256, with RSA keys of 2048 to 8192 bits.
+    RS256,
+    /// ECDSA with P-256 and SHA-256.
+    ES256,
+}
+
+impl Algorithm {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "HS256" => Some(Self::HS256),
+            "RS256" => Some(Self::RS256),
+            "ES256" => Some(Self::ES256),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+enum VerifyingKey {
+    Hmac(hmac::Key),
+    Rsa { n: Vec<u8>, e: Vec<u8> },
+    Ec(Vec<u8>),
+}
+
+impl VerifyingKey {
+    fn algorithm(&self) -> Algorithm {
+        match self {
+            Self::Hmac(_) => Algorithm::HS256,
+            Self::Rsa { .. } => Algorithm::RS256,
+            Self::Ec(_) => Algorithm::ES256,
+        }
+    }
+
+    fn verify(&self, message: &[u8], sig: &[u8]) -> bool {
+        match self {
+            Self::Hmac(key) => hmac::verify(key, message, sig).is_ok(),
+            Self::Rsa { n, e } => signature::RsaPublicKeyComponents { n, e }
+                .verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, sig)
+                .is_ok(),
+            Self::Ec(point) => {
+                signature::UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point)
+                    .verify(message, sig)
+                    .is_ok()
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+struct KeyEntry {
+    kid: Option<String>,
+    key: VerifyingKey,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    alg: Option<String>,
+    #[serde(rename = "use")]
+    usage: Option<String>,
+    crv: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+    k: Option<String>,
+}
+
+/// Jwks is a JSON Web Key Set (RFC 7517): the keys tokens may be signed with. RSA keys are used
+/// for RS256, P-256 EC keys for ES256 and symmetric (`oct`) keys for HS256; keys for other
+/// algorithms or uses are ignored. Tokens naming a key with `kid` are only checked against the
+/// keys with that ID, or without one.
+#[derive(Clone, Default)]
+pub struct Jwks {
+    keys: Vec<KeyEntry>,
+}
+
+impl Jwks {
+    /// Parse a key set.
+    pub fn parse(json: &[u8]) -> Result<Self, ServerError> {
+        #[derive(Deserialize)]
+        struct Set {
+            keys: Vec<Jwk>,
+        }
+
+        let set: Set = serde_json::from_slice(json)
+            .map_err(|e| ServerError(format!("invalid JWKS: {}", e)))?;
+
+        let mut keys = Vec::new();
+        for jwk in set.keys {
+            if jwk.usage.as_deref().is_some_and(|u| u != "sig") {
+                continue;
+            }
+
+            let decode = |field: &Option<String>, name: &str| {
+                URL_SAFE_NO_PAD
+                    .decode(field.as_deref().unwrap_or_default())
+                    .ok()
+                    .filter(|v| !v.is_empty())
+                    .ok_or_else(|| ServerError(format!("invalid JWK: missing or bad {}", name)))
+            };
+
+            let key = match (jwk.kty.as_str(), jwk.crv.as_deref()) {
+                ("RSA", _) => VerifyingKey::Rsa {
+                    n: decode(&jwk.n, "n")?,
+                    e: decode(&jwk.e, "e")?,
+                },
+                ("EC", Some("P-256")) => {
+                    let mut point = vec![0x04];
+                    point.append(&mut decode(&jwk.x, "x")?);
+                    point.append(&mut decode(&jwk.y, "y")?);
+                    VerifyingKey::Ec(point)
+                }
+                ("oct", _) => {
+                    VerifyingKey::Hmac(hmac::Key::new(hmac::HMAC_SHA256, &decode(&jwk.k, "k")?))
+                }
+                _ => continue,
+            };
+
+            if jwk
+                .alg
+                .as_deref()
+                .is_some_and(|alg| Algorithm::parse(alg) != Some(key.algorithm()))
+            {
+                continue;
+            }
+
+            keys.push(KeyEntry { kid: jwk.kid, key });
+        }
+
+        Ok(Self { keys })
+    }
+
+    /// Read and parse a key set from a file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ServerError> {
+        Self::parse(&std::fs::read(path)?)
+    }
+
+    /// The number of usable keys in the set.
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Whether the set has no usable keys.
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+// A key set file, re-read when its modification time or size changes.
+struct JwksFile {
+    path: PathBuf,
+    cached: Mutex<(Option<(SystemTime, u64)>, Jwks)>,
+}
+
+impl JwksFile {
+    fn keys(&self) -> Vec<KeyEntry> {
+        let version = std::fs::metadata(&self.path)
+            .ok()
+            .and_then(|m| Some((m.modified().ok()?, m.len())));
+
+        let mut cached = self.cached.lock().unwrap();
+        if version.is_some() && version != cached.0 {
+            // a file which cannot be read or parsed, perhaps because it is being replaced, leaves
+            // the previous keys in use.
+            if let Ok(jwks) = Jwks::load(&self.path) {
+                *cached = (version, jwks);
+            }
+        }
+
+        cached.1.keys.clone()
+    }
+}
+
+/// Claims are the claims of a validated token, which [Jwt] inserts in the request extensions
+/// for the handlers it wraps.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Claims(serde_json::Map<String, serde_json::Value>);
+
+impl Claims {
+    /// The claims of the request, if it carried a valid token.
+    pub fn from_request(req: &Request<Body>) -> Option<Self> {
+        req.extensions().get::<Self>().cloned()
+    }
+
+    /// The claim, if it is present and can be deserialized as `V`.
+    pub fn get<V: DeserializeOwned>(&self, name: &str) -> Option<V> {
+        self.0
+            .get(name)
+            .and_then(|v| serde_json::from_value(v.clone()).ok())
+    }
+
+    /// The subject (`sub`) claim.
+    pub fn subject(&self) -> Option<String> {
+        self.get("sub")
+    }
+
+    /// The issuer (`iss`) claim.
+    pub fn issuer(&self) -> Option<String> {
+        self.get("iss")
+    }
+
+    /// The audiences (`aud`) of the token, which may be a single string or an array.
+    pub fn audience(&self) -> Vec<String> {
+        match self.0.get("aud") {
+            Some(serde_json::Value::String(aud)) => vec![aud.clone()],
+            Some(serde_json::Value::Array(auds)) => auds
+                .iter()
+                .filter_map(|a| a.as_str().map(|s| s.to_string()))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// All claims.
+    pub fn as_map(&self) -> &serde_json::Map<String, serde_json::Value> {
+        &self.0
+    }
+
+    /// Deserialize the claims into a type of the application's own.
+    pub fn deserialize<V: DeserializeOwned>(&self) -> Result<V, Error> {
+        serde_json::from_value(serde_json::Value::Object(self.0.clone())).map_err(Error::new)
+    }
+}
+
+/// Jwt requires a valid JSON Web Token for the handlers it wraps:
+///
+/// ```ignore
+///     let jwt = Jwt::new()
+///         .with_jwks_file("/etc/app/jwks.json")?
+///         .with_issuer("https://auth.example.com")
+///         .with_audience("api");
+///     app.get("/api/*", jwt.wrap(compose_handler!(api)))?;
+///
+///     // and in the handlers:
+///     let claims = Claims::from_request(&req).unwrap();
+/// ```
+///
+/// Tokens are read from the `Authorization` header as bearer tokens, or from a cookie set with
+/// [Jwt::with_cookie] when there is no header. They must be signed with HS256, RS256 or ES256 by
+/// one of the configured keys, and must carry an `exp` claim unless
+/// [Jwt::with_required_expiry] is disabled. `exp` and `nbf` are checked allowing for clock skew
+/// (a minute by default), and `iss` and `aud` are checked when an issuer or audiences are
+/// configured.
+///
+/// Requests without a valid token are answered with 401 Unauthorized and a bearer
+/// `WWW-Authenticate` challenge, without running the wrapped handlers. Otherwise the token's
+/// [Claims] are inserted in the request extensions, along with a [crate::auth::Principal] for
+/// its subject, if it has one.
+///
+/// A key set file given to [Jwt::with_jwks_file] is re-read when it changes, so keys can be
+/// rotated by replacing the file. Replace it atomically (by renaming a new file over it), and
+/// keep the old key in the set until tokens signed with it have expired.
+#[derive(Clone)]
+pub struct Jwt {
+    keys: Vec<KeyEntry>,
+    jwks_file: Option<Arc<JwksFile>>,
+    cookie: Option<String>,
+    issuer: Option<String>,
+    audiences: Vec<String>,
+    clock_skew: Duration,
+    require_exp: bool,
+    realm: String,
+}
+
+impl Default for Jwt {
+    fn default() -> Self {
+        Self {
+            keys: Vec::new(),
+            jwks_file: None,
+            cookie: None,
+            issuer: None,
+            audiences: Vec::new(),
+            clock_skew: Duration::from_secs(60),
+            require_exp: true,
+            realm: "api".to_string(),
+        }
+    }
+}
+
+impl Jwt {
+    /// A validator without keys; add them with [Jwt::with_secret], [Jwt::with_jwks] or
+    /// [Jwt::with_jwks_file].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accept HS256 tokens signed with the secret.
+    pub fn with_secret(mut self, secret: &[u8]) -> Self {
+        self.keys.push(KeyEntry {
+            kid: None,
+            key: VerifyingKey::Hmac(hmac::Key::new(hmac::HMAC_SHA256, secret)),
+        });
+        self
+    }
+
+    /// Accept tokens signed with the keys of the set.
+    pub fn with_jwks(mut self, jwks: Jwks) -> Self {
+        self.keys.extend(jwks.keys);
+        self
+    }
+
+    /// Accept tokens signed with the keys of the set in the file, which is re-read when it
+    /// changes. The file must be readable now.
+    pub fn with_jwks_file(mut self, path: impl Into<PathBuf>) -> Result<Self, ServerError> {
+        let path = path.into();
+        let jwks = Jwks::load(&path)?;
+        self.jwks_file = Some(Arc::new(JwksFile {
+            cached: Mutex::new((None, jwks)),
+            path,
+        }));
+        Ok(self)
+    }
+
+    /// Read tokens from the cookie when the request has no `Authorization` header.
+    pub fn with_cookie(mut self, name: &str) -> Self {
+        self.cookie = Some(name.to_string());
+        self
+    }
+
+    /// Require the `iss` claim to be the issuer.
+    pub fn with_issuer(mut self, issuer: &str) -> Self {
+        self.issuer = Some(issuer.to_string());
+        self
+    }
+
+    /// Require the `aud` claim to contain the audience. Calling this more than once accepts any
+    /// of the audiences.
+    pub fn with_audience(mut self, audience: &str) -> Self {
+        self.audiences.push(audience.to_string());
+        self
+    }
+
+    /// Set the clock skew allowed when checking `exp` and `nbf`.
+    pub fn with_clock_skew(mut self, skew: Duration) -> Self {
+        self.clock_skew = skew;
+        self
+    }
+
+    /// Set whether tokens must carry an `exp` claim. Tokens without one never expire.
+    pub fn with_required_expiry(mut self, required: bool) -> Self {
+        self.require_exp = required;
+        self
+    }
+
+    /// Set the realm of the `WWW-Authenticate` challenge.
+    pub fn with_realm(mut self, realm: &str) -> Self {
+        self.realm = realm.to_string();
+        self
+    }
+
+    /// Validate a token, returning its claims. The error describes why the token is invalid.
+    pub fn validate(&self, token: &str) -> Result<Claims, String> {
+        // the signature covers the encoded header and payload.
+        let (message, sig) = token.rsplit_once('.').ok_or("malformed token")?;
+        let (header, payload) = match message.split_once('.') {
+            Some((header, payload)) if !payload.contains('.') => (header, payload),
+            _ => return Err("malformed token".to_string()),
+        };
+
+        #[derive(Deserialize)]
+        struct Header {
+            alg: String,
+            kid: Option<String>,
+        }
+
+        let decode = |part: &str| URL_SAFE_NO_PAD.decode(part).ok();
+        let header: Header = decode(header)
+            .and_then(|h| serde_json::from_slice(&h).ok())
+            .ok_or("malformed token header")?;
+        let alg = Algorithm::parse(&header.alg).ok_or("unsupported algorithm")?;
+        let sig = decode(sig).ok_or("malformed signature")?;
+
+        let file_keys = self
+            .jwks_file
+            .as_ref()
+            .map(|f| f.keys())
+            .unwrap_or_default();
+        let verified = self
+            .keys
+            .iter()
+            .chain(file_keys.iter())
+            .filter(|k| k.key.algorithm() == alg)
+            .filter(|k| match (&header.kid, &k.kid) {
+                (Some(wanted), Some(kid)) => wanted == kid,
+                _ => true,
+            })
+            .any(|k| k.key.verify(message.as_bytes(), &sig));
+        if !verified {
+            return Err("invalid signature".to_string());
+        }
+
+        let claims = match decode(payload).and_then(|p| serde_json::from_slice(&p).ok()) {
+            Some(serde_json::Value::Object(claims)) => Claims(claims),
+            _ => return Err("malformed claims".to_string()),
+        };
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let skew = self.clock_skew.as_secs_f64();
+
+        match claims.0.get("exp").map(|v| v.as_f64()) {
+            Some(Some(exp)) if now > exp + skew => return Err("token expired".to_string()),
+            Some(None) => return Err("invalid exp claim".to_string()),
+            None if self.require_exp => return Err("missing exp claim".to_string()),
+            _ => {}
+        }
+
+        match claims.0.get("nbf").map(|v| v.as_f64()) {
+            Some(Some(nbf)) if now + skew < nbf => return Err("token not yet valid".to_string()),
+            Some(None) => return Err("invalid nbf claim".to_string()),
+            _ => {}
+        }
+
+        if let Some(issuer) = &self.issuer {
+            if claims.issuer().as_ref() != Some(issuer) {
+                return Err("invalid issuer".to_string());
+            }
+        }
+
+        if !self.audiences.is_empty()
+            && !claims.audience().iter().any(|a| self.audiences.contains(a))
+        {
+            return Err("invalid audience".to_string());
+        }
+
+        Ok(claims)
+    }
+
+    fn token(&self, req: &Request<Body>) -> Option<String> {
+        if let Some(token) = credentials(req, "Bearer") {
+            return Some(token.to_string());
+        }
+
+        let cookie = self.cookie.as_ref()?;
+        Cookies::from_request(req)
+            .get(cookie)
+            .map(|t| t.to_string())
+    }
+
+    /// Wrap the handler (or chain), so that it only runs for requests with a valid token.
+    pub fn wrap<S: Clone + Send + 'static, T: TransientState + 'static>(
+        self,
+        inner: Handler<S, T>,
+    ) -> Handler<S, T> {
+        let jwt = Arc::new(self);
+
+        inner.around(move |inner, mut req, resp, params, app, state| {
+            let jwt = jwt.clone();
+
+            Box::pin(async move {
+                let mut challenge = format!("Bearer realm=\"{}\"", quote(&jwt.realm));
+
+                if let Some(token) = jwt.token(&req) {
+                    match jwt.validate(&token) {
+                        Ok(claims) => {
+                            if let Some(sub) = claims.subject() {
+                                req.extensions_mut().insert(Principal::new("Bearer", &sub));
+                            }
+
+                            req.extensions_mut().insert(claims);
+                            return inner.perform(req, resp, params, app, state).await;
+                        }
+                        Err(e) => {
+                            challenge += &format!(
+                                ", error=\"invalid_token\", error_description=\"{}\"",
+                                quote(&e)
+                            )
+                        }
+                    }
+                }
+
+                Ok((req, Some(unauthorized(&challenge)?), state))
+            })
+        })
+    }
+}
+
+mod tests {
+    // Sign a token with the algorithm; keys are PKCS#8 documents for RS256 and ES256, and HMAC
+    // secrets otherwise.
+    #[allow(dead_code)]
+    fn sign(alg: &str, kid: Option<&str>, claims: serde_json::Value, key: &[u8]) -> String {
+        use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+        use ring::{hmac, rand::SystemRandom, signature};
+
+        let mut header = serde_json::json!({ "alg": alg, "typ": "JWT" });
+        if let Some(kid) = kid {
+            header["kid"] = kid.into();
+        }
+
+        let message = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header.to_string()),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        let rng = SystemRandom::new();
+        let sig = match alg {
+            "RS256" => {
+                let pair = signature::RsaKeyPair::from_pkcs8(key).unwrap();
+                let mut sig = vec![0; pair.public_modulus_len()];
+                pair.sign(
+                    &signature::RSA_PKCS1_SHA256,
+                    &rng,
+                    message.as_bytes(),
+                    &mut sig,
+                )
+                .unwrap();
+                sig
+            }
+            "ES256" => signature::EcdsaKeyPair::from_pkcs8(
+                &signature::ECDSA_P256_SHA256_FIXED_SIGNING,
+                key,
+            )
+            .unwrap()
+            .sign(&rng, message.as_bytes())
+            .unwrap()
+            .as_ref()
+            .to_vec(),
+            _ => hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), message.as_bytes())
+                .as_ref()
+                .to_vec(),
+        };
+
+        format!("{}.{}", message, URL_SAFE_NO_PAD.encode(sig))
+    }
+
+    // Generate a P-256 key, returning its PKCS#8 document and JWK.
+    #[allow(dead_code)]
+    fn ec_key(kid: &str) -> (Vec<u8>, serde_json::Value) {
+        use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+        use ring::{
+            rand::SystemRandom,
+            signature::{self, KeyPair},
+        };
+
+        let alg = &signature::ECDSA_P256_SHA256_FIXED_SIGNING;
+        let pkcs8 = signature::EcdsaKeyPair::generate_pkcs8(alg, &SystemRandom::new()).unwrap();
+        let point = signature::EcdsaKeyPair::from_pkcs8(alg, pkcs8.as_ref())
+            .unwrap()
+            .public_key()
+            .as_ref()
+            .to_vec();
+
+        (
+            pkcs8.as_ref().to_vec(),
+            serde_json::json!({
+                "kty": "EC",
+                "crv": "P-256",
+                "kid": kid,
+                "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+                "y": URL_SAFE_NO_PAD.encode(&point[33..]),
+            }),
+        )
+    }
+
+    #[test]
+    fn test_validate() {
+        use super::{Jwks, Jwt};
+        use serde_json::json;
+        use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let rsa = std::fs::read("testdata/jwt/rsa.pk8").unwrap();
+        let jwks = Jwks::load("testdata/jwt/jwks.json").unwrap();
+        assert_eq!(jwks.len(), 1);
+
+        let jwt = Jwt::new()
+            .with_secret(b"hmac secret")
+            .with_jwks(jwks)
+            .with_issuer("https://auth.example.com")
+            .with_audience("api")
+            .with_clock_skew(Duration::from_secs(30));
+        let claims = json!({
+            "sub": "alice",
+            "iss": "https://auth.example.com",
+            "aud": ["web", "api"],
+            "exp": now + 60,
+            "nbf": now - 60,
+            "admin": true,
+        });
+
+        let token = sign("HS256", None, claims.clone(), b"hmac secret");
+        let validated = jwt.validate(&token).unwrap();
+        assert_eq!(validated.subject().as_deref(), Some("alice"));
+        assert_eq!(validated.get::<bool>("admin"), Some(true));
+        assert_eq!(validated.audience(), vec!["web", "api"]);
+
+        let token = sign("RS256", Some("rsa-1"), claims.clone(), &rsa);
+        assert!(jwt.validate(&token).is_ok());
+        // the key ID must match, when it is given.
+        let token = sign("RS256", Some("rsa-2"), claims.clone(), &rsa);
+        assert_eq!(jwt.validate(&token).unwrap_err(), "invalid signature");
+
+        let with = |changes: serde_json::Value| {
+            let mut claims = claims.clone();
+            for (k, v) in changes.as_object().unwrap() {
+                if v.is_null() {
+                    claims.as_object_mut().unwrap().remove(k);
+                } else {
+                    claims[k] = v.clone();
+                }
+            }
+            sign("HS256", None, claims, b"hmac secret")
+        };
+
+        // within the clock skew.
+        assert!(jwt.validate(&with(json!({ "exp": now - 20 }))).is_ok());
+        assert!(jwt.validate(&with(json!({ "nbf": now + 20 }))).is_ok());
+        assert!(jwt.validate(&with(json!({ "aud": "api" }))).is_ok());
+
+        for (token, error) in [
+            (with(json!({ "exp": now - 40 })), "token expired"),
+            (with(json!({ "nbf": now + 40 })), "token not yet valid"),
+            (with(json!({ "exp": null })), "missing exp claim"),
+            (with(json!({ "exp": "soon" })), "invalid exp claim"),
+            (
+                with(json!({ "iss": "https://evil.example.com" })),
+                "invalid issuer",
+            ),
+            (with(json!({ "aud": "web" })), "invalid audience"),
+            (with(json!({ "aud": null })), "invalid audience"),
+            (
+                sign("HS256", None, claims.clone(), b"wrong"),
+                "invalid signature",
+            ),
+            (
+                sign("HS384", None, claims.clone(), b"hmac secret"),
+                "unsupported algorithm",
+            ),
+            ("a.b".to_string(), "malformed token"),
+            ("e30.e30.e30.e30".to_string(), "malformed token"),
+        ] {
+            assert_eq!(jwt.validate(&token).unwrap_err(), error);
+        }
+
+        // an HMAC key cannot be used to forge an RS256 token, nor the reverse.
+        let forged = sign("HS256", None, claims.clone(), b"hmac secret").replacen(
+            &base64::Engine::encode(
+                &base64::engine::general_purpose::URL_SAFE_NO_PAD,
+                r#"{"alg":"HS256","typ":"JWT"}"#,
+            ),
+            &base64::Engine::encode(
+                &base64::engine::general_purpose::URL_SAFE_NO_PAD,
+                r#"{"alg":"RS256","typ":"JWT"}"#,
+            ),
+            1,
+        );
+        assert_eq!(jwt.validate(&forged).unwrap_err(), "invalid signature");
+
+        assert!(Jwt::new()
+            .with_required_expiry(false)
+            .with_secret(b"hmac secret")
+            .validate(&with(json!({ "exp": null })))
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_jwt() {
+        use super::{Claims, Jwt};
+        use crate::{
+            app::{App, TestApp},
+            auth::Principal,
+            compose_handler, HTTPResult, NoState, Params,
+        };
+        use http::{Method, Request, Response, StatusCode};
+        use hyper::Body;
+        use serde_json::json;
+        use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+        async fn whoami(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            state: NoState,
+        ) -> HTTPResult<NoState> {
+            let claims = Claims::from_request(&req).unwrap();
+            let principal = Principal::from_request(&req).unwrap();
+            let body = format!(
+                "{} {}",
+                principal.name(),
+                claims.get::<String>("role").unwrap_or_default()
+            );
+            Ok((
+                req,
+                Some(
+                    Response::builder()
+                        .status(StatusCode::OK)
+                        .body(Body::from(body))?,
+                ),
+                state,
+            ))
+        }
+
+        async fn replace(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            state: NoState,
+        ) -> HTTPResult<NoState> {
+            Ok((
+                req,
+                Some(
+                    Response::builder()
+                        .status(StatusCode::OK)
+                        .body(Body::from("replaced"))?,
+                ),
+                state,
+            ))
+        }
+
+        let dir = std::env::temp_dir().join(format!("davisjr-jwks-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("jwks.json");
+
+        let (old, old_jwk) = ec_key("old");
+        let (new, new_jwk) = ec_key("new");
+        let write = |keys: Vec<&serde_json::Value>, age: u64| {
+            std::fs::write(&path, json!({ "keys": keys }).to_string()).unwrap();
+            std::fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(SystemTime::now() - Duration::from_secs(age))
+                .unwrap();
+        };
+        write(vec![&old_jwk], 60);
+
+        let mut app: App<(), NoState> = App::new();
+        app.get(
+            "/whoami",
+            Jwt::new()
+                .with_jwks_file(&path)
+                .unwrap()
+                .with_cookie("token")
+                .wrap(compose_handler!(whoami)),
+        )
+        .unwrap();
+        app.get(
+            "/chained",
+            Jwt::new()
+                .with_jwks_file(&path)
+                .unwrap()
+                .wrap(compose_handler!(whoami))
+                .then(compose_handler!(replace)),
+        )
+        .unwrap();
+        let test_app = TestApp::new(app);
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let claims = json!({ "sub": "alice", "role": "admin", "exp": now + 60 });
+        let old_token = sign("ES256", Some("old"), claims.clone(), &old);
+        let new_token = sign("ES256", Some("new"), claims.clone(), &new);
+
+        let resp = test_app
+            .request(Method::GET, "/whoami")
+            .header("authorization", format!("Bearer {}", old_token).as_str())
+            .send()
+            .await;
+        resp.assert_status(StatusCode::OK);
+        assert_eq!(resp.text(), "alice admin");
+
+        let resp = test_app
+            .request(Method::GET, "/whoami")
+            .header("cookie", format!("token={}", old_token).as_str())
+            .send()
+            .await;
+        assert_eq!(resp.text(), "alice admin");
+
+        test_app
+            .request(Method::GET, "/whoami")
+            .send()
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED)
+            .assert_header("www-authenticate", "Bearer realm=\"api\"");
+
+        // rejections end the chain, so handlers after the wrapper cannot replace them.
+        test_app
+            .request(Method::GET, "/chained")
+            .send()
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        let resp = test_app
+            .request(Method::GET, "/chained")
+            .header("authorization", format!("Bearer {}", old_token).as_str())
+            .send()
+            .await;
+        assert_eq!(resp.text(), "replaced");
+        test_app
+            .request(Method::GET, "/whoami")
+            .header("authorization", format!("Bearer {}", new_token).as_str())
+            .send()
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED)
+            .assert_header(
+                "www-authenticate",
+                "Bearer realm=\"api\", error=\"invalid_token\", \
+                 error_description=\"invalid signature\"",
+            );
+
+        // rotate the keys.
+        write(vec![&new_jwk], 0);
+        for (token, status) in [
+            (&new_token, StatusCode::OK),
+            (&old_token, StatusCode::UNAUTHORIZED),
+        ] {
+            test_app
+                .request(Method::GET, "/whoami")
+                .header("authorization", format!("Bearer {}", token).as_str())
+                .send()
+                .await
+                .assert_status(status);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,15 +1,52 @@
 /// Application/Server-level management and routing configuration and testing support; outermost functionality.
 pub mod app;
+/// HTTP Basic and Bearer authentication middleware
+pub mod auth;
+/// Request body size limits
+pub mod body;
+/// Response compression and request decompression middleware
+#[cfg(feature = "compression")]
+pub mod compression;
+/// Conditional requests: entity tags, validators and precondition evaluation
+pub mod conditional;
+/// Connection information inserted into requests by the server
+pub mod connection;
+/// Cookie parsing, Set-Cookie building, and signed and encrypted cookies
+pub mod cookies;
+/// Cross-Origin Resource Sharing middleware
+pub mod cors;
+/// Cross-site request forgery protection middleware
+pub mod csrf;
 /// Error types that davisjr uses
 pub mod errors;
+/// Static file serving
+pub mod files;
 /// Handler construction and prototypes
 pub mod handler;
+/// JSON Web Token validation middleware
+pub mod jwt;
 /// Macros for quality-of-life when interacting with Handlers
 pub mod macros;
 /// Path management for Routes
 pub(crate) mod path;
+/// Reverse proxy support: trusted proxies, forwarded client information and the PROXY protocol
+pub mod proxy;
+/// Range requests: byte ranges, partial content and multipart/byteranges responses
+pub mod range;
+/// Rate limiting middleware and backends
+pub mod ratelimit;
 /// Router, Route management and organization
 pub(crate) mod router;
+/// Server-side sessions and the stores which keep them
+pub mod sessions;
+/// Testing support: request builders, response assertions, cookie jars, recorded fixtures,
+/// handler unit tests and real-socket test servers
+pub mod testing;
+/// Request timeouts and cancellation
+pub mod timeout;
+/// TLS configuration helpers: PEM loading and self-signed certificates
+#[cfg(feature = "tls")]
+pub mod tls;
 
 use http::{Request, Response};
 use std::{collections::BTreeMap, pin::Pin};
@@ -19,6 +56,20 @@ pub type Params = BTreeMap<String, String>;
 
 pub(crate) type PinBox<F> = Pin<Box<F>>;
 
+// Add the header name to the Vary header, unless it is already there.
+pub(crate) fn add_vary(headers: &mut http::HeaderMap, name: &'static str) {
+    let varies = headers.get_all(http::header::VARY).iter().any(|v| {
+        v.to_str()
+            .unwrap_or_default()
+            .split(',')
+            .any(|n| n.trim() == "*" || n.trim().eq_ignore_ascii_case(name))
+    });
+
+    if !varies {
+        headers.append(http::header::VARY, http::HeaderValue::from_static(name));
+    }
+}
+
 /// HTTPResult is the return type for handlers. If a handler terminates at the end of its chain
 /// with [std::option::Option::None] as the [http::Response], a 500 Internal Server Error will be
 /// returned. If you wish to return Err(), a [http::StatusCode] or [std::string::String] can be
@@ -61,7 +112,8 @@ impl TransientState for NoState {
 /// ```
 pub mod prelude {
     pub use crate::{
-        app::App, compose_handler, errors::*, HTTPResult, NoState, Params, TransientState,
+        app::App, compose_handler, connection::ConnectionInfo, errors::*, HTTPResult, NoState,
+        Params, TransientState,
     };
     pub use http::{Request, Response, StatusCode};
     pub use hyper::Body;
@@ -10,11 +10,11 @@ macro_rules! compose_handler {
         {
             use $crate::handler::{HandlerFunc, Handler};
             {
-                let mut funcs: Vec<HandlerFunc<_, _>> = Vec::new();
-
-                $(
-                    funcs.push(|req, resp, params, app, state| Box::pin($x(req, resp, params, app, state)));
-                )*
+                let mut funcs: Vec<HandlerFunc<_, _>> = vec![
+                    $(
+                        |req, resp, params, app, state| Box::pin($x(req, resp, params, app, state)),
+                    )*
+                ];
 
                 if funcs.len() == 0 {
                     panic!("compose_handler requires at least one handler to be supplied")
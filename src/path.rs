@@ -236,8 +236,8 @@ impl Default for Path {
     }
 }
 
-impl ToString for Path {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for Path {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut s = Vec::new();
 
         for part in self.0.clone() {
@@ -252,10 +252,10 @@ impl ToString for Path {
         }
 
         if s.len() < 2 {
-            return "/".to_string();
+            return f.write_str("/");
         }
 
-        s.join("/")
+        f.write_str(&s.join("/"))
     }
 }
 
@@ -0,0 +1,526 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+};
+
+use http::HeaderMap;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::errors::ServerError;
+
+/// Cidr is a network in CIDR notation, such as `10.0.0.0/8` or `fd00::/8`. A bare address is
+/// treated as a network containing only that address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Returns true if the address is within the network. IPv4-mapped IPv6 addresses are
+    /// compared as IPv4.
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (self.addr, canonical(*addr)) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = ServerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+
+        let addr = canonical(addr.trim().parse::<IpAddr>()?);
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| ServerError(format!("invalid prefix length in {}", s)))?,
+            None => max,
+        };
+
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl std::fmt::Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Forwarded is the client information reported by trusted reverse proxies through the
+/// `Forwarded` or `X-Forwarded-*` headers.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Forwarded {
+    /// The address of the client, as seen by the outermost trusted proxy.
+    pub client_ip: Option<IpAddr>,
+    /// The scheme the client used to reach the proxy, e.g. `https`.
+    pub proto: Option<String>,
+    /// The `Host` the client requested from the proxy.
+    pub host: Option<String>,
+}
+
+impl Forwarded {
+    /// Derive the client information from the request headers. The proxy chain is walked from the
+    /// nearest hop outward, and the first address which is not a trusted proxy is the client;
+    /// anything an untrusted hop claims beyond that is ignored. The `Forwarded` header is
+    /// preferred over the `X-Forwarded-*` headers when both are present.
+    ///
+    /// The caller is responsible for checking that the peer itself is a trusted proxy.
+    pub fn from_headers(headers: &HeaderMap, trusted: &[Cidr]) -> Option<Self> {
+        let elements = forwarded_elements(headers);
+        if !elements.is_empty() {
+            let mut chosen = None;
+            for element in elements.iter().rev() {
+                chosen = Some(element);
+                match element.client_ip {
+                    Some(ip) if is_trusted(trusted, &ip) => continue,
+                    _ => break,
+                }
+            }
+
+            return chosen.cloned();
+        }
+
+        let xff = header_list(headers, "x-forwarded-for");
+        if xff.is_empty() {
+            return None;
+        }
+
+        let mut client_ip = None;
+        for addr in xff.iter().rev() {
+            match parse_node(addr) {
+                Some(ip) => {
+                    client_ip = Some(ip);
+                    if !is_trusted(trusted, &ip) {
+                        break;
+                    }
+                }
+                None => {
+                    client_ip = None;
+                    break;
+                }
+            }
+        }
+
+        Some(Self {
+            client_ip,
+            proto: header_list(headers, "x-forwarded-proto")
+                .pop()
+                .map(|s| s.to_lowercase()),
+            host: header_list(headers, "x-forwarded-host").pop(),
+        })
+    }
+}
+
+// The signature which opens a PROXY protocol v2 header.
+const PROXY_V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+// The longest PROXY protocol v1 header allowed by the specification, including the CRLF.
+const PROXY_V1_MAX_LENGTH: usize = 107;
+
+/// ProxyHeader holds the addresses carried by a PROXY protocol header. Both are
+/// [std::option::Option::None] when the proxy reports a `LOCAL` (v2) or `UNKNOWN` (v1)
+/// connection, such as a health check.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProxyHeader {
+    /// The address of the client which connected to the proxy.
+    pub source: Option<SocketAddr>,
+    /// The address the client connected to on the proxy.
+    pub destination: Option<SocketAddr>,
+}
+
+impl ProxyHeader {
+    /// Read a PROXY protocol v1 or v2 header from the front of the stream. Exactly the bytes of
+    /// the header are consumed, so the stream is left positioned at the start of the proxied
+    /// data.
+    pub async fn read<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Self, ServerError> {
+        // v1 headers are at least 15 bytes long and v2 headers at least 16, so this never reads
+        // past the header.
+        let mut buf = vec![0u8; PROXY_V2_SIGNATURE.len()];
+        stream.read_exact(&mut buf).await?;
+
+        if buf == PROXY_V2_SIGNATURE {
+            let mut header = [0u8; 4];
+            stream.read_exact(&mut header).await?;
+
+            let mut payload = vec![0u8; u16::from_be_bytes([header[2], header[3]]) as usize];
+            stream.read_exact(&mut payload).await?;
+
+            Self::parse_v2(header[0], header[1], &payload)
+        } else if buf.starts_with(b"PROXY ") {
+            while !buf.ends_with(b"\r\n") {
+                if buf.len() >= PROXY_V1_MAX_LENGTH {
+                    return Err(ServerError(
+                        "PROXY protocol v1 header is too long".to_string(),
+                    ));
+                }
+
+                let mut byte = [0u8; 1];
+                stream.read_exact(&mut byte).await?;
+                buf.push(byte[0]);
+            }
+
+            Self::parse_v1(&buf[..buf.len() - 2])
+        } else {
+            Err(ServerError(
+                "connection did not begin with a PROXY protocol header".to_string(),
+            ))
+        }
+    }
+
+    fn parse_v1(line: &[u8]) -> Result<Self, ServerError> {
+        let invalid = || ServerError("invalid PROXY protocol v1 header".to_string());
+
+        let line = std::str::from_utf8(line).map_err(|_| invalid())?;
+        let parts: Vec<&str> = line.split(' ').collect();
+
+        match parts.get(1).copied() {
+            Some("UNKNOWN") => Ok(Self::default()),
+            Some("TCP4") | Some("TCP6") if parts.len() == 6 => {
+                let v6 = parts[1] == "TCP6";
+                let addr = |ip: &str, port: &str| -> Result<SocketAddr, ServerError> {
+                    let ip: IpAddr = ip.parse().map_err(|_| invalid())?;
+                    if ip.is_ipv6() != v6 {
+                        return Err(invalid());
+                    }
+                    Ok(SocketAddr::new(ip, port.parse().map_err(|_| invalid())?))
+                };
+
+                Ok(Self {
+                    source: Some(addr(parts[2], parts[4])?),
+                    destination: Some(addr(parts[3], parts[5])?),
+                })
+            }
+            _ => Err(invalid()),
+        }
+    }
+
+    fn parse_v2(version_command: u8, family: u8, payload: &[u8]) -> Result<Self, ServerError> {
+        if version_command >> 4 != 2 {
+            return Err(ServerError(format!(
+                "unsupported PROXY protocol version {}",
+                version_command >> 4
+            )));
+        }
+
+        match version_command & 0x0f {
+            // LOCAL: the proxy's own connection; use the real peer.
+            0x0 => return Ok(Self::default()),
+            // PROXY
+            0x1 => {}
+            command => {
+                return Err(ServerError(format!(
+                    "unsupported PROXY protocol v2 command {}",
+                    command
+                )))
+            }
+        }
+
+        let short = || ServerError("PROXY protocol v2 address block is too short".to_string());
+        let port = |b: &[u8]| u16::from_be_bytes([b[0], b[1]]);
+
+        // the high nibble is the address family; the low nibble (stream or datagram) does not
+        // matter to us. TLVs following the addresses are ignored.
+        match family >> 4 {
+            0x1 => {
+                let b = payload.get(..12).ok_or_else(short)?;
+                Ok(Self {
+                    source: Some(SocketAddr::new(
+                        Ipv4Addr::new(b[0], b[1], b[2], b[3]).into(),
+                        port(&b[8..10]),
+                    )),
+                    destination: Some(SocketAddr::new(
+                        Ipv4Addr::new(b[4], b[5], b[6], b[7]).into(),
+                        port(&b[10..12]),
+                    )),
+                })
+            }
+            0x2 => {
+                let b = payload.get(..36).ok_or_else(short)?;
+                let ip = |b: &[u8]| {
+                    let mut octets = [0u8; 16];
+                    octets.copy_from_slice(b);
+                    IpAddr::from(Ipv6Addr::from(octets))
+                };
+
+                Ok(Self {
+                    source: Some(SocketAddr::new(ip(&b[..16]), port(&b[32..34]))),
+                    destination: Some(SocketAddr::new(ip(&b[16..32]), port(&b[34..36]))),
+                })
+            }
+            // AF_UNSPEC and AF_UNIX carry no addresses we can use.
+            _ => Ok(Self::default()),
+        }
+    }
+}
+
+pub(crate) fn is_trusted(trusted: &[Cidr], ip: &IpAddr) -> bool {
+    trusted.iter().any(|c| c.contains(ip))
+}
+
+fn canonical(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => addr,
+        },
+        _ => addr,
+    }
+}
+
+// Collect every comma-separated value of a header across all its occurrences.
+fn header_list(headers: &HeaderMap, name: &str) -> Vec<String> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+// Parse a node identifier from `Forwarded: for=` or `X-Forwarded-For`. These may carry ports, and
+// IPv6 addresses may be bracketed. Obfuscated identifiers and `unknown` yield None.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok().map(canonical);
+    }
+
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(canonical(ip));
+    }
+
+    // IPv4 with a port
+    node.rsplit_once(':')
+        .and_then(|(ip, _)| ip.parse().ok())
+        .map(canonical)
+}
+
+fn forwarded_elements(headers: &HeaderMap) -> Vec<Forwarded> {
+    header_list(headers, "forwarded")
+        .into_iter()
+        .map(|element| {
+            let mut forwarded = Forwarded::default();
+
+            for pair in element.split(';') {
+                if let Some((key, value)) = pair.split_once('=') {
+                    let value = value.trim().trim_matches('"');
+                    match key.trim().to_lowercase().as_str() {
+                        "for" => forwarded.client_ip = parse_node(value),
+                        "proto" => forwarded.proto = Some(value.to_lowercase()),
+                        "host" => forwarded.host = Some(value.to_string()),
+                        _ => {}
+                    }
+                }
+            }
+
+            forwarded
+        })
+        .collect()
+}
+
+mod tests {
+    #[test]
+    fn test_cidr() {
+        use super::Cidr;
+        use std::net::IpAddr;
+
+        let cidr: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(cidr.contains(&"10.1.2.3".parse::<IpAddr>().unwrap()));
+        assert!(cidr.contains(&"::ffff:10.1.2.3".parse::<IpAddr>().unwrap()));
+        assert!(!cidr.contains(&"11.1.2.3".parse::<IpAddr>().unwrap()));
+        assert!(!cidr.contains(&"fd00::1".parse::<IpAddr>().unwrap()));
+
+        let cidr: Cidr = "fd00::/8".parse().unwrap();
+        assert!(cidr.contains(&"fd12::1".parse::<IpAddr>().unwrap()));
+        assert!(!cidr.contains(&"fe80::1".parse::<IpAddr>().unwrap()));
+
+        let cidr: Cidr = "192.168.1.1".parse().unwrap();
+        assert_eq!(cidr.to_string(), "192.168.1.1/32");
+        assert!(cidr.contains(&"192.168.1.1".parse::<IpAddr>().unwrap()));
+        assert!(!cidr.contains(&"192.168.1.2".parse::<IpAddr>().unwrap()));
+
+        let cidr: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(cidr.contains(&"1.2.3.4".parse::<IpAddr>().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_forwarded() {
+        use super::{Cidr, Forwarded};
+        use http::HeaderMap;
+
+        let trusted: Vec<Cidr> = vec!["10.0.0.0/8".parse().unwrap()];
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(Forwarded::from_headers(&headers, &trusted), None);
+
+        // the client spoofs an address; the proxy appends the real one.
+        headers.insert(
+            "x-forwarded-for",
+            "1.1.1.1, 2.2.2.2, 10.0.0.2".parse().unwrap(),
+        );
+        headers.insert("x-forwarded-proto", "HTTPS".parse().unwrap());
+        headers.insert("x-forwarded-host", "example.com".parse().unwrap());
+        assert_eq!(
+            Forwarded::from_headers(&headers, &trusted),
+            Some(Forwarded {
+                client_ip: Some("2.2.2.2".parse().unwrap()),
+                proto: Some("https".to_string()),
+                host: Some("example.com".to_string()),
+            })
+        );
+
+        // entirely trusted chains resolve to the outermost address.
+        headers.insert("x-forwarded-for", "10.0.0.3, 10.0.0.2".parse().unwrap());
+        assert_eq!(
+            Forwarded::from_headers(&headers, &trusted)
+                .unwrap()
+                .client_ip,
+            Some("10.0.0.3".parse().unwrap())
+        );
+
+        // the Forwarded header takes precedence.
+        headers.insert(
+            "forwarded",
+            r#"for=1.1.1.1;proto=http, for="[2001:db8::1]:4711";proto=https;host=example.org, for=10.0.0.2"#
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(
+            Forwarded::from_headers(&headers, &trusted),
+            Some(Forwarded {
+                client_ip: Some("2001:db8::1".parse().unwrap()),
+                proto: Some("https".to_string()),
+                host: Some("example.org".to_string()),
+            })
+        );
+
+        headers.insert("forwarded", "for=unknown".parse().unwrap());
+        assert_eq!(
+            Forwarded::from_headers(&headers, &trusted),
+            Some(Forwarded::default())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_proxy_header() {
+        use super::ProxyHeader;
+        use tokio::io::AsyncReadExt;
+
+        let mut stream: &[u8] = b"PROXY TCP4 1.2.3.4 10.0.0.1 4711 443\r\nGET / HTTP/1.1\r\n";
+        assert_eq!(
+            ProxyHeader::read(&mut stream).await.unwrap(),
+            ProxyHeader {
+                source: Some("1.2.3.4:4711".parse().unwrap()),
+                destination: Some("10.0.0.1:443".parse().unwrap()),
+            }
+        );
+        let mut rest = String::new();
+        stream.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "GET / HTTP/1.1\r\n");
+
+        let mut stream: &[u8] = b"PROXY TCP6 2001:db8::1 2001:db8::2 4711 443\r\n";
+        assert_eq!(
+            ProxyHeader::read(&mut stream).await.unwrap().source,
+            Some("[2001:db8::1]:4711".parse().unwrap())
+        );
+
+        let mut stream: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(
+            ProxyHeader::read(&mut stream).await.unwrap(),
+            ProxyHeader::default()
+        );
+
+        for bad in [
+            &b"PROXY TCP4 2001:db8::1 10.0.0.1 4711 443\r\n"[..],
+            &b"PROXY TCP4 1.2.3.4 10.0.0.1 4711\r\n"[..],
+            &b"PROXY TCP4 1.2.3.4 10.0.0.1 4711 99999\r\n"[..],
+            &b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n"[..],
+            &[b"PROXY TCP4 ".as_slice(), &[b'1'; 120]].concat()[..],
+        ] {
+            let mut stream = bad;
+            assert!(ProxyHeader::read(&mut stream).await.is_err());
+        }
+
+        let mut v2 = super::PROXY_V2_SIGNATURE.to_vec();
+        v2.extend([0x21, 0x11, 0, 15]);
+        v2.extend([1, 2, 3, 4, 10, 0, 0, 1, 0x12, 0x67, 0x01, 0xbb]);
+        // a TLV to be skipped
+        v2.extend([0x04, 0, 0]);
+        v2.extend(b"GET");
+
+        let mut stream = v2.as_slice();
+        assert_eq!(
+            ProxyHeader::read(&mut stream).await.unwrap(),
+            ProxyHeader {
+                source: Some("1.2.3.4:4711".parse().unwrap()),
+                destination: Some("10.0.0.1:443".parse().unwrap()),
+            }
+        );
+        assert_eq!(stream, b"GET");
+
+        let mut v2 = super::PROXY_V2_SIGNATURE.to_vec();
+        v2.extend([0x21, 0x21, 0, 36]);
+        v2.extend(
+            "2001:db8::1"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        v2.extend(
+            "2001:db8::2"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        v2.extend([0x12, 0x67, 0x01, 0xbb]);
+
+        let mut stream = v2.as_slice();
+        assert_eq!(
+            ProxyHeader::read(&mut stream).await.unwrap().source,
+            Some("[2001:db8::1]:4711".parse().unwrap())
+        );
+
+        let mut local = super::PROXY_V2_SIGNATURE.to_vec();
+        local.extend([0x20, 0x00, 0, 0]);
+        let mut stream = local.as_slice();
+        assert_eq!(
+            ProxyHeader::read(&mut stream).await.unwrap(),
+            ProxyHeader::default()
+        );
+
+        let mut short = super::PROXY_V2_SIGNATURE.to_vec();
+        short.extend([0x21, 0x11, 0, 4, 1, 2, 3, 4]);
+        let mut stream = short.as_slice();
+        assert!(ProxyHeader::read(&mut stream).await.is_err());
+
+        let mut version = super::PROXY_V2_SIGNATURE.to_vec();
+        version.extend([0x11, 0x11, 0, 0]);
+        let mut stream = version.as_slice();
+        assert!(ProxyHeader::read(&mut stream).await.is_err());
+    }
+}
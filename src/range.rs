@@ -0,0 +1,595 @@
+use std::{
+    io::{Cursor, SeekFrom},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use http::{
+    header::{
+        ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_RANGE, LAST_MODIFIED,
+        RANGE,
+    },
+    HeaderMap, HeaderValue, Method, Request, Response, StatusCode,
+};
+use hyper::{
+    body::{Bytes, HttpBody},
+    Body,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+
+use crate::{conditional::EntityTag, errors::Error, handler::Handler, TransientState};
+
+// The size of the chunks bodies are streamed in.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// Requests for more ranges than this, after overlapping ranges are merged, are answered with the
+// whole representation; many tiny ranges are far costlier to serve than one large one.
+const MAX_RANGES: usize = 16;
+
+static BOUNDARY_ID: AtomicU64 = AtomicU64::new(0);
+
+/// ByteRange is a satisfiable range of bytes within a representation. Both ends are inclusive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    // The number of bytes in the range; never zero.
+    pub(crate) fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+/// Ranges is the interpretation of a `Range` header against a representation of known length.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Ranges {
+    /// The header is absent, malformed or uses a unit other than bytes, and the whole
+    /// representation should be sent.
+    Ignored,
+    /// The ranges to send, ordered and with overlapping ranges merged.
+    Satisfiable(Vec<ByteRange>),
+    /// None of the ranges overlap the representation; the answer is 416 Range Not Satisfiable.
+    Unsatisfiable,
+}
+
+impl Ranges {
+    /// Interpret a `Range` header value for a representation of the length.
+    pub fn parse(value: &str, len: u64) -> Self {
+        let specs = match value.split_once('=') {
+            Some((unit, specs)) if unit.trim().eq_ignore_ascii_case("bytes") => specs,
+            _ => return Self::Ignored,
+        };
+
+        let mut ranges = Vec::new();
+
+        for spec in specs.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            let (first, last) = match spec.split_once('-') {
+                Some(pair) => pair,
+                None => return Self::Ignored,
+            };
+
+            let range = if first.is_empty() {
+                // a suffix: the last N bytes.
+                match last.parse::<u64>() {
+                    Ok(0) => None,
+                    Ok(_) if len == 0 => None,
+                    Ok(n) => Some(ByteRange {
+                        start: len.saturating_sub(n),
+                        end: len - 1,
+                    }),
+                    Err(_) => return Self::Ignored,
+                }
+            } else {
+                let first = match first.parse::<u64>() {
+                    Ok(first) => first,
+                    Err(_) => return Self::Ignored,
+                };
+                let last = match last {
+                    "" => u64::MAX,
+                    last => match last.parse::<u64>() {
+                        Ok(last) if last >= first => last,
+                        _ => return Self::Ignored,
+                    },
+                };
+
+                (first < len).then(|| ByteRange {
+                    start: first,
+                    end: last.min(len - 1),
+                })
+            };
+
+            ranges.extend(range);
+        }
+
+        if ranges.is_empty() {
+            return Self::Unsatisfiable;
+        }
+
+        ranges.sort_by_key(|r| r.start);
+        let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end.saturating_add(1) => {
+                    last.end = last.end.max(range.end)
+                }
+                _ => merged.push(range),
+            }
+        }
+
+        if merged.len() > MAX_RANGES {
+            return Self::Ignored;
+        }
+
+        Self::Satisfiable(merged)
+    }
+
+    /// Interpret the request's `Range` header for a representation of the length. The header is
+    /// ignored for methods other than GET, and when an `If-Range` precondition does not match the
+    /// `ETag` or `Last-Modified` values among the response headers.
+    pub fn from_request<B>(req: &Request<B>, headers: &HeaderMap, len: u64) -> Self {
+        if req.method() != Method::GET {
+            return Self::Ignored;
+        }
+
+        let value = match req.headers().get(RANGE).and_then(|v| v.to_str().ok()) {
+            Some(value) => value,
+            None => return Self::Ignored,
+        };
+
+        if let Some(if_range) = req.headers().get(IF_RANGE) {
+            let if_range = if_range.to_str().unwrap_or_default().trim();
+            let current = |name| {
+                headers
+                    .get(name)
+                    .and_then(|v: &HeaderValue| v.to_str().ok())
+            };
+
+            let matches = if if_range.starts_with('"') || if_range.starts_with("W/") {
+                match (
+                    if_range.parse::<EntityTag>(),
+                    current(ETAG).map(|e| e.parse::<EntityTag>()),
+                ) {
+                    (Ok(wanted), Some(Ok(etag))) => wanted.strong_eq(&etag),
+                    _ => false,
+                }
+            } else {
+                match (httpdate::parse_http_date(if_range), current(LAST_MODIFIED)) {
+                    (Ok(wanted), Some(modified)) => {
+                        httpdate::parse_http_date(modified).is_ok_and(|m| m == wanted)
+                    }
+                    _ => false,
+                }
+            };
+
+            if !matches {
+                return Self::Ignored;
+            }
+        }
+
+        Self::parse(value, len)
+    }
+}
+
+/// Answer the request with the seekable source of the given length, honoring its `Range`
+/// header: the whole source is sent with 200 OK, a single range with 206 Partial Content, and
+/// multiple ranges as a `multipart/byteranges` body. Ranges that cannot be satisfied are answered
+/// with 416 Range Not Satisfiable. The provided headers are included in the response, and their
+/// `Content-Type`, `ETag` and `Last-Modified` values are used for the parts of a multipart body
+/// and for `If-Range`. Responses advertise `Accept-Ranges: bytes`, and bodies are streamed from
+/// the source; `HEAD` requests are answered without one.
+///
+/// Files and in-memory buffers (through [std::io::Cursor]) are both seekable sources:
+///
+/// ```ignore
+///     let file = tokio::fs::File::open("build.log").await?;
+///     let len = file.metadata().await?.len();
+///     let mut headers = HeaderMap::new();
+///     headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+///     let resp = serve_range(&req, headers, file, len).await?;
+/// ```
+pub async fn serve_range<R>(
+    req: &Request<Body>,
+    mut headers: HeaderMap,
+    source: R,
+    len: u64,
+) -> Result<Response<Body>, Error>
+where
+    R: AsyncRead + AsyncSeek + Unpin + Send + 'static,
+{
+    headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+
+    let (status, segments) = match Ranges::from_request(req, &headers, len) {
+        Ranges::Ignored => (
+            StatusCode::OK,
+            vec![Segment::Source(ByteRange {
+                start: 0,
+                end: len.wrapping_sub(1),
+            })],
+        ),
+        Ranges::Unsatisfiable => {
+            headers.remove(CONTENT_TYPE);
+            headers.insert(
+                CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes */{}", len))?,
+            );
+            headers.insert(CONTENT_LENGTH, HeaderValue::from_static("0"));
+
+            let mut resp = Response::new(Body::default());
+            *resp.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+            *resp.headers_mut() = headers;
+            return Ok(resp);
+        }
+        Ranges::Satisfiable(ranges) if ranges.len() == 1 => {
+            let range = ranges[0];
+            headers.insert(
+                CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes {}-{}/{}", range.start, range.end, len))?,
+            );
+            (StatusCode::PARTIAL_CONTENT, vec![Segment::Source(range)])
+        }
+        Ranges::Satisfiable(ranges) => {
+            let boundary = boundary();
+            let content_type = headers.remove(CONTENT_TYPE);
+            let mut segments = Vec::new();
+
+            for range in ranges {
+                let mut part = format!("\r\n--{}\r\n", boundary);
+                if let Some(content_type) = &content_type {
+                    part += &format!("Content-Type: {}\r\n", content_type.to_str()?);
+                }
+                part += &format!(
+                    "Content-Range: bytes {}-{}/{}\r\n\r\n",
+                    range.start, range.end, len
+                );
+
+                segments.push(Segment::Data(Bytes::from(part)));
+                segments.push(Segment::Source(range));
+            }
+            segments.push(Segment::Data(Bytes::from(format!(
+                "\r\n--{}--\r\n",
+                boundary
+            ))));
+
+            headers.insert(
+                CONTENT_TYPE,
+                HeaderValue::from_str(&format!("multipart/byteranges; boundary={}", boundary))?,
+            );
+            (StatusCode::PARTIAL_CONTENT, segments)
+        }
+    };
+
+    // an empty source has no bytes to send, not all of them.
+    let segments: Vec<Segment> = segments
+        .into_iter()
+        .filter(|s| !matches!(s, Segment::Source(_) if len == 0))
+        .collect();
+
+    let content_length: u64 = segments.iter().map(|s| s.len()).sum();
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(content_length));
+
+    let body = if req.method() == Method::HEAD {
+        Body::default()
+    } else {
+        segmented_body(source, segments)
+    };
+
+    let mut resp = Response::new(body);
+    *resp.status_mut() = status;
+    *resp.headers_mut() = headers;
+    Ok(resp)
+}
+
+/// Ranges is middleware which answers `Range` requests on behalf of the handlers before it in the
+/// chain. Successful GET responses whose bodies are held in memory are served through
+/// [serve_range]; other responses, and those which already carry an `Accept-Ranges` or
+/// `Content-Range` header, pass through untouched.
+///
+/// ```ignore
+///     app.get("/artifact/:id", compose_handler!(artifact).then(ranges()))?;
+/// ```
+pub fn ranges<S: Clone + Send + 'static, T: TransientState + 'static>() -> Handler<S, T> {
+    Handler::from_fn(
+        |req, resp, _params, _app, state| {
+            Box::pin(async move {
+                let resp = match resp {
+                    Some(resp)
+                        if resp.status() == StatusCode::OK
+                            && (req.method() == Method::GET || req.method() == Method::HEAD)
+                            && resp.body().size_hint().exact().is_some()
+                            && !resp.headers().contains_key(ACCEPT_RANGES)
+                            && !resp.headers().contains_key(CONTENT_RANGE) =>
+                    {
+                        let (parts, body) = resp.into_parts();
+                        let bytes = hyper::body::to_bytes(body).await?;
+                        let len = bytes.len() as u64;
+                        Some(serve_range(&req, parts.headers, Cursor::new(bytes), len).await?)
+                    }
+                    resp => resp,
+                };
+
+                Ok((req, resp, state))
+            })
+        },
+        None,
+    )
+}
+
+// A piece of a streamed body: literal bytes, or a range read from the source.
+enum Segment {
+    Data(Bytes),
+    Source(ByteRange),
+}
+
+impl Segment {
+    fn len(&self) -> u64 {
+        match self {
+            Self::Data(bytes) => bytes.len() as u64,
+            Self::Source(range) => range.len(),
+        }
+    }
+}
+
+// Stream the segments as a body, reading ranges from the source as they are reached.
+fn segmented_body<R>(mut source: R, segments: Vec<Segment>) -> Body
+where
+    R: AsyncRead + AsyncSeek + Unpin + Send + 'static,
+{
+    let (mut tx, body) = Body::channel();
+
+    tokio::spawn(async move {
+        for segment in segments {
+            let range = match segment {
+                Segment::Data(bytes) => {
+                    if tx.send_data(bytes).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+                Segment::Source(range) => range,
+            };
+
+            if source.seek(SeekFrom::Start(range.start)).await.is_err() {
+                tx.abort();
+                return;
+            }
+
+            let mut remaining = range.len();
+            while remaining > 0 {
+                let mut buf = vec![0; CHUNK_SIZE.min(remaining as usize)];
+                let n = match source.read(&mut buf).await {
+                    Ok(n) if n > 0 => n,
+                    // the source shrank or could not be read; the body is shorter than its
+                    // Content-Length.
+                    _ => {
+                        tx.abort();
+                        return;
+                    }
+                };
+
+                buf.truncate(n);
+                remaining -= n as u64;
+                // the client went away.
+                if tx.send_data(Bytes::from(buf)).await.is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    body
+}
+
+// A multipart boundary unlikely to appear in any body.
+fn boundary() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    let id = BOUNDARY_ID.fetch_add(1, Ordering::Relaxed);
+    format!(
+        "davisjr-{:016x}",
+        (nanos ^ id.rotate_left(32)).wrapping_mul(0x9e3779b97f4a7c15)
+    )
+}
+
+mod tests {
+    #[test]
+    fn test_parse_ranges() {
+        use super::{ByteRange, Ranges};
+
+        let r = |start, end| ByteRange { start, end };
+
+        assert_eq!(
+            Ranges::parse("bytes=0-499", 1000),
+            Ranges::Satisfiable(vec![r(0, 499)])
+        );
+        assert_eq!(
+            Ranges::parse("bytes=500-", 1000),
+            Ranges::Satisfiable(vec![r(500, 999)])
+        );
+        assert_eq!(
+            Ranges::parse("bytes=-200", 1000),
+            Ranges::Satisfiable(vec![r(800, 999)])
+        );
+        assert_eq!(
+            Ranges::parse("bytes=-2000", 1000),
+            Ranges::Satisfiable(vec![r(0, 999)])
+        );
+        assert_eq!(
+            Ranges::parse("bytes=900-5000", 1000),
+            Ranges::Satisfiable(vec![r(900, 999)])
+        );
+        assert_eq!(
+            Ranges::parse("Bytes= 500-599, 0-99 ,", 1000),
+            Ranges::Satisfiable(vec![r(0, 99), r(500, 599)])
+        );
+        // overlapping and adjacent ranges are merged.
+        assert_eq!(
+            Ranges::parse("bytes=0-99,50-149,150-199,-10", 1000),
+            Ranges::Satisfiable(vec![r(0, 199), r(990, 999)])
+        );
+        // unsatisfiable ranges are dropped when others remain.
+        assert_eq!(
+            Ranges::parse("bytes=2000-,0-0", 1000),
+            Ranges::Satisfiable(vec![r(0, 0)])
+        );
+
+        assert_eq!(Ranges::parse("bytes=1000-", 1000), Ranges::Unsatisfiable);
+        assert_eq!(Ranges::parse("bytes=-0", 1000), Ranges::Unsatisfiable);
+        assert_eq!(Ranges::parse("bytes=-5", 0), Ranges::Unsatisfiable);
+        assert_eq!(Ranges::parse("bytes=", 1000), Ranges::Unsatisfiable);
+
+        for invalid in [
+            "items=0-1",
+            "bytes 0-1",
+            "bytes=1",
+            "bytes=a-b",
+            "bytes=5-1",
+            "bytes=--1",
+        ] {
+            assert_eq!(Ranges::parse(invalid, 1000), Ranges::Ignored, "{}", invalid);
+        }
+
+        let many: Vec<String> = (0..20).map(|i| format!("{}-{}", i * 10, i * 10)).collect();
+        assert_eq!(
+            Ranges::parse(&format!("bytes={}", many.join(",")), 1000),
+            Ranges::Ignored
+        );
+    }
+
+    #[tokio::test]
+    async fn test_serve_range() {
+        use super::{ranges, serve_range};
+        use crate::{
+            app::{App, TestApp},
+            compose_handler, HTTPResult, NoState, Params,
+        };
+        use http::{
+            header::{CONTENT_TYPE, ETAG},
+            HeaderMap, HeaderValue, Method, Request, Response, StatusCode,
+        };
+        use hyper::Body;
+        use std::io::Cursor;
+
+        const DATA: &str = "0123456789abcdefghijklmnopqrstuvwxyz";
+
+        async fn log(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            _state: NoState,
+        ) -> HTTPResult<NoState> {
+            let mut headers = HeaderMap::new();
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+            headers.insert(ETAG, HeaderValue::from_static("\"log-1\""));
+
+            let resp = serve_range(&req, headers, Cursor::new(DATA), DATA.len() as u64).await?;
+            Ok((req, Some(resp), NoState {}))
+        }
+
+        async fn bytes(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            _state: NoState,
+        ) -> HTTPResult<NoState> {
+            Ok((
+                req,
+                Some(Response::builder().status(200).body(Body::from(DATA))?),
+                NoState {},
+            ))
+        }
+
+        let mut app = App::new();
+        app.get("/log", compose_handler!(log)).unwrap();
+        app.head("/log", compose_handler!(log)).unwrap();
+        app.get("/bytes", compose_handler!(bytes).then(ranges()))
+            .unwrap();
+        let test_app = TestApp::new(app);
+
+        let resp = test_app.request(Method::GET, "/log").send().await;
+        resp.assert_status(StatusCode::OK)
+            .assert_header("accept-ranges", "bytes")
+            .assert_header("content-length", "36");
+        assert_eq!(resp.text(), DATA);
+
+        let resp = test_app
+            .request(Method::GET, "/log")
+            .header("range", "bytes=10-15")
+            .send()
+            .await;
+        resp.assert_status(StatusCode::PARTIAL_CONTENT)
+            .assert_header("content-range", "bytes 10-15/36")
+            .assert_header("content-length", "6")
+            .assert_header("content-type", "text/plain");
+        assert_eq!(resp.text(), "abcdef");
+
+        let resp = test_app
+            .request(Method::HEAD, "/log")
+            .header("range", "bytes=10-15")
+            .send()
+            .await;
+        resp.assert_status(StatusCode::OK)
+            .assert_header("content-length", "36");
+        assert!(resp.bytes().is_empty());
+
+        let resp = test_app
+            .request(Method::GET, "/log")
+            .header("range", "bytes=-3,0-1")
+            .send()
+            .await;
+        resp.assert_status(StatusCode::PARTIAL_CONTENT);
+        let content_type = resp.header("content-type").unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap();
+        let expected = format!(
+            "\r\n--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/36\r\n\r\n01\
+             \r\n--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 33-35/36\r\n\r\nxyz\
+             \r\n--{b}--\r\n",
+            b = boundary
+        );
+        assert_eq!(resp.text(), expected);
+        resp.assert_header("content-length", &expected.len().to_string());
+
+        let resp = test_app
+            .request(Method::GET, "/log")
+            .header("range", "bytes=36-")
+            .send()
+            .await;
+        resp.assert_status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .assert_header("content-range", "bytes */36");
+
+        // If-Range must match the current entity tag for the range to apply.
+        let resp = test_app
+            .request(Method::GET, "/log")
+            .header("range", "bytes=0-0")
+            .header("if-range", "\"log-1\"")
+            .send()
+            .await;
+        resp.assert_status(StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.text(), "0");
+
+        let resp = test_app
+            .request(Method::GET, "/log")
+            .header("range", "bytes=0-0")
+            .header("if-range", "\"log-0\"")
+            .send()
+            .await;
+        resp.assert_status(StatusCode::OK);
+        assert_eq!(resp.text(), DATA);
+
+        let resp = test_app
+            .request(Method::GET, "/bytes")
+            .header("range", "bytes=-1")
+            .send()
+            .await;
+        resp.assert_status(StatusCode::PARTIAL_CONTENT)
+            .assert_header("content-range", "bytes 35-35/36");
+        assert_eq!(resp.text(), "z");
+    }
+}
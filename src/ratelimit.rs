@@ -0,0 +1,730 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use http::{HeaderMap, HeaderValue, Request, Response, StatusCode};
+use hyper::Body;
+
+use crate::{
+    auth::Principal,
+    errors::Error,
+    handler::{Handler, Terminal},
+    PinBox, TransientState,
+};
+
+/// Strategy is how requests are counted against a [Quota].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Strategy {
+    /// A bucket holding up to the quota's limit of tokens, refilled steadily over its period,
+    /// from which each request takes one. Bursts of up to the limit are allowed, after which
+    /// requests are spaced evenly.
+    TokenBucket,
+    /// A count of the requests in the last period, estimated from the counts of the current and
+    /// previous fixed windows. Bursts are smoothed over the period.
+    SlidingWindow,
+}
+
+/// Quota is the number of requests allowed per period.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Quota {
+    limit: u64,
+    period: Duration,
+}
+
+impl Quota {
+    /// Allow `limit` requests per `period`. Neither may be zero.
+    pub fn new(limit: u64, period: Duration) -> Self {
+        Self {
+            limit: limit.max(1),
+            period: period.max(Duration::from_millis(1)),
+        }
+    }
+
+    /// Allow `limit` requests per second.
+    pub fn per_second(limit: u64) -> Self {
+        Self::new(limit, Duration::from_secs(1))
+    }
+
+    /// Allow `limit` requests per minute.
+    pub fn per_minute(limit: u64) -> Self {
+        Self::new(limit, Duration::from_secs(60))
+    }
+
+    /// Allow `limit` requests per hour.
+    pub fn per_hour(limit: u64) -> Self {
+        Self::new(limit, Duration::from_secs(60 * 60))
+    }
+
+    /// The number of requests allowed per period.
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    /// The period.
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+}
+
+/// Decision is a backend's answer for a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Decision {
+    /// Whether the request is allowed.
+    pub allowed: bool,
+    /// The number of further requests which would be allowed now.
+    pub remaining: u64,
+    /// The time until the quota is fully available again.
+    pub reset: Duration,
+    /// For denied requests, the time until a request would be allowed.
+    pub retry_after: Option<Duration>,
+}
+
+/// RateLimitBackend counts requests by key. The [MemoryBackend] counts the requests of one
+/// process; implement this to share counts between processes, such as in a database.
+pub trait RateLimitBackend: Send + Sync {
+    /// Count a request for the key against the quota, deciding whether it is allowed. Denied
+    /// requests are not counted.
+    fn hit<'a>(
+        &'a self,
+        key: &'a str,
+        quota: Quota,
+        strategy: Strategy,
+    ) -> PinBox<dyn Future<Output = Result<Decision, Error>> + Send + 'a>;
+}
+
+/// Counter is the state of one key, for backends which keep it themselves.
+#[derive(Clone, Copy, Debug)]
+pub enum Counter {
+    /// The tokens left in a [Strategy::TokenBucket], when last updated.
+    Bucket { tokens: f64, updated: Instant },
+    /// The counts of the current and previous windows of a [Strategy::SlidingWindow], and when
+    /// the current window started.
+    Window {
+        start: Instant,
+        current: u64,
+        previous: u64,
+    },
+}
+
+impl Counter {
+    /// A counter which has not seen any requests.
+    pub fn new(strategy: Strategy, quota: Quota, now: Instant) -> Self {
+        match strategy {
+            Strategy::TokenBucket => Self::Bucket {
+                tokens: quota.limit as f64,
+                updated: now,
+            },
+            Strategy::SlidingWindow => Self::Window {
+                start: now,
+                current: 0,
+                previous: 0,
+            },
+        }
+    }
+
+    /// Count a request at `now` against the quota.
+    pub fn hit(&mut self, quota: Quota, now: Instant) -> Decision {
+        let limit = quota.limit as f64;
+        let period = quota.period.as_secs_f64();
+
+        match self {
+            Self::Bucket { tokens, updated } => {
+                let rate = limit / period;
+                let elapsed = now.saturating_duration_since(*updated).as_secs_f64();
+                *tokens = (*tokens + elapsed * rate).min(limit);
+                *updated = now;
+
+                let allowed = *tokens >= 1.0;
+                let retry_after = if allowed {
+                    *tokens -= 1.0;
+                    None
+                } else {
+                    Some(Duration::from_secs_f64((1.0 - *tokens) / rate))
+                };
+
+                Decision {
+                    allowed,
+                    remaining: tokens.floor() as u64,
+                    reset: Duration::from_secs_f64((limit - *tokens) / rate),
+                    retry_after,
+                }
+            }
+            Self::Window {
+                start,
+                current,
+                previous,
+            } => {
+                let windows = now.saturating_duration_since(*start).as_secs_f64() / period;
+                if windows >= 2.0 {
+                    *previous = 0;
+                    *current = 0;
+                } else if windows >= 1.0 {
+                    *previous = *current;
+                    *current = 0;
+                }
+                if windows >= 1.0 {
+                    *start += quota.period.mul_f64(windows.floor());
+                }
+
+                let into = now.saturating_duration_since(*start).as_secs_f64();
+                let weight = 1.0 - into / period;
+                let estimate = *previous as f64 * weight + *current as f64;
+                let reset = Duration::from_secs_f64(period - into);
+
+                if estimate + 1.0 <= limit {
+                    *current += 1;
+                    return Decision {
+                        allowed: true,
+                        remaining: (limit - estimate - 1.0).floor() as u64,
+                        reset,
+                        retry_after: None,
+                    };
+                }
+
+                // wait until enough of the previous window has slid out, or for the next window
+                // if this one alone is full.
+                let retry_after = if (*current as f64) + 1.0 <= limit && *previous > 0 {
+                    let excess = estimate + 1.0 - limit;
+                    Duration::from_secs_f64((excess / *previous as f64 * period).min(period - into))
+                } else {
+                    reset
+                };
+
+                Decision {
+                    allowed: false,
+                    remaining: 0,
+                    reset,
+                    retry_after: Some(retry_after),
+                }
+            }
+        }
+    }
+
+    // Whether the counter is back to its initial state, so it may be forgotten.
+    fn idle(&self, quota: Quota, now: Instant) -> bool {
+        let since = match self {
+            Self::Bucket { updated, .. } => *updated,
+            Self::Window { start, .. } => *start,
+        };
+
+        now.saturating_duration_since(since) >= quota.period * 2
+    }
+}
+
+// A key, counted separately for each strategy and quota.
+type CounterKey = (String, Strategy, Quota);
+
+/// MemoryBackend counts requests in memory, in one process. Clones share their counts. A key is
+/// counted separately for each strategy and quota it is hit with, so limits sharing a backend
+/// never read each other's counters. Counters which have returned to their initial state are
+/// forgotten from time to time.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryBackend {
+    counters: Arc<Mutex<HashMap<CounterKey, Counter>>>,
+    hits: Arc<AtomicU64>,
+}
+
+impl MemoryBackend {
+    /// A backend with no counts.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of counters.
+    pub fn len(&self) -> usize {
+        self.counters.lock().unwrap().len()
+    }
+
+    /// Whether no keys have counters.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl RateLimitBackend for MemoryBackend {
+    fn hit<'a>(
+        &'a self,
+        key: &'a str,
+        quota: Quota,
+        strategy: Strategy,
+    ) -> PinBox<dyn Future<Output = Result<Decision, Error>> + Send + 'a> {
+        Box::pin(async move {
+            let now = Instant::now();
+            let mut counters = self.counters.lock().unwrap();
+
+            if self.hits.fetch_add(1, Ordering::Relaxed) % 1024 == 1023 {
+                counters.retain(|(_, _, quota), counter| !counter.idle(*quota, now));
+            }
+
+            let counter = counters
+                .entry((key.to_string(), strategy, quota))
+                .or_insert_with(|| Counter::new(strategy, quota, now));
+            Ok(counter.hit(quota, now))
+        })
+    }
+}
+
+type KeyFn = Arc<dyn Fn(&Request<Body>) -> Option<String> + Send + Sync>;
+
+/// KeyBy is what requests are counted by.
+#[derive(Clone)]
+pub enum KeyBy {
+    /// The client's address, as inserted in the request extensions by the server (see
+    /// [crate::app::App::with_trusted_proxies]). Requests over unix sockets have no address, and
+    /// are counted by the user ID of their [crate::app::PeerCredentials] instead. Requests with
+    /// neither are not limited.
+    ClientIp,
+    /// The authenticated [crate::auth::Principal], or the client as for [KeyBy::ClientIp] for
+    /// requests without one.
+    Principal,
+    /// The method and path of the request, shared by all clients.
+    Route,
+    /// A key computed from the request. Requests for which it returns
+    /// [std::option::Option::None] are not limited.
+    Custom(KeyFn),
+}
+
+impl KeyBy {
+    /// Count requests by a key computed from the request.
+    pub fn custom<F>(f: F) -> Self
+    where
+        F: Fn(&Request<Body>) -> Option<String> + Send + Sync + 'static,
+    {
+        Self::Custom(Arc::new(f))
+    }
+
+    fn key(&self, req: &Request<Body>) -> Option<String> {
+        let client = || {
+            if let Some(ip) = req.extensions().get::<IpAddr>() {
+                return Some(format!("ip:{}", ip));
+            }
+
+            #[cfg(feature = "unix")]
+            if let Some(creds) = req.extensions().get::<crate::app::PeerCredentials>() {
+                return Some(format!("uid:{}", creds.uid));
+            }
+
+            None
+        };
+
+        match self {
+            Self::ClientIp => client(),
+            Self::Principal => match Principal::from_request(req) {
+                Some(principal) => Some(format!(
+                    "principal:{}:{}",
+                    principal.scheme(),
+                    principal.name()
+                )),
+                None => client(),
+            },
+            Self::Route => Some(format!("route:{} {}", req.method(), req.uri().path())),
+            Self::Custom(f) => f(req).map(|k| format!("custom:{}", k)),
+        }
+    }
+}
+
+impl std::fmt::Debug for KeyBy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ClientIp => f.write_str("ClientIp"),
+            Self::Principal => f.write_str("Principal"),
+            Self::Route => f.write_str("Route"),
+            Self::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+/// RateLimit limits the rate of requests to the handlers it wraps:
+///
+/// ```ignore
+///     let limit = RateLimit::new(Quota::per_minute(60)).with_key(KeyBy::Principal);
+///     app.post("/api/*", BearerAuth::new("api", verify).wrap(limit.wrap(compose_handler!(api))))?;
+/// ```
+///
+/// Requests are counted by client address by default, with a [Strategy::TokenBucket] in a
+/// [MemoryBackend]. Requests over the quota are answered with 429 Too Many Requests and a
+/// `Retry-After` header, ending the chain without running the wrapped handlers or any after
+/// them. All responses carry the `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset`
+/// headers, and `RateLimit-Policy`, as described by the IETF `RateLimit` header fields draft.
+///
+/// Limits keyed by principal must be wrapped by the authentication middleware, so that the
+/// principal is known. Each RateLimit keeps its own counts unless they are given the same
+/// backend, in which case limits with the same key, quota and strategy share counts, and a
+/// client is limited across all the routes using them.
+#[derive(Clone)]
+pub struct RateLimit {
+    quota: Quota,
+    strategy: Strategy,
+    key: KeyBy,
+    backend: Arc<dyn RateLimitBackend>,
+}
+
+impl RateLimit {
+    /// Limit requests to the quota.
+    pub fn new(quota: Quota) -> Self {
+        Self {
+            quota,
+            strategy: Strategy::TokenBucket,
+            key: KeyBy::ClientIp,
+            backend: Arc::new(MemoryBackend::new()),
+        }
+    }
+
+    /// Set how requests are counted.
+    pub fn with_strategy(mut self, strategy: Strategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Set what requests are counted by.
+    pub fn with_key(mut self, key: KeyBy) -> Self {
+        self.key = key;
+        self
+    }
+
+    /// Set where requests are counted.
+    pub fn with_backend(mut self, backend: impl RateLimitBackend + 'static) -> Self {
+        self.backend = Arc::new(backend);
+        self
+    }
+
+    /// Wrap the handler (or chain), so that it only runs for requests within the quota.
+    pub fn wrap<S: Clone + Send + 'static, T: TransientState + 'static>(
+        self,
+        inner: Handler<S, T>,
+    ) -> Handler<S, T> {
+        let limit = Arc::new(self);
+
+        inner.around(move |inner, req, resp, params, app, state| {
+            let limit = limit.clone();
+
+            Box::pin(async move {
+                let key = match limit.key.key(&req) {
+                    Some(key) => key,
+                    None => return inner.perform(req, resp, params, app, state).await,
+                };
+
+                let decision = limit.backend.hit(&key, limit.quota, limit.strategy).await?;
+
+                if !decision.allowed {
+                    let mut resp = Response::builder()
+                        .status(StatusCode::TOO_MANY_REQUESTS)
+                        .body(Body::from("too many requests\n"))?;
+                    limit.add_headers(resp.headers_mut(), &decision);
+                    return Ok((req, Some(Terminal::mark(resp)), state));
+                }
+
+                let (req, mut resp, state) = inner.perform(req, resp, params, app, state).await?;
+                if let Some(resp) = resp.as_mut() {
+                    limit.add_headers(resp.headers_mut(), &decision);
+                }
+
+                Ok((req, resp, state))
+            })
+        })
+    }
+
+    fn add_headers(&self, headers: &mut HeaderMap, decision: &Decision) {
+        headers.insert("ratelimit-limit", HeaderValue::from(self.quota.limit));
+        headers.insert("ratelimit-remaining", HeaderValue::from(decision.remaining));
+        headers.insert(
+            "ratelimit-reset",
+            HeaderValue::from(ceil_secs(decision.reset)),
+        );
+        if let Ok(policy) = HeaderValue::from_str(&format!(
+            "{};w={}",
+            self.quota.limit,
+            ceil_secs(self.quota.period)
+        )) {
+            headers.insert("ratelimit-policy", policy);
+        }
+
+        if let Some(retry_after) = decision.retry_after {
+            headers.insert(
+                http::header::RETRY_AFTER,
+                HeaderValue::from(ceil_secs(retry_after).max(1)),
+            );
+        }
+    }
+}
+
+fn ceil_secs(d: Duration) -> u64 {
+    d.as_secs() + u64::from(d.subsec_nanos() > 0)
+}
+
+mod tests {
+    #[test]
+    fn test_token_bucket() {
+        use super::{Counter, Quota, Strategy};
+        use std::time::{Duration, Instant};
+
+        let quota = Quota::new(3, Duration::from_secs(3));
+        let start = Instant::now();
+        let mut counter = Counter::new(Strategy::TokenBucket, quota, start);
+
+        for remaining in [2, 1, 0] {
+            let decision = counter.hit(quota, start);
+            assert!(decision.allowed);
+            assert_eq!(decision.remaining, remaining);
+        }
+
+        let decision = counter.hit(quota, start);
+        assert!(!decision.allowed);
+        assert_eq!(decision.retry_after, Some(Duration::from_secs(1)));
+        assert_eq!(decision.reset, Duration::from_secs(3));
+
+        // one token is refilled per second.
+        let decision = counter.hit(quota, start + Duration::from_secs(1));
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 0);
+        assert!(!counter.hit(quota, start + Duration::from_secs(1)).allowed);
+
+        // the bucket holds no more than the limit.
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(counter.hit(quota, later).allowed);
+        }
+        assert!(!counter.hit(quota, later).allowed);
+    }
+
+    #[test]
+    fn test_sliding_window() {
+        use super::{Counter, Quota, Strategy};
+        use std::time::{Duration, Instant};
+
+        let quota = Quota::new(4, Duration::from_secs(10));
+        let start = Instant::now();
+        let mut counter = Counter::new(Strategy::SlidingWindow, quota, start);
+
+        for remaining in [3, 2, 1, 0] {
+            let decision = counter.hit(quota, start);
+            assert!(decision.allowed);
+            assert_eq!(decision.remaining, remaining);
+        }
+
+        let decision = counter.hit(quota, start + Duration::from_secs(5));
+        assert!(!decision.allowed);
+        assert_eq!(decision.retry_after, Some(Duration::from_secs(5)));
+
+        // halfway through the next window, half of the previous window still counts.
+        let halfway = start + Duration::from_secs(15);
+        for _ in 0..2 {
+            assert!(counter.hit(quota, halfway).allowed);
+        }
+        let decision = counter.hit(quota, halfway);
+        assert!(!decision.allowed);
+        // 4 * 0.5 + 2 = 4, so a quarter of the previous window must slide out.
+        assert_eq!(decision.retry_after, Some(Duration::from_millis(2500)));
+
+        // two windows later, nothing counts.
+        let later = start + Duration::from_secs(31);
+        for _ in 0..4 {
+            assert!(counter.hit(quota, later).allowed);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        use super::{KeyBy, MemoryBackend, Quota, RateLimit, Strategy};
+        use crate::{
+            app::{App, TestApp},
+            auth::BearerAuth,
+            compose_handler, HTTPResult, NoState, Params,
+        };
+        use http::{Method, Request, Response, StatusCode};
+        use hyper::Body;
+
+        async fn ok(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            state: NoState,
+        ) -> HTTPResult<NoState> {
+            Ok((
+                req,
+                Some(
+                    Response::builder()
+                        .status(StatusCode::OK)
+                        .body(Body::default())?,
+                ),
+                state,
+            ))
+        }
+
+        async fn teapot(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            state: NoState,
+        ) -> HTTPResult<NoState> {
+            Ok((
+                req,
+                Some(
+                    Response::builder()
+                        .status(StatusCode::IM_A_TEAPOT)
+                        .body(Body::default())?,
+                ),
+                state,
+            ))
+        }
+
+        let backend = MemoryBackend::new();
+        let mut app: App<(), NoState> = App::new();
+        app.get(
+            "/ip",
+            RateLimit::new(Quota::per_minute(2))
+                .with_backend(backend.clone())
+                .wrap(compose_handler!(ok)),
+        )
+        .unwrap();
+        app.get(
+            "/strict",
+            RateLimit::new(Quota::per_minute(1))
+                .with_backend(backend.clone())
+                .wrap(compose_handler!(ok)),
+        )
+        .unwrap();
+        app.get(
+            "/chained",
+            RateLimit::new(Quota::per_minute(1))
+                .wrap(compose_handler!(ok))
+                .then(compose_handler!(teapot)),
+        )
+        .unwrap();
+        app.get(
+            "/user",
+            BearerAuth::with_tokens("api", &[("a", "alice"), ("b", "bob")]).wrap(
+                RateLimit::new(Quota::per_hour(1))
+                    .with_key(KeyBy::Principal)
+                    .with_strategy(Strategy::SlidingWindow)
+                    .wrap(compose_handler!(ok)),
+            ),
+        )
+        .unwrap();
+        app.get(
+            "/route/:id",
+            RateLimit::new(Quota::per_minute(1))
+                .with_key(KeyBy::Route)
+                .wrap(compose_handler!(ok)),
+        )
+        .unwrap();
+        let test_app = TestApp::new(app);
+
+        let resp = test_app.request(Method::GET, "/ip").send().await;
+        resp.assert_status(StatusCode::OK)
+            .assert_header("ratelimit-limit", "2")
+            .assert_header("ratelimit-remaining", "1")
+            .assert_header("ratelimit-reset", "30")
+            .assert_header("ratelimit-policy", "2;w=60");
+        assert!(resp.header("retry-after").is_none());
+
+        test_app
+            .request(Method::GET, "/ip")
+            .send()
+            .await
+            .assert_status(StatusCode::OK)
+            .assert_header("ratelimit-remaining", "0");
+        let resp = test_app.request(Method::GET, "/ip").send().await;
+        resp.assert_status(StatusCode::TOO_MANY_REQUESTS)
+            .assert_header("retry-after", "30")
+            .assert_header("ratelimit-remaining", "0");
+
+        // other clients have their own counts.
+        test_app
+            .with_peer_addr("10.0.0.2:1234".parse().unwrap())
+            .request(Method::GET, "/ip")
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        assert_eq!(backend.len(), 2);
+
+        // limits sharing a backend with another quota keep their own counts.
+        for status in [StatusCode::OK, StatusCode::TOO_MANY_REQUESTS] {
+            test_app
+                .with_peer_addr("10.0.0.4:1234".parse().unwrap())
+                .request(Method::GET, "/strict")
+                .send()
+                .await
+                .assert_status(status);
+        }
+        test_app
+            .with_peer_addr("10.0.0.4:1234".parse().unwrap())
+            .request(Method::GET, "/ip")
+            .send()
+            .await
+            .assert_status(StatusCode::OK)
+            .assert_header("ratelimit-remaining", "1");
+        assert_eq!(backend.len(), 4);
+
+        // the 429 ends the chain.
+        for status in [StatusCode::IM_A_TEAPOT, StatusCode::TOO_MANY_REQUESTS] {
+            test_app
+                .request(Method::GET, "/chained")
+                .send()
+                .await
+                .assert_status(status);
+        }
+
+        for (token, status) in [
+            ("a", StatusCode::OK),
+            ("a", StatusCode::TOO_MANY_REQUESTS),
+            ("b", StatusCode::OK),
+        ] {
+            test_app
+                .request(Method::GET, "/user")
+                .header("authorization", format!("Bearer {}", token).as_str())
+                .send()
+                .await
+                .assert_status(status);
+        }
+
+        for (path, status) in [
+            ("/route/1", StatusCode::OK),
+            ("/route/1", StatusCode::TOO_MANY_REQUESTS),
+            ("/route/2", StatusCode::OK),
+        ] {
+            test_app
+                .with_peer_addr("10.0.0.3:1234".parse().unwrap())
+                .request(Method::GET, path)
+                .send()
+                .await
+                .assert_status(status);
+        }
+    }
+
+    #[test]
+    fn test_key_by_client() {
+        use super::KeyBy;
+        use http::Request;
+        use hyper::Body;
+        use std::net::IpAddr;
+
+        let mut req = Request::new(Body::default());
+        assert_eq!(KeyBy::ClientIp.key(&req), None);
+        assert_eq!(KeyBy::Principal.key(&req), None);
+
+        #[cfg(feature = "unix")]
+        {
+            req.extensions_mut().insert(crate::app::PeerCredentials {
+                uid: 1000,
+                gid: 1000,
+                pid: None,
+            });
+            assert_eq!(KeyBy::ClientIp.key(&req), Some("uid:1000".to_string()));
+        }
+
+        req.extensions_mut()
+            .insert("10.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(KeyBy::ClientIp.key(&req), Some("ip:10.0.0.1".to_string()));
+        assert_eq!(KeyBy::Principal.key(&req), Some("ip:10.0.0.1".to_string()));
+    }
+}
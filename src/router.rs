@@ -1,7 +1,15 @@
 use http::{Request, Response};
 use hyper::Body;
 
-use crate::{app::App, errors::*, handler::Handler, path::Path, HTTPResult, TransientState};
+use crate::{
+    app::App,
+    body::limit_body_default,
+    errors::*,
+    handler::Handler,
+    path::Path,
+    timeout::{timed_out, Cancellation, TimeoutOverride},
+    HTTPResult, TransientState,
+};
 
 #[derive(Clone)]
 pub(crate) struct Route<S: Clone + Send, T: TransientState + 'static> {
@@ -33,7 +41,7 @@ impl<S: Clone + Send, T: TransientState> Ord for Route<S, T> {
     }
 }
 
-impl<S: Clone + Send, T: TransientState> Route<S, T> {
+impl<S: Clone + Send + 'static, T: TransientState> Route<S, T> {
     fn new(
         method: http::Method,
         path: String,
@@ -62,14 +70,35 @@ impl<S: Clone + Send, T: TransientState> Route<S, T> {
             ));
         }
 
-        self.handler.perform(req, None, params, app, state).await
+        let mut req = match app.body_limit() {
+            Some(limit) => limit_body_default(req, limit, self.handler.body_limit().is_none())?,
+            None => req,
+        };
+
+        let (timeout, status) = app.timeout();
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => return self.handler.perform(req, None, params, app, state).await,
+        };
+
+        let overridden = TimeoutOverride::default();
+        req.extensions_mut().insert(overridden.clone());
+
+        let cancellation = Cancellation::from_request(&req);
+        let mut perform = self.handler.perform(req, None, params, app, state);
+        match tokio::time::timeout(timeout, &mut perform).await {
+            Ok(result) => result,
+            // a handler in the chain has set its own timeout, which it enforces instead.
+            Err(_) if overridden.is_set() => perform.await,
+            Err(_) => Err(timed_out(cancellation, status)),
+        }
     }
 }
 
 #[derive(Clone)]
 pub(crate) struct Router<S: Clone + Send, T: TransientState + 'static>(Vec<Route<S, T>>);
 
-impl<S: Clone + Send, T: TransientState + Clone + Send> Router<S, T> {
+impl<S: Clone + Send + 'static, T: TransientState + Clone + Send> Router<S, T> {
     pub fn new() -> Self {
         Self(Vec::new())
     }
@@ -134,14 +163,14 @@ mod tests {
             _app: App<State, NoState>,
             _state: NoState,
         ) -> HTTPResult<NoState> {
-            return Ok((
+            Ok((
                 req,
                 Some(Response::builder().status(400).body(Body::from(format!(
                     "hello, {}",
                     *params.get("name").unwrap()
                 )))?),
                 NoState {},
-            ));
+            ))
         }
 
         let route = Route::new(
@@ -233,7 +262,7 @@ mod tests {
             _app: App<State, NoState>,
             _state: NoState,
         ) -> HTTPResult<NoState> {
-            return Ok((
+            Ok((
                 req,
                 Some(
                     Response::builder()
@@ -241,7 +270,7 @@ mod tests {
                         .body(Body::from("hello, world".as_bytes()))?,
                 ),
                 NoState {},
-            ));
+            ))
         }
 
         let route = Route::new(
@@ -351,7 +380,7 @@ mod tests {
             let name = params.get("name").unwrap().clone();
             state.name = Some(name.clone());
 
-            return Ok((
+            Ok((
                 req,
                 Some(
                     Response::builder()
@@ -363,7 +392,7 @@ mod tests {
                         .unwrap(),
                 ),
                 state,
-            ));
+            ))
         }
 
         async fn handler_continued(
@@ -373,7 +402,7 @@ mod tests {
             _app: App<State, HelloState>,
             state: HelloState,
         ) -> HTTPResult<HelloState> {
-            return Ok((
+            Ok((
                 req,
                 Some(
                     Response::builder()
@@ -385,7 +414,7 @@ mod tests {
                         .unwrap(),
                 ),
                 state,
-            ));
+            ))
         }
 
         async fn handler_static(
@@ -395,7 +424,7 @@ mod tests {
             _app: App<State, HelloState>,
             _state: HelloState,
         ) -> HTTPResult<HelloState> {
-            return Ok((
+            Ok((
                 req,
                 Some(
                     Response::builder()
@@ -403,7 +432,7 @@ mod tests {
                         .body(Body::from("hello, world".as_bytes()))?,
                 ),
                 HelloState::initial(),
-            ));
+            ))
         }
 
         let mut router = Router::new();
@@ -492,7 +521,7 @@ mod tests {
             assert_eq!(body, format!("hello, {}", name).as_bytes());
         }
 
-        for bad_route in vec!["/", "/bad", "/bad/route", "/a/b/c/param", "/c/b/a/0/bad"] {
+        for bad_route in ["/", "/bad", "/bad/route", "/a/b/c/param", "/c/b/a/0/bad"] {
             let response = router
                 .dispatch(
                     Request::builder()
@@ -0,0 +1,701 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use http::{Request, Response};
+use hyper::Body;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    cookies::{Cookies, SameSite, SetCookie},
+    errors::Error,
+    handler::Handler,
+    PinBox, TransientState,
+};
+
+/// SessionData is the content of a session: JSON values by key.
+pub type SessionData = BTreeMap<String, serde_json::Value>;
+
+/// SessionStore keeps sessions between requests, by ID. Sessions are saved with the time they
+/// expire, after which a store must no longer load them; stores may remove expired sessions
+/// whenever they like.
+pub trait SessionStore: Send + Sync {
+    /// Load the session, or [std::option::Option::None] if it does not exist or has expired.
+    fn load<'a>(
+        &'a self,
+        id: &'a str,
+    ) -> PinBox<dyn Future<Output = Result<Option<SessionData>, Error>> + Send + 'a>;
+
+    /// Save the session, replacing any with the same ID.
+    fn save<'a>(
+        &'a self,
+        id: &'a str,
+        data: &'a SessionData,
+        expires: SystemTime,
+    ) -> PinBox<dyn Future<Output = Result<(), Error>> + Send + 'a>;
+
+    /// Remove the session, if it exists.
+    fn remove<'a>(
+        &'a self,
+        id: &'a str,
+    ) -> PinBox<dyn Future<Output = Result<(), Error>> + Send + 'a>;
+}
+
+/// MemoryStore keeps sessions in memory, so they are lost when the process exits and are not
+/// shared between processes. Clones share their sessions.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStore {
+    sessions: Arc<Mutex<HashMap<String, (SessionData, SystemTime)>>>,
+}
+
+impl MemoryStore {
+    /// An empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of sessions held, including expired sessions not yet purged.
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    /// Whether the store holds no sessions.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remove expired sessions. Expired sessions are otherwise only removed when loaded.
+    pub fn purge(&self) {
+        let now = SystemTime::now();
+        self.sessions
+            .lock()
+            .unwrap()
+            .retain(|_, (_, expires)| *expires > now);
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn load<'a>(
+        &'a self,
+        id: &'a str,
+    ) -> PinBox<dyn Future<Output = Result<Option<SessionData>, Error>> + Send + 'a> {
+        Box::pin(async move {
+            let mut sessions = self.sessions.lock().unwrap();
+            match sessions.get(id) {
+                Some((data, expires)) if *expires > SystemTime::now() => Ok(Some(data.clone())),
+                Some(_) => {
+                    sessions.remove(id);
+                    Ok(None)
+                }
+                None => Ok(None),
+            }
+        })
+    }
+
+    fn save<'a>(
+        &'a self,
+        id: &'a str,
+        data: &'a SessionData,
+        expires: SystemTime,
+    ) -> PinBox<dyn Future<Output = Result<(), Error>> + Send + 'a> {
+        Box::pin(async move {
+            self.sessions
+                .lock()
+                .unwrap()
+                .insert(id.to_string(), (data.clone(), expires));
+            Ok(())
+        })
+    }
+
+    fn remove<'a>(
+        &'a self,
+        id: &'a str,
+    ) -> PinBox<dyn Future<Output = Result<(), Error>> + Send + 'a> {
+        Box::pin(async move {
+            self.sessions.lock().unwrap().remove(id);
+            Ok(())
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredSession {
+    expires: u64,
+    data: SessionData,
+}
+
+/// FileStore keeps each session in a JSON file in a directory, which is created if needed.
+/// Sessions survive restarts, and processes sharing the directory share sessions.
+#[derive(Clone, Debug)]
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    /// Store sessions in the directory.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Remove the files of expired sessions. Expired sessions are otherwise only removed when
+    /// loaded.
+    pub async fn purge(&self) -> Result<(), Error> {
+        let mut entries = match tokio::fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(Error::new(e)),
+        };
+
+        while let Some(entry) = entries.next_entry().await.map_err(Error::new)? {
+            if let Some(id) = entry
+                .file_name()
+                .to_str()
+                .and_then(|n| n.strip_suffix(".json"))
+            {
+                self.load(id).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // IDs are checked before they are used in a path, as they come from the client.
+    fn path(&self, id: &str) -> Option<PathBuf> {
+        valid_id(id).then(|| self.dir.join(format!("{}.json", id)))
+    }
+}
+
+impl SessionStore for FileStore {
+    fn load<'a>(
+        &'a self,
+        id: &'a str,
+    ) -> PinBox<dyn Future<Output = Result<Option<SessionData>, Error>> + Send + 'a> {
+        Box::pin(async move {
+            let path = match self.path(id) {
+                Some(path) => path,
+                None => return Ok(None),
+            };
+
+            let content = match tokio::fs::read(&path).await {
+                Ok(content) => content,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(Error::new(e)),
+            };
+
+            // unreadable sessions are treated as expired.
+            match serde_json::from_slice::<StoredSession>(&content) {
+                Ok(stored)
+                    if UNIX_EPOCH + Duration::from_secs(stored.expires) > SystemTime::now() =>
+                {
+                    Ok(Some(stored.data))
+                }
+                _ => {
+                    self.remove(id).await?;
+                    Ok(None)
+                }
+            }
+        })
+    }
+
+    fn save<'a>(
+        &'a self,
+        id: &'a str,
+        data: &'a SessionData,
+        expires: SystemTime,
+    ) -> PinBox<dyn Future<Output = Result<(), Error>> + Send + 'a> {
+        Box::pin(async move {
+            let path = self
+                .path(id)
+                .ok_or_else(|| Error::new("invalid session id"))?;
+            let stored = StoredSession {
+                expires: expires
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+                data: data.clone(),
+            };
+
+            // write a temporary file and rename it, so that readers never see a partial session.
+            tokio::fs::create_dir_all(&self.dir)
+                .await
+                .map_err(Error::new)?;
+            let tmp = path.with_extension("json.tmp");
+            tokio::fs::write(&tmp, serde_json::to_vec(&stored).map_err(Error::new)?)
+                .await
+                .map_err(Error::new)?;
+            tokio::fs::rename(&tmp, &path).await.map_err(Error::new)
+        })
+    }
+
+    fn remove<'a>(
+        &'a self,
+        id: &'a str,
+    ) -> PinBox<dyn Future<Output = Result<(), Error>> + Send + 'a> {
+        Box::pin(async move {
+            let path = match self.path(id) {
+                Some(path) => path,
+                None => return Ok(()),
+            };
+
+            match tokio::fs::remove_file(&path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(Error::new(e)),
+                _ => Ok(()),
+            }
+        })
+    }
+}
+
+const ID_LEN: usize = 32;
+
+// Generate a random identifier, also used for other unguessable tokens.
+pub(crate) fn generate_id() -> Result<String, Error> {
+    let mut id = [0u8; ID_LEN];
+    SystemRandom::new()
+        .fill(&mut id)
+        .map_err(|_| Error::new("could not generate session id"))?;
+    Ok(URL_SAFE_NO_PAD.encode(id))
+}
+
+fn valid_id(id: &str) -> bool {
+    id.len() == URL_SAFE_NO_PAD.encode([0u8; ID_LEN]).len()
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+#[derive(Debug, Default)]
+struct SessionState {
+    id: Option<String>,
+    data: SessionData,
+    rotate: bool,
+    destroyed: bool,
+}
+
+/// Session is the session of a request handled within [Sessions], which handlers find in the
+/// request extensions with [Session::from_request]. Clones refer to the same session, and changes
+/// are saved once the handlers have finished.
+#[derive(Clone, Debug, Default)]
+pub struct Session(Arc<Mutex<SessionState>>);
+
+impl Session {
+    /// The session of the request, if it is handled within [Sessions].
+    pub fn from_request(req: &Request<Body>) -> Option<Self> {
+        req.extensions().get::<Self>().cloned()
+    }
+
+    /// The ID of the session, or [std::option::Option::None] if it is new and has not been
+    /// saved.
+    pub fn id(&self) -> Option<String> {
+        self.0.lock().unwrap().id.clone()
+    }
+
+    /// The value for the key, if it is present and can be deserialized as `V`.
+    pub fn get<V: DeserializeOwned>(&self, key: &str) -> Option<V> {
+        self.0
+            .lock()
+            .unwrap()
+            .data
+            .get(key)
+            .and_then(|v| serde_json::from_value(v.clone()).ok())
+    }
+
+    /// Set the value for the key.
+    pub fn insert<V: Serialize>(&self, key: &str, value: V) -> Result<(), Error> {
+        let value = serde_json::to_value(value).map_err(Error::new)?;
+        self.0.lock().unwrap().data.insert(key.to_string(), value);
+        Ok(())
+    }
+
+    /// Remove the key, returning its value if it was present.
+    pub fn remove(&self, key: &str) -> Option<serde_json::Value> {
+        self.0.lock().unwrap().data.remove(key)
+    }
+
+    /// Remove every key.
+    pub fn clear(&self) {
+        self.0.lock().unwrap().data.clear()
+    }
+
+    /// Whether the session holds no keys.
+    pub fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().data.is_empty()
+    }
+
+    /// Give the session a new ID when it is saved, removing the old one from the store. Rotate
+    /// the ID whenever the session's privileges change, such as at login, so that an ID obtained
+    /// before then is of no use.
+    pub fn rotate(&self) {
+        self.0.lock().unwrap().rotate = true;
+    }
+
+    /// Remove the session from the store and the client, such as at logout.
+    pub fn destroy(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.data.clear();
+        state.destroyed = true;
+    }
+}
+
+/// Sessions keeps server-side sessions for the handlers it wraps, identified by a cookie holding
+/// a random session ID:
+///
+/// ```ignore
+///     let sessions = Sessions::new(FileStore::new("/var/lib/app/sessions"));
+///     app.get("/", sessions.clone().wrap(compose_handler!(index)))?;
+///     app.post("/login", sessions.wrap(compose_handler!(login)))?;
+///
+///     // and in the handlers:
+///     let session = Session::from_request(&req).unwrap();
+///     session.insert("user", "alice")?;
+/// ```
+///
+/// The session is loaded before the wrapped handlers run and saved after they finish, unless they
+/// return an error. Sessions expire once they have not been used for the time to live, which is a
+/// day by default; each request that saves the session extends it. New sessions are only saved,
+/// and the cookie only sent, once they hold a value. Unknown, expired and malformed IDs sent by the
+/// client are ignored, and a new ID is generated for the session.
+///
+/// The cookie is `HttpOnly`, `SameSite=Lax` and applies to the whole site; set
+/// [Sessions::with_secure] when serving over HTTPS.
+#[derive(Clone)]
+pub struct Sessions {
+    store: Arc<dyn SessionStore>,
+    cookie_name: String,
+    ttl: Duration,
+    secure: bool,
+    same_site: SameSite,
+}
+
+impl Sessions {
+    /// Keep sessions in the store.
+    pub fn new(store: impl SessionStore + 'static) -> Self {
+        Self {
+            store: Arc::new(store),
+            cookie_name: "session".to_string(),
+            ttl: Duration::from_secs(24 * 60 * 60),
+            secure: false,
+            same_site: SameSite::Lax,
+        }
+    }
+
+    /// Set the name of the session cookie.
+    pub fn with_cookie_name(mut self, name: &str) -> Self {
+        self.cookie_name = name.to_string();
+        self
+    }
+
+    /// Set how long an unused session lives.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Set the `Secure` attribute of the session cookie.
+    pub fn with_secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// Set the `SameSite` attribute of the session cookie.
+    pub fn with_same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = same_site;
+        self
+    }
+
+    /// Wrap the handler (or chain), so that it runs with the request's session.
+    pub fn wrap<S: Clone + Send + 'static, T: TransientState + 'static>(
+        self,
+        inner: Handler<S, T>,
+    ) -> Handler<S, T> {
+        let sessions = Arc::new(self);
+
+        inner.around(move |inner, mut req, resp, params, app, state| {
+            let sessions = sessions.clone();
+
+            Box::pin(async move {
+                let session = sessions.load(&req).await?;
+                req.extensions_mut().insert(session.clone());
+
+                let (req, mut resp, state) = inner.perform(req, resp, params, app, state).await?;
+                sessions.save(&session, resp.as_mut()).await?;
+                Ok((req, resp, state))
+            })
+        })
+    }
+
+    async fn load(&self, req: &Request<Body>) -> Result<Session, Error> {
+        let jar = Cookies::from_request(req);
+        for id in jar.get_all(&self.cookie_name) {
+            if let Some(data) = self.store.load(id).await? {
+                return Ok(Session(Arc::new(Mutex::new(SessionState {
+                    id: Some(id.to_string()),
+                    data,
+                    ..Default::default()
+                }))));
+            }
+        }
+
+        Ok(Session::default())
+    }
+
+    async fn save(
+        &self,
+        session: &Session,
+        resp: Option<&mut Response<Body>>,
+    ) -> Result<(), Error> {
+        let (old_id, data, rotate, destroyed) = {
+            let state = session.0.lock().unwrap();
+            (
+                state.id.clone(),
+                state.data.clone(),
+                state.rotate,
+                state.destroyed,
+            )
+        };
+
+        let cookie = if destroyed {
+            if let Some(id) = &old_id {
+                self.store.remove(id).await?;
+            }
+
+            old_id
+                .as_ref()
+                .map(|_| SetCookie::removal(&self.cookie_name))
+        } else if old_id.is_none() && data.is_empty() {
+            None
+        } else {
+            let id = match &old_id {
+                Some(id) if !rotate => id.clone(),
+                _ => generate_id()?,
+            };
+
+            if rotate {
+                if let Some(old_id) = &old_id {
+                    self.store.remove(old_id).await?;
+                }
+            }
+
+            self.store
+                .save(&id, &data, SystemTime::now() + self.ttl)
+                .await?;
+
+            let mut state = session.0.lock().unwrap();
+            state.id = Some(id.clone());
+            state.rotate = false;
+
+            Some(
+                SetCookie::new(&self.cookie_name, &id)
+                    .with_max_age(self.ttl)
+                    .with_secure(self.secure)
+                    .with_same_site(self.same_site),
+            )
+        };
+
+        if let (Some(cookie), Some(resp)) = (cookie, resp) {
+            cookie
+                .with_path("/")
+                .with_http_only(true)
+                .append_to(resp.headers_mut())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Wrap the handler (or chain) with sessions kept in the store, with the default [Sessions]
+/// configuration.
+pub fn sessions<S: Clone + Send + 'static, T: TransientState + 'static>(
+    store: impl SessionStore + 'static,
+    inner: Handler<S, T>,
+) -> Handler<S, T> {
+    Sessions::new(store).wrap(inner)
+}
+
+mod tests {
+    #[tokio::test]
+    async fn test_stores() {
+        use super::{generate_id, FileStore, MemoryStore, SessionData, SessionStore};
+        use std::time::{Duration, SystemTime};
+
+        let dir = std::env::temp_dir().join(format!("davisjr-sessions-{}", std::process::id()));
+        let file_store = FileStore::new(&dir);
+        let memory_store = MemoryStore::new();
+
+        let mut data = SessionData::new();
+        data.insert("user".to_string(), serde_json::json!("alice"));
+
+        let stores: [&dyn SessionStore; 2] = [&memory_store, &file_store];
+        for store in stores {
+            let id = generate_id().unwrap();
+            let expired = generate_id().unwrap();
+            let later = SystemTime::now() + Duration::from_secs(60);
+
+            assert_eq!(store.load(&id).await.unwrap(), None);
+            store.save(&id, &data, later).await.unwrap();
+            assert_eq!(store.load(&id).await.unwrap(), Some(data.clone()));
+
+            store
+                .save(&expired, &data, SystemTime::now() - Duration::from_secs(1))
+                .await
+                .unwrap();
+            assert_eq!(store.load(&expired).await.unwrap(), None);
+
+            store.remove(&id).await.unwrap();
+            assert_eq!(store.load(&id).await.unwrap(), None);
+            store.remove(&id).await.unwrap();
+        }
+
+        assert!(memory_store.is_empty());
+
+        // IDs from clients never leave the directory.
+        assert_eq!(file_store.load("../../etc/passwd").await.unwrap(), None);
+        assert!(file_store
+            .save("../escape", &data, SystemTime::now())
+            .await
+            .is_err());
+
+        let id = generate_id().unwrap();
+        file_store
+            .save(&id, &data, SystemTime::now() - Duration::from_secs(1))
+            .await
+            .unwrap();
+        file_store.purge().await.unwrap();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sessions() {
+        use super::{MemoryStore, Session, Sessions};
+        use crate::{
+            app::{App, TestApp},
+            compose_handler,
+            errors::Error,
+            HTTPResult, NoState, Params,
+        };
+        use http::{Method, Request, Response, StatusCode};
+        use hyper::Body;
+        use std::time::Duration;
+
+        async fn session(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            params: Params,
+            _app: App<(), NoState>,
+            state: NoState,
+        ) -> HTTPResult<NoState> {
+            let session = Session::from_request(&req).unwrap();
+            match params["action"].as_str() {
+                "count" => {
+                    let count = session.get::<u64>("count").unwrap_or_default() + 1;
+                    session.insert("count", count)?;
+                }
+                "login" => {
+                    session.insert("user", "alice")?;
+                    session.rotate();
+                }
+                "logout" => session.destroy(),
+                "fail" => {
+                    session.insert("count", 100)?;
+                    return Err(Error::new("failed"));
+                }
+                _ => {}
+            }
+
+            let body = format!(
+                "{} {}",
+                session.get::<String>("user").unwrap_or_default(),
+                session.get::<u64>("count").unwrap_or_default()
+            );
+            Ok((
+                req,
+                Some(
+                    Response::builder()
+                        .status(StatusCode::OK)
+                        .body(Body::from(body))?,
+                ),
+                state,
+            ))
+        }
+
+        let store = MemoryStore::new();
+        let mut app: App<(), NoState> = App::new();
+        app.get(
+            "/:action",
+            Sessions::new(store.clone())
+                .with_ttl(Duration::from_secs(600))
+                .wrap(compose_handler!(session)),
+        )
+        .unwrap();
+        let test_app = TestApp::new(app).with_cookie_jar();
+        let sid = || test_app.cookie_jar().unwrap().get("session");
+
+        // empty sessions are not saved.
+        let resp = test_app.request(Method::GET, "/show").send().await;
+        assert_eq!(resp.text(), " 0");
+        assert!(resp.header("set-cookie").is_none());
+        assert!(store.is_empty());
+
+        let resp = test_app.request(Method::GET, "/count").send().await;
+        assert_eq!(resp.text(), " 1");
+        let cookie = resp.header("set-cookie").unwrap().to_string();
+        assert!(cookie.contains("Max-Age=600"), "{}", cookie);
+        assert!(cookie.contains("HttpOnly; SameSite=Lax"), "{}", cookie);
+        let first = sid().unwrap();
+
+        assert_eq!(
+            test_app.request(Method::GET, "/count").send().await.text(),
+            " 2"
+        );
+        assert_eq!(sid().unwrap(), first);
+
+        // failed requests are not saved.
+        test_app
+            .request(Method::GET, "/fail")
+            .send()
+            .await
+            .assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            test_app.request(Method::GET, "/show").send().await.text(),
+            " 2"
+        );
+
+        assert_eq!(
+            test_app.request(Method::GET, "/login").send().await.text(),
+            "alice 2"
+        );
+        let second = sid().unwrap();
+        assert_ne!(first, second);
+        assert_eq!(store.len(), 1);
+
+        // the old ID is no longer accepted.
+        let resp = TestApp::new({
+            let mut app: App<(), NoState> = App::new();
+            app.get(
+                "/:action",
+                Sessions::new(store.clone()).wrap(compose_handler!(session)),
+            )
+            .unwrap();
+            app
+        })
+        .request(Method::GET, "/show")
+        .header("cookie", format!("session={}", first).as_str())
+        .send()
+        .await;
+        assert_eq!(resp.text(), " 0");
+
+        assert_eq!(
+            test_app.request(Method::GET, "/logout").send().await.text(),
+            " 0"
+        );
+        assert!(sid().is_none());
+        assert!(store.is_empty());
+    }
+}
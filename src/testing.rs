@@ -0,0 +1,1939 @@
+use http::{
+    header::{HeaderName, HeaderValue, CONTENT_TYPE, HOST},
+    Extensions, HeaderMap, Method, Request, Response, StatusCode,
+};
+use hyper::{body::Bytes, client::conn::SendRequest, Body};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    future::Future,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+use crate::{
+    app::App,
+    errors::{Error, ServerError},
+    handler::Handler,
+    timeout::Cancellation,
+    Params, PinBox, TransientState,
+};
+
+/// TestRequest is a request under construction against a [crate::app::TestApp] or a
+/// [TestServer]. It is created with [crate::app::TestApp::request] or [TestClient::request] and
+/// dispatched with [TestRequest::send]:
+///
+/// ```ignore
+///     let resp = test_app
+///         .request(Method::POST, "/users")
+///         .header("X-AuthToken", "867-5309")
+///         .json(&serde_json::json!({ "name": "erik" }))
+///         .send()
+///         .await;
+///
+///     resp.assert_status(StatusCode::CREATED);
+///     let user: User = resp.json();
+/// ```
+pub struct TestRequest {
+    dispatcher: Dispatcher,
+    default_headers: HeaderMap,
+    method: Method,
+    path: String,
+    query: Vec<(String, String)>,
+    headers: HeaderMap,
+    extensions: Extensions,
+    body: Body,
+}
+
+// Performs a built request against whatever the TestRequest was created from.
+type Dispatcher =
+    Box<dyn FnOnce(Request<Body>) -> PinBox<dyn Future<Output = Response<Body>> + Send> + Send>;
+
+impl TestRequest {
+    pub(crate) fn new(
+        dispatcher: Dispatcher,
+        default_headers: HeaderMap,
+        method: Method,
+        path: &str,
+    ) -> Self {
+        Self {
+            dispatcher,
+            default_headers,
+            method,
+            path: path.to_string(),
+            query: Vec::new(),
+            headers: HeaderMap::new(),
+            extensions: Extensions::new(),
+            body: Body::default(),
+        }
+    }
+
+    /// Add a header to the request. Headers set here take precedence over headers of the same
+    /// name provided to [crate::app::TestApp::with_headers]. Panics if the name or value is
+    /// invalid.
+    pub fn header<K, V>(mut self, name: K, value: V) -> Self
+    where
+        HeaderName: TryFrom<K>,
+        <HeaderName as TryFrom<K>>::Error: std::fmt::Debug,
+        HeaderValue: TryFrom<V>,
+        <HeaderValue as TryFrom<V>>::Error: std::fmt::Debug,
+    {
+        self.headers.append(
+            HeaderName::try_from(name).expect("invalid header name"),
+            HeaderValue::try_from(value).expect("invalid header value"),
+        );
+        self
+    }
+
+    /// Append query parameters to the path. Names and values are percent-encoded.
+    pub fn query<K: ToString, V: ToString>(mut self, pairs: &[(K, V)]) -> Self {
+        self.query
+            .extend(pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())));
+        self
+    }
+
+    /// Insert a value into the request's extensions. Extensions are not transmitted by a
+    /// [TestClient], so this is only useful with a [crate::app::TestApp].
+    pub fn extension<E: Send + Sync + 'static>(mut self, ext: E) -> Self {
+        self.extensions.insert(ext);
+        self
+    }
+
+    /// Set the request body.
+    pub fn body(mut self, body: impl Into<Body>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Serialize the value as the JSON request body, setting `Content-Type: application/json`.
+    /// Panics if the value cannot be serialized.
+    pub fn json<J: Serialize + ?Sized>(mut self, value: &J) -> Self {
+        self.body = Body::from(serde_json::to_vec(value).expect("could not serialize JSON body"));
+        self.headers
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        self
+    }
+
+    /// Build the [http::Request] without dispatching it.
+    pub fn build(self) -> Request<Body> {
+        self.split().1
+    }
+
+    fn split(self) -> (Dispatcher, Request<Body>) {
+        let mut uri = self.path;
+
+        if !self.query.is_empty() {
+            let query = self
+                .query
+                .iter()
+                .map(|(k, v)| format!("{}={}", percent_encode(k), percent_encode(v)))
+                .collect::<Vec<String>>()
+                .join("&");
+
+            uri.push(if uri.contains('?') { '&' } else { '?' });
+            uri.push_str(&query);
+        }
+
+        let mut headers = self.default_headers;
+        for name in self.headers.keys() {
+            headers.remove(name);
+        }
+        for (name, value) in &self.headers {
+            headers.append(name, value.clone());
+        }
+
+        let mut req = Request::builder()
+            .method(self.method)
+            .uri(uri)
+            .body(self.body)
+            .expect("invalid request");
+        *req.headers_mut() = headers;
+        *req.extensions_mut() = self.extensions;
+        (self.dispatcher, req)
+    }
+
+    /// Dispatch the request and buffer the response.
+    pub async fn send(self) -> TestResponse {
+        let (dispatcher, req) = self.split();
+        TestResponse::from_response(dispatcher(req).await).await
+    }
+}
+
+/// TestResponse is a buffered response returned by [TestRequest::send], with helpers for
+/// inspecting it in tests. The `assert_` methods panic with a descriptive message on failure and
+/// return the response, so they may be chained.
+#[derive(Clone, Debug)]
+pub struct TestResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl TestResponse {
+    /// Buffer a [http::Response] into a TestResponse.
+    pub async fn from_response(resp: Response<Body>) -> Self {
+        let (parts, body) = resp.into_parts();
+
+        Self {
+            status: parts.status,
+            headers: parts.headers,
+            body: hyper::body::to_bytes(body)
+                .await
+                .expect("could not read response body"),
+        }
+    }
+
+    /// The response status.
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// The response headers.
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// The value of a response header, if it is present and valid UTF-8.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|v| v.to_str().ok())
+    }
+
+    /// The response body.
+    pub fn bytes(&self) -> Bytes {
+        self.body.clone()
+    }
+
+    /// The response body as a string. Panics if it is not valid UTF-8.
+    pub fn text(&self) -> String {
+        String::from_utf8(self.body.to_vec()).expect("response body is not valid UTF-8")
+    }
+
+    /// Deserialize the response body from JSON. Panics if it cannot be deserialized.
+    pub fn json<J: DeserializeOwned>(&self) -> J {
+        serde_json::from_slice(&self.body).unwrap_or_else(|e| {
+            panic!(
+                "could not deserialize response body as JSON: {}: {}",
+                e,
+                String::from_utf8_lossy(&self.body)
+            )
+        })
+    }
+
+    /// Assert the response has the status.
+    pub fn assert_status(&self, status: StatusCode) -> &Self {
+        assert_eq!(
+            self.status,
+            status,
+            "unexpected status; body: {}",
+            String::from_utf8_lossy(&self.body)
+        );
+        self
+    }
+
+    /// Assert the response has a header with the value.
+    pub fn assert_header(&self, name: &str, value: &str) -> &Self {
+        assert_eq!(
+            self.header(name),
+            Some(value),
+            "unexpected value for header {}",
+            name
+        );
+        self
+    }
+}
+
+/// HandlerTest runs a single [crate::handler::Handler] or chain directly, without routing or
+/// dispatch, so that handlers can be unit tested in isolation. The request defaults to an empty
+/// `GET /`, params are empty, the application is [crate::app::App::new] and the transient state
+/// is [crate::TransientState::initial]; each can be replaced:
+///
+/// ```ignore
+///     let outcome = HandlerTest::new(compose_handler!(authenticate, greet))
+///         .request(
+///             Request::get("/hello/erik")
+///                 .header("X-AuthToken", "867-5309")
+///                 .body(Body::default())?,
+///         )
+///         .param("name", "erik")
+///         .run()
+///         .await?;
+///
+///     outcome.assert_status(StatusCode::OK);
+///     assert_eq!(outcome.request().headers()["x-user"], "erik");
+///     assert!(outcome.state().authenticated);
+/// ```
+pub struct HandlerTest<S: Clone + Send + 'static, T: TransientState + 'static> {
+    handler: Handler<S, T>,
+    app: Option<App<S, T>>,
+    request: Request<Body>,
+    response: Option<Response<Body>>,
+    params: Params,
+    state: Option<T>,
+}
+
+impl<S: Clone + Send + 'static, T: TransientState + 'static> HandlerTest<S, T> {
+    /// Prepare to run the handler.
+    pub fn new(handler: Handler<S, T>) -> Self {
+        Self {
+            handler,
+            app: None,
+            request: Request::default(),
+            response: None,
+            params: Params::new(),
+            state: None,
+        }
+    }
+
+    /// Provide the application passed to the handler, e.g. one constructed with
+    /// [crate::app::App::with_state].
+    pub fn app(mut self, app: App<S, T>) -> Self {
+        self.app = Some(app);
+        self
+    }
+
+    /// Provide the request passed to the first handler in the chain.
+    pub fn request(mut self, req: Request<Body>) -> Self {
+        self.request = req;
+        self
+    }
+
+    /// Provide a response for the first handler in the chain, as if an earlier handler had
+    /// produced it.
+    pub fn response(mut self, resp: Response<Body>) -> Self {
+        self.response = Some(resp);
+        self
+    }
+
+    /// Set a single route parameter.
+    pub fn param(mut self, name: &str, value: &str) -> Self {
+        self.params.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    /// Replace all route parameters.
+    pub fn params(mut self, params: Params) -> Self {
+        self.params = params;
+        self
+    }
+
+    /// Provide the transient state passed to the first handler in the chain.
+    pub fn state(mut self, state: T) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    /// Run the handler chain. The response, if any, is buffered; errors returned by any handler
+    /// are returned as-is.
+    pub async fn run(self) -> Result<HandlerOutcome<T>, Error> {
+        let (request, response, state) = self
+            .handler
+            .perform(
+                self.request,
+                self.response,
+                self.params,
+                self.app.unwrap_or_default(),
+                self.state.unwrap_or_else(T::initial),
+            )
+            .await?;
+
+        let response = match response {
+            Some(resp) => Some(TestResponse::from_response(resp).await),
+            None => None,
+        };
+
+        Ok(HandlerOutcome {
+            request,
+            response,
+            state,
+        })
+    }
+}
+
+/// HandlerOutcome is the result of [HandlerTest::run]: the request as left by the chain, the
+/// buffered response and the final transient state.
+pub struct HandlerOutcome<T> {
+    request: Request<Body>,
+    response: Option<TestResponse>,
+    state: T,
+}
+
+impl<T> HandlerOutcome<T> {
+    /// The request, including any changes the handlers made to it.
+    pub fn request(&self) -> &Request<Body> {
+        &self.request
+    }
+
+    /// The response, if the chain produced one.
+    pub fn response(&self) -> Option<&TestResponse> {
+        self.response.as_ref()
+    }
+
+    /// The transient state after the last handler.
+    pub fn state(&self) -> &T {
+        &self.state
+    }
+
+    /// Consume the outcome, returning the request, response and state.
+    pub fn into_parts(self) -> (Request<Body>, Option<TestResponse>, T) {
+        (self.request, self.response, self.state)
+    }
+
+    /// Assert the chain produced a response with the status.
+    pub fn assert_status(&self, status: StatusCode) -> &Self {
+        match &self.response {
+            Some(resp) => {
+                resp.assert_status(status);
+            }
+            None => panic!("handler produced no response; expected {}", status),
+        }
+        self
+    }
+
+    /// Assert the chain did not produce a response.
+    pub fn assert_no_response(&self) -> &Self {
+        if let Some(resp) = &self.response {
+            panic!("handler produced a response with status {}", resp.status);
+        }
+        self
+    }
+}
+
+/// TestServer runs an [crate::app::App] on a real socket, so that tests exercise the same serve
+/// loops, connection handling, TLS and unix socket code as production. The server listens on an
+/// ephemeral localhost port (or a temporary unix socket), and is shut down when dropped.
+///
+/// ```ignore
+///     let server = TestServer::start(app).await?;
+///     server
+///         .client()
+///         .get("/hello/erik")
+///         .await
+///         .assert_status(StatusCode::OK);
+/// ```
+pub struct TestServer {
+    addr: Option<SocketAddr>,
+    socket_path: Option<PathBuf>,
+    cert_der: Option<Vec<u8>>,
+    client: TestClient,
+    handle: JoinHandle<Result<(), ServerError>>,
+    shutdown: Cancellation,
+}
+
+impl TestServer {
+    /// Start serving HTTP on an ephemeral port on 127.0.0.1.
+    pub async fn start<S, T>(app: App<S, T>) -> Result<Self, ServerError>
+    where
+        S: Clone + Send + 'static,
+        T: TransientState + 'static + Clone + Send,
+    {
+        Self::spawn(app, |app, listener| async move {
+            app.serve_listener(listener).await
+        })
+        .await
+    }
+
+    /// Start serving HTTPS on an ephemeral port on 127.0.0.1, with a freshly generated
+    /// self-signed certificate for `localhost`. The client returned by [TestServer::client]
+    /// trusts the certificate.
+    #[cfg(feature = "tls")]
+    pub async fn start_tls<S, T>(app: App<S, T>) -> Result<Self, ServerError>
+    where
+        S: Clone + Send + 'static,
+        T: TransientState + 'static + Clone + Send,
+    {
+        use tokio_rustls::rustls::{Certificate, ClientConfig, RootCertStore};
+
+        let (config, cert_der) = crate::tls::self_signed(&["localhost"])?;
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(&Certificate(cert_der.clone()))
+            .map_err(|e| ServerError(e.to_string()))?;
+        let client_config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let mut server = Self::spawn(app, move |app, listener| async move {
+            app.serve_tls_listener(listener, config).await
+        })
+        .await?;
+        server.cert_der = Some(cert_der);
+        server.client = TestClient::new(ClientTarget::Tls(
+            server.addr.unwrap(),
+            Arc::new(client_config),
+        ));
+
+        Ok(server)
+    }
+
+    /// Start serving HTTP on a temporary unix socket.
+    #[cfg(feature = "unix")]
+    pub async fn start_unix<S, T>(mut app: App<S, T>) -> Result<Self, ServerError>
+    where
+        S: Clone + Send + 'static,
+        T: TransientState + 'static + Clone + Send,
+    {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        static SOCKET_ID: AtomicU64 = AtomicU64::new(1);
+
+        let path = std::env::temp_dir().join(format!(
+            "davisjr-test-{}-{}.sock",
+            std::process::id(),
+            SOCKET_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        let shutdown = Cancellation::new();
+        app.with_shutdown(shutdown.clone());
+
+        let serve_path = path.clone();
+        let mut handle = tokio::spawn(async move { app.serve_unix(serve_path).await });
+        wait_for_socket(&path, &mut handle).await?;
+
+        Ok(Self {
+            addr: None,
+            socket_path: Some(path.clone()),
+            cert_der: None,
+            client: TestClient::new(ClientTarget::Unix(path)),
+            handle,
+            shutdown,
+        })
+    }
+
+    // Bind an ephemeral port on 127.0.0.1, and run the serve function with the app on the
+    // listener, so that the crate's own tests can start any of the TCP serve loops. The port is
+    // bound before the server task starts, so the server can be connected to at once.
+    pub(crate) async fn spawn<S, T, F, Fut>(
+        mut app: App<S, T>,
+        serve: F,
+    ) -> Result<Self, ServerError>
+    where
+        S: Clone + Send + 'static,
+        T: TransientState + 'static + Clone + Send,
+        F: FnOnce(App<S, T>, TcpListener) -> Fut,
+        Fut: Future<Output = Result<(), ServerError>> + Send + 'static,
+    {
+        let shutdown = Cancellation::new();
+        app.with_shutdown(shutdown.clone());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let handle = tokio::spawn(serve(app, listener));
+
+        Ok(Self {
+            addr: Some(addr),
+            socket_path: None,
+            cert_der: None,
+            client: TestClient::new(ClientTarget::Tcp(addr)),
+            handle,
+            shutdown,
+        })
+    }
+
+    /// The address the server is listening on. [std::option::Option::None] for unix sockets.
+    pub fn addr(&self) -> Option<SocketAddr> {
+        self.addr
+    }
+
+    /// The path of the unix socket the server is listening on.
+    pub fn socket_path(&self) -> Option<&Path> {
+        self.socket_path.as_deref()
+    }
+
+    /// The DER-encoded certificate of a server started with [TestServer::start_tls].
+    pub fn cert_der(&self) -> Option<&[u8]> {
+        self.cert_der.as_deref()
+    }
+
+    /// A URL for the path on this server, for use with other HTTP clients. Unix socket servers
+    /// use `localhost` as the host.
+    pub fn url(&self, path: &str) -> String {
+        let scheme = if self.cert_der.is_some() {
+            "https"
+        } else {
+            "http"
+        };
+
+        match self.addr {
+            Some(addr) if self.cert_der.is_some() => {
+                format!("{}://localhost:{}{}", scheme, addr.port(), path)
+            }
+            Some(addr) => format!("{}://{}{}", scheme, addr, path),
+            None => format!("{}://localhost{}", scheme, path),
+        }
+    }
+
+    /// A client connected to this server. Clones of the client share a keep-alive connection.
+    pub fn client(&self) -> TestClient {
+        self.client.clone()
+    }
+
+    /// Start building a request against the path with this server's client.
+    pub fn request(&self, method: Method, path: &str) -> TestRequest {
+        self.client.request(method, path)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        // end the connections being served as well as the serve loop.
+        self.shutdown.cancel();
+        self.handle.abort();
+
+        if let Some(path) = &self.socket_path {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+// The unix socket is bound by the server task; wait for it to accept connections, or for the
+// server to fail. A stale socket file may exist before the server replaces it.
+#[cfg(feature = "unix")]
+pub(crate) async fn wait_for_socket(
+    path: &Path,
+    handle: &mut JoinHandle<Result<(), ServerError>>,
+) -> Result<(), ServerError> {
+    while tokio::net::UnixStream::connect(path).await.is_err() {
+        if handle.is_finished() {
+            return match handle.await {
+                Ok(Err(e)) => Err(e),
+                _ => Err(ServerError("test server exited".to_string())),
+            };
+        }
+
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+
+    Ok(())
+}
+
+// Write a raw request to the stream and read the response until the server closes the
+// connection, for the crate's tests of its serve loops. A connection the server drops reads as
+// an empty response.
+#[cfg(test)]
+pub(crate) async fn exchange<S>(stream: &mut S, request: &[u8]) -> String
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut buf = String::new();
+    if stream.write_all(request).await.is_ok() {
+        let _ = stream.read_to_string(&mut buf).await;
+    }
+
+    buf
+}
+
+#[derive(Clone)]
+enum ClientTarget {
+    Tcp(SocketAddr),
+    #[cfg(feature = "tls")]
+    Tls(SocketAddr, Arc<tokio_rustls::rustls::ClientConfig>),
+    #[cfg(feature = "unix")]
+    Unix(PathBuf),
+}
+
+/// TestClient is a HTTP client for a [TestServer]. It keeps a single connection open across
+/// requests, reconnecting when the server closes it.
+#[derive(Clone)]
+pub struct TestClient {
+    target: ClientTarget,
+    conn: Arc<tokio::sync::Mutex<Option<SendRequest<Body>>>>,
+}
+
+impl TestClient {
+    fn new(target: ClientTarget) -> Self {
+        Self {
+            target,
+            conn: Arc::new(tokio::sync::Mutex::new(None)),
+        }
+    }
+
+    /// Send a request to the server. The request's URI should contain only a path and query; a
+    /// `Host` header of `localhost` is added if none is provided.
+    pub async fn send(&self, mut req: Request<Body>) -> Result<Response<Body>, ServerError> {
+        if !req.headers().contains_key(HOST) {
+            req.headers_mut()
+                .insert(HOST, HeaderValue::from_static("localhost"));
+        }
+
+        let mut conn = self.conn.lock().await;
+
+        let reusable = match conn.as_mut() {
+            Some(sender) => std::future::poll_fn(|cx| sender.poll_ready(cx))
+                .await
+                .is_ok(),
+            None => false,
+        };
+
+        if !reusable {
+            conn.replace(self.connect().await?);
+        }
+
+        conn.as_mut()
+            .unwrap()
+            .send_request(req)
+            .await
+            .map_err(|e| ServerError(format!("request to test server failed: {}", e)))
+    }
+
+    /// Start building a request against the path, which may be dispatched with
+    /// [TestRequest::send].
+    pub fn request(&self, method: Method, path: &str) -> TestRequest {
+        let client = self.clone();
+        TestRequest::new(
+            Box::new(move |req| {
+                Box::pin(async move { client.send(req).await.expect("test server request") })
+            }),
+            HeaderMap::new(),
+            method,
+            path,
+        )
+    }
+
+    /// Perform a GET request against the path.
+    pub async fn get(&self, path: &str) -> TestResponse {
+        self.request(Method::GET, path).send().await
+    }
+
+    async fn connect(&self) -> Result<SendRequest<Body>, ServerError> {
+        match &self.target {
+            ClientTarget::Tcp(addr) => handshake(TcpStream::connect(addr).await?).await,
+            #[cfg(feature = "tls")]
+            ClientTarget::Tls(addr, config) => {
+                use std::convert::TryFrom;
+                use tokio_rustls::{rustls::ServerName, TlsConnector};
+
+                let stream = TcpStream::connect(addr).await?;
+                let stream = TlsConnector::from(config.clone())
+                    .connect(ServerName::try_from("localhost").unwrap(), stream)
+                    .await?;
+
+                handshake(stream).await
+            }
+            #[cfg(feature = "unix")]
+            ClientTarget::Unix(path) => {
+                handshake(tokio::net::UnixStream::connect(path).await?).await
+            }
+        }
+    }
+}
+
+async fn handshake<I>(io: I) -> Result<SendRequest<Body>, ServerError>
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (sender, conn) = hyper::client::conn::handshake(io)
+        .await
+        .map_err(|e| ServerError(format!("could not connect to test server: {}", e)))?;
+
+    tokio::spawn(async move {
+        let _ = conn.await;
+    });
+
+    Ok(sender)
+}
+
+/// StoredCookie is a cookie held by a [CookieJar].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StoredCookie {
+    pub name: String,
+    pub value: String,
+    pub path: String,
+    pub expires: Option<SystemTime>,
+}
+
+impl StoredCookie {
+    fn expired(&self, now: SystemTime) -> bool {
+        self.expires.is_some_and(|e| e <= now)
+    }
+
+    // RFC 6265 section 5.1.4 path matching.
+    fn matches(&self, path: &str) -> bool {
+        path == self.path
+            || (path.starts_with(&self.path)
+                && (self.path.ends_with('/') || path[self.path.len()..].starts_with('/')))
+    }
+}
+
+/// CookieJar holds cookies for a [crate::app::TestApp] created with
+/// [crate::app::TestApp::with_cookie_jar]. Cookies are keyed by name and path; the `Domain` and
+/// `Secure` attributes are not considered, as a TestApp only ever talks to one application.
+#[derive(Clone, Debug, Default)]
+pub struct CookieJar(Vec<StoredCookie>);
+
+impl CookieJar {
+    /// Store a cookie from a `Set-Cookie` header value, received in response to a request for
+    /// `request_path`. Cookies which have already expired remove any stored cookie of the same
+    /// name and path.
+    pub fn store(&mut self, request_path: &str, set_cookie: &str) {
+        let mut parts = set_cookie.split(';');
+        let (name, value) = match parts.next().and_then(|nv| nv.split_once('=')) {
+            Some((name, value)) if !name.trim().is_empty() => (name.trim(), value.trim()),
+            _ => return,
+        };
+
+        let mut path = None;
+        let mut expires = None;
+        let mut max_age = None;
+
+        for attr in parts {
+            let (key, value) = attr.split_once('=').unwrap_or((attr, ""));
+            let value = value.trim();
+
+            match key.trim().to_lowercase().as_str() {
+                "path" if value.starts_with('/') => path = Some(value.to_string()),
+                "expires" => expires = httpdate::parse_http_date(value).ok(),
+                "max-age" => max_age = value.parse::<i64>().ok(),
+                _ => {}
+            }
+        }
+
+        let now = SystemTime::now();
+        // Max-Age takes precedence over Expires. Ages too large to represent never expire.
+        let expires = match max_age {
+            Some(age) if age <= 0 => Some(SystemTime::UNIX_EPOCH),
+            Some(age) => now.checked_add(Duration::from_secs(age as u64)),
+            None => expires,
+        };
+
+        let path = path.unwrap_or_else(|| default_path(request_path));
+        self.0.retain(|c| !(c.name == name && c.path == path));
+
+        let cookie = StoredCookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            path,
+            expires,
+        };
+
+        if !cookie.expired(now) {
+            self.0.push(cookie);
+        }
+    }
+
+    /// Add a cookie to the jar, as if it was set by the application for the path.
+    pub fn insert(&mut self, name: &str, value: &str, path: &str) {
+        self.store(path, &format!("{}={}; Path={}", name, value, path))
+    }
+
+    /// The value of an unexpired cookie with the name, for any path.
+    pub fn get(&self, name: &str) -> Option<String> {
+        let now = SystemTime::now();
+        self.0
+            .iter()
+            .find(|c| c.name == name && !c.expired(now))
+            .map(|c| c.value.clone())
+    }
+
+    /// All unexpired cookies in the jar.
+    pub fn cookies(&self) -> Vec<StoredCookie> {
+        let now = SystemTime::now();
+        self.0.iter().filter(|c| !c.expired(now)).cloned().collect()
+    }
+
+    /// Remove all cookies from the jar.
+    pub fn clear(&mut self) {
+        self.0.clear()
+    }
+
+    /// The `Cookie` header value to send with a request for the path, if any cookies match.
+    /// Cookies with longer paths are listed first.
+    pub fn header(&self, path: &str) -> Option<String> {
+        let now = SystemTime::now();
+        let mut cookies: Vec<&StoredCookie> = self
+            .0
+            .iter()
+            .filter(|c| !c.expired(now) && c.matches(path))
+            .collect();
+
+        if cookies.is_empty() {
+            return None;
+        }
+
+        cookies.sort_by_key(|c| std::cmp::Reverse(c.path.len()));
+
+        Some(
+            cookies
+                .iter()
+                .map(|c| format!("{}={}", c.name, c.value))
+                .collect::<Vec<String>>()
+                .join("; "),
+        )
+    }
+}
+
+// RFC 6265 section 5.1.4 default-path: the request path up to, but not including, its last slash.
+fn default_path(request_path: &str) -> String {
+    match request_path.rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(i) => request_path[..i].to_string(),
+    }
+}
+
+/// Fixtures is a set of recorded request/response pairs, used for golden-file regression tests.
+/// They are stored as JSON in a HAR-like format:
+///
+/// ```json
+/// {
+///   "entries": [
+///     {
+///       "name": "create a user",
+///       "request": {
+///         "method": "POST",
+///         "path": "/users",
+///         "headers": [{ "name": "x-authtoken", "value": "867-5309" }],
+///         "json": { "name": "erik" }
+///       },
+///       "response": {
+///         "status": 201,
+///         "headers": [{ "name": "content-type", "value": "application/json" }],
+///         "json": { "id": 1, "name": "erik" }
+///       }
+///     }
+///   ]
+/// }
+/// ```
+///
+/// Fixtures are replayed in order with [crate::app::TestApp::replay], and can be produced from a
+/// working application with [crate::app::TestApp::record].
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Fixtures {
+    pub entries: Vec<FixtureEntry>,
+}
+
+impl Fixtures {
+    /// Parse fixtures from a JSON string.
+    pub fn parse(s: &str) -> Result<Self, ServerError> {
+        serde_json::from_str(s).map_err(|e| ServerError(format!("invalid fixtures: {}", e)))
+    }
+
+    /// Load fixtures from a JSON file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ServerError> {
+        let path = path.as_ref();
+        let s = std::fs::read_to_string(path)
+            .map_err(|e| ServerError(format!("could not open {}: {}", path.display(), e)))?;
+        Self::parse(&s).map_err(|e| ServerError(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Save the fixtures to a JSON file, replacing it if it exists.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), ServerError> {
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| ServerError(format!("could not serialize fixtures: {}", e)))?;
+        Ok(std::fs::write(path, json + "\n")?)
+    }
+}
+
+/// FixtureEntry is a single recorded request and the response expected for it.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct FixtureEntry {
+    /// A description of the entry, used when reporting mismatches.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub request: FixtureRequest,
+    pub response: FixtureResponse,
+}
+
+impl FixtureEntry {
+    // Compare the response against the expected one, returning each difference.
+    pub(crate) fn diff(&self, index: usize, resp: &TestResponse) -> Vec<Mismatch> {
+        let expected = &self.response;
+        let mut diffs = Vec::new();
+        let mut mismatch = |field: String, want: String, got: String| {
+            diffs.push(Mismatch {
+                entry: index,
+                name: self.name.clone(),
+                field,
+                expected: want,
+                actual: got,
+            })
+        };
+
+        if resp.status.as_u16() != expected.status {
+            mismatch(
+                "status".to_string(),
+                expected.status.to_string(),
+                resp.status.as_u16().to_string(),
+            );
+        }
+
+        let mut names: Vec<String> = expected
+            .headers
+            .iter()
+            .map(|h| h.name.to_lowercase())
+            .collect();
+        names.sort();
+        names.dedup();
+
+        for name in names {
+            let want: Vec<&str> = expected
+                .headers
+                .iter()
+                .filter(|h| h.name.eq_ignore_ascii_case(&name))
+                .map(|h| h.value.as_str())
+                .collect();
+            let got: Vec<String> = resp
+                .headers
+                .get_all(name.as_str())
+                .iter()
+                .map(|v| String::from_utf8_lossy(v.as_bytes()).to_string())
+                .collect();
+
+            if want != got {
+                mismatch(
+                    format!("header {}", name),
+                    format!("{:?}", want),
+                    format!("{:?}", got),
+                );
+            }
+        }
+
+        let text = String::from_utf8_lossy(&resp.body).to_string();
+        if let Some(json) = &expected.json {
+            match serde_json::from_slice::<serde_json::Value>(&resp.body) {
+                Ok(got) if &got == json => {}
+                Ok(got) => mismatch("json body".to_string(), json.to_string(), got.to_string()),
+                Err(_) => mismatch("json body".to_string(), json.to_string(), text),
+            }
+        } else if let Some(body) = &expected.body {
+            if body != &text {
+                mismatch("body".to_string(), body.clone(), text);
+            }
+        }
+
+        diffs
+    }
+}
+
+/// FixtureHeader is a header name and value.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct FixtureHeader {
+    pub name: String,
+    pub value: String,
+}
+
+/// FixtureRequest is a recorded request. If both `body` and `json` are provided, `json` is sent.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct FixtureRequest {
+    pub method: String,
+    /// The path of the request, including any query string.
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub headers: Vec<FixtureHeader>,
+    /// The request body as text.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    /// The request body as JSON. `Content-Type: application/json` is added when it is sent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub json: Option<serde_json::Value>,
+}
+
+impl FixtureRequest {
+    /// Construct a request with no headers or body.
+    pub fn new(method: Method, path: &str) -> Self {
+        Self {
+            method: method.to_string(),
+            path: path.to_string(),
+            headers: Vec::new(),
+            body: None,
+            json: None,
+        }
+    }
+
+    // Turn the fixture into a TestRequest. Panics if the method or headers are invalid, as the
+    // fixture file is then unusable.
+    pub(crate) fn to_test_request(
+        &self,
+        request: impl FnOnce(Method, &str) -> TestRequest,
+    ) -> TestRequest {
+        let method = Method::from_bytes(self.method.as_bytes())
+            .unwrap_or_else(|_| panic!("invalid method in fixture: {}", self.method));
+        let mut req = request(method, &self.path);
+
+        for header in &self.headers {
+            req = req.header(header.name.as_str(), header.value.as_str());
+        }
+
+        match (&self.json, &self.body) {
+            (Some(json), _) => req.json(json),
+            (None, Some(body)) => req.body(body.clone()),
+            (None, None) => req,
+        }
+    }
+}
+
+/// FixtureResponse is the expected response to a [FixtureRequest]. Only the listed headers are
+/// compared, and the body is only compared if `body` or `json` is provided. `json` is compared
+/// structurally, so formatting and key order do not matter.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct FixtureResponse {
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub headers: Vec<FixtureHeader>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub json: Option<serde_json::Value>,
+}
+
+impl FixtureResponse {
+    // Record a response, keeping only the named headers. JSON bodies are stored as JSON so that
+    // they are compared structurally on replay.
+    pub(crate) fn record(resp: &TestResponse, headers: &[&str]) -> Self {
+        let json = match resp.header(CONTENT_TYPE.as_str()) {
+            Some(ct) if ct.starts_with("application/json") => {
+                serde_json::from_slice(&resp.body).ok()
+            }
+            _ => None,
+        };
+
+        Self {
+            status: resp.status.as_u16(),
+            headers: headers
+                .iter()
+                .flat_map(|name| {
+                    resp.headers
+                        .get_all(*name)
+                        .iter()
+                        .map(|value| FixtureHeader {
+                            name: name.to_lowercase(),
+                            value: String::from_utf8_lossy(value.as_bytes()).to_string(),
+                        })
+                })
+                .collect(),
+            body: match json {
+                Some(_) => None,
+                None if resp.body.is_empty() => None,
+                None => Some(String::from_utf8_lossy(&resp.body).to_string()),
+            },
+            json,
+        }
+    }
+}
+
+/// Mismatch is a difference between a replayed response and its fixture.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mismatch {
+    /// The index of the entry in the fixtures.
+    pub entry: usize,
+    /// The name of the entry, if it has one.
+    pub name: Option<String>,
+    /// What differed: `status`, `header <name>`, `body` or `json body`.
+    pub field: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl std::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "entry {}", self.entry)?;
+        if let Some(name) = &self.name {
+            write!(f, " ({})", name)?;
+        }
+        write!(
+            f,
+            ": {} differs\n  expected: {}\n    actual: {}",
+            self.field, self.expected, self.actual
+        )
+    }
+}
+
+/// ReplayReport is the result of [crate::app::TestApp::replay].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReplayReport {
+    /// The number of entries replayed.
+    pub replayed: usize,
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl ReplayReport {
+    /// Whether every response matched its fixture.
+    pub fn is_ok(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+
+    /// Assert every response matched its fixture, panicking with all mismatches otherwise.
+    pub fn assert_ok(&self) -> &Self {
+        if !self.is_ok() {
+            panic!(
+                "{} of {} fixtures did not match:\n{}",
+                self.mismatches
+                    .iter()
+                    .map(|m| m.entry)
+                    .collect::<std::collections::BTreeSet<usize>>()
+                    .len(),
+                self.replayed,
+                self.mismatches
+                    .iter()
+                    .map(|m| m.to_string())
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            );
+        }
+        self
+    }
+}
+
+// Percent-encode everything but RFC 3986 unreserved characters.
+pub(crate) fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(b as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+
+    out
+}
+
+mod tests {
+    #[tokio::test]
+    async fn test_request_builder() {
+        use crate::{
+            app::{App, TestApp},
+            compose_handler, HTTPResult, NoState, Params,
+        };
+        use http::{HeaderMap, Method, Request, Response, StatusCode};
+        use hyper::Body;
+
+        #[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+        struct Echo {
+            query: Option<String>,
+            content_type: Option<String>,
+            token: Vec<String>,
+            body: String,
+        }
+
+        async fn echo(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            _state: NoState,
+        ) -> HTTPResult<NoState> {
+            let (parts, body) = req.into_parts();
+            let echo = Echo {
+                query: parts.uri.query().map(|q| q.to_string()),
+                content_type: parts
+                    .headers
+                    .get("content-type")
+                    .map(|v| v.to_str().unwrap().to_string()),
+                token: parts
+                    .headers
+                    .get_all("x-token")
+                    .iter()
+                    .map(|v| v.to_str().unwrap().to_string())
+                    .collect(),
+                body: String::from_utf8(hyper::body::to_bytes(body).await?.to_vec())?,
+            };
+
+            Ok((
+                Request::from_parts(parts, Body::default()),
+                Some(
+                    Response::builder()
+                        .status(StatusCode::CREATED)
+                        .header("x-echo", "yes")
+                        .body(Body::from(serde_json::to_vec(&echo)?))?,
+                ),
+                NoState {},
+            ))
+        }
+
+        let mut app = App::new();
+        app.post("/echo", compose_handler!(echo)).unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-token", "default".parse().unwrap());
+        let test_app = TestApp::new(app).with_headers(headers);
+
+        let resp = test_app
+            .request(Method::POST, "/echo")
+            .query(&[("a", "b c"), ("d", "é&")])
+            .json(&serde_json::json!({"one": 1}))
+            .send()
+            .await;
+
+        resp.assert_status(StatusCode::CREATED)
+            .assert_header("x-echo", "yes");
+        assert_eq!(
+            resp.json::<Echo>(),
+            Echo {
+                query: Some("a=b%20c&d=%C3%A9%26".to_string()),
+                content_type: Some("application/json".to_string()),
+                token: vec!["default".to_string()],
+                body: r#"{"one":1}"#.to_string(),
+            }
+        );
+
+        let resp = test_app
+            .request(Method::POST, "/echo?x=y")
+            .query(&[("z", 1)])
+            .header("x-token", "one")
+            .header("x-token", "two")
+            .body("plain")
+            .send()
+            .await;
+
+        let echo: Echo = resp.json();
+        assert_eq!(echo.query, Some("x=y&z=1".to_string()));
+        assert_eq!(echo.token, vec!["one".to_string(), "two".to_string()]);
+        assert_eq!(echo.content_type, None);
+        assert_eq!(echo.body, "plain");
+
+        let resp = test_app.request(Method::GET, "/echo").send().await;
+        resp.assert_status(StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(resp.text(), "\n");
+        assert_eq!(resp.bytes(), "\n".as_bytes());
+    }
+
+    #[test]
+    fn test_cookie_jar() {
+        use super::CookieJar;
+
+        let mut jar = CookieJar::default();
+        assert_eq!(jar.header("/"), None);
+
+        jar.store("/login", "session=abc; Path=/; HttpOnly; SameSite=Lax");
+        jar.store("/account/settings", "tab=profile");
+        jar.store("/", "admin=1; Path=/admin");
+        jar.store("/", "old=1; Expires=Thu, 01 Jan 1970 00:00:00 GMT");
+        jar.store("/", "future=1; Expires=Fri, 01 Jan 2100 00:00:00 GMT");
+        jar.store("/", "invalid");
+
+        assert_eq!(jar.header("/"), Some("session=abc; future=1".to_string()));
+        assert_eq!(
+            jar.header("/account/settings"),
+            Some("tab=profile; session=abc; future=1".to_string())
+        );
+        assert_eq!(
+            jar.header("/admin/users"),
+            Some("admin=1; session=abc; future=1".to_string())
+        );
+        assert_eq!(
+            jar.header("/administrator"),
+            Some("session=abc; future=1".to_string())
+        );
+
+        jar.store("/", "session=def; Path=/");
+        assert_eq!(jar.get("session"), Some("def".to_string()));
+
+        jar.store("/logout", "session=; Path=/; Max-Age=0");
+        assert_eq!(jar.get("session"), None);
+        assert_eq!(jar.cookies().len(), 3);
+
+        // Max-Age takes precedence over Expires
+        jar.store(
+            "/",
+            "both=1; Max-Age=60; Expires=Thu, 01 Jan 1970 00:00:00 GMT",
+        );
+        assert_eq!(jar.get("both"), Some("1".to_string()));
+
+        jar.store("/", "forever=1; Max-Age=9223372036854775807");
+        assert_eq!(jar.get("forever"), Some("1".to_string()));
+        assert!(jar
+            .cookies()
+            .iter()
+            .any(|c| c.name == "forever" && c.expires.is_none()));
+
+        jar.clear();
+        assert_eq!(jar.header("/"), None);
+
+        jar.insert("manual", "yes", "/");
+        assert_eq!(jar.header("/anything"), Some("manual=yes".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_cookie_jar_app() {
+        use crate::{
+            app::{App, TestApp},
+            compose_handler,
+            errors::Error,
+            HTTPResult, NoState, Params,
+        };
+        use http::{Method, Request, Response, StatusCode};
+        use hyper::Body;
+
+        async fn login(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            _state: NoState,
+        ) -> HTTPResult<NoState> {
+            Ok((
+                req,
+                Some(
+                    Response::builder()
+                        .status(StatusCode::OK)
+                        .header("set-cookie", "session=s3cr3t; Path=/; HttpOnly")
+                        .body(Body::default())?,
+                ),
+                NoState {},
+            ))
+        }
+
+        async fn logout(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            _state: NoState,
+        ) -> HTTPResult<NoState> {
+            Ok((
+                req,
+                Some(
+                    Response::builder()
+                        .status(StatusCode::OK)
+                        .header("set-cookie", "session=; Path=/; Max-Age=0")
+                        .body(Body::default())?,
+                ),
+                NoState {},
+            ))
+        }
+
+        async fn me(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            _state: NoState,
+        ) -> HTTPResult<NoState> {
+            match req.headers().get("cookie") {
+                Some(cookie) if cookie == "session=s3cr3t" => Ok((
+                    req,
+                    Some(Response::builder().status(200).body(Body::from("erik"))?),
+                    NoState {},
+                )),
+                _ => Err(Error::new_status(StatusCode::UNAUTHORIZED, "log in")),
+            }
+        }
+
+        async fn cookies(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            _state: NoState,
+        ) -> HTTPResult<NoState> {
+            let values: Vec<&str> = req
+                .headers()
+                .get_all("cookie")
+                .iter()
+                .map(|v| v.to_str().unwrap())
+                .collect();
+            let body = Body::from(values.join("\n"));
+
+            Ok((
+                req,
+                Some(Response::builder().status(200).body(body)?),
+                NoState {},
+            ))
+        }
+
+        let mut app = App::new();
+        app.post("/login", compose_handler!(login)).unwrap();
+        app.post("/logout", compose_handler!(logout)).unwrap();
+        app.get("/me", compose_handler!(me)).unwrap();
+        app.get("/cookies", compose_handler!(cookies)).unwrap();
+
+        // without a jar, cookies are not kept.
+        let test_app = TestApp::new(app.clone());
+        test_app.post("/login", Body::default()).await;
+        assert_eq!(test_app.get("/me").await.status(), StatusCode::UNAUTHORIZED);
+        assert!(test_app.cookie_jar().is_none());
+
+        let test_app = TestApp::new(app).with_cookie_jar();
+        assert_eq!(test_app.get("/me").await.status(), StatusCode::UNAUTHORIZED);
+
+        test_app
+            .request(Method::POST, "/login")
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        assert_eq!(
+            test_app.cookie_jar().unwrap().get("session"),
+            Some("s3cr3t".to_string())
+        );
+
+        let resp = test_app.clone().request(Method::GET, "/me").send().await;
+        resp.assert_status(StatusCode::OK);
+        assert_eq!(resp.text(), "erik");
+
+        // the jar's cookies join those set on the request, in a single header.
+        let resp = test_app
+            .request(Method::GET, "/cookies")
+            .header("cookie", "theme=dark")
+            .send()
+            .await;
+        assert_eq!(resp.text(), "theme=dark; session=s3cr3t");
+
+        test_app.post("/logout", Body::default()).await;
+        assert_eq!(test_app.get("/me").await.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_test_app_connection() {
+        use crate::{
+            app::{App, TestApp},
+            compose_handler,
+            connection::{ConnectionInfo, TlsInfo},
+            HTTPResult, NoState, Params,
+        };
+        use http::{HeaderValue, Method, Request, Response};
+        use hyper::Body;
+        use std::net::IpAddr;
+
+        #[derive(Clone)]
+        struct Principal(&'static str);
+
+        async fn whoami(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            _state: NoState,
+        ) -> HTTPResult<NoState> {
+            let info = ConnectionInfo::from_request(&req).unwrap();
+            let body = format!(
+                "{} {} {:?} {}",
+                req.extensions().get::<IpAddr>().unwrap(),
+                info.scheme(),
+                info.transport,
+                req.extensions()
+                    .get::<Principal>()
+                    .map_or("anonymous", |p| p.0),
+            );
+
+            Ok((
+                req,
+                Some(Response::builder().status(200).body(Body::from(body))?),
+                NoState {},
+            ))
+        }
+
+        let mut app = App::new();
+        app.get("/whoami", compose_handler!(whoami)).unwrap();
+
+        let test_app = TestApp::new(app.clone());
+        let resp = test_app.request(Method::GET, "/whoami").send().await;
+        assert_eq!(resp.text(), "127.0.0.1 http Tcp anonymous");
+
+        let test_app = TestApp::new(app.clone())
+            .with_peer_addr("10.1.2.3:5555".parse().unwrap())
+            .with_tls(TlsInfo {
+                server_name: Some("example.com".to_string()),
+                ..Default::default()
+            })
+            .with_extension(Principal("erik"));
+        let resp = test_app
+            .clone()
+            .request(Method::GET, "/whoami")
+            .send()
+            .await;
+        assert_eq!(resp.text(), "10.1.2.3 https Tls erik");
+
+        // request extensions take precedence over those of the test app.
+        let resp = test_app
+            .request(Method::GET, "/whoami")
+            .extension(Principal("admin"))
+            .send()
+            .await;
+        assert_eq!(resp.text(), "10.1.2.3 https Tls admin");
+
+        // trusted proxies apply to simulated connections too.
+        app.with_trusted_proxies(&["10.0.0.0/8"]).unwrap();
+        let resp = TestApp::new(app)
+            .with_peer_addr("10.1.2.3:5555".parse().unwrap())
+            .with_header(
+                "x-forwarded-for".parse().unwrap(),
+                HeaderValue::from_static("1.2.3.4"),
+            )
+            .request(Method::GET, "/whoami")
+            .send()
+            .await;
+        assert_eq!(resp.text(), "1.2.3.4 http Tcp anonymous");
+    }
+
+    #[tokio::test]
+    async fn test_fixtures() {
+        use super::{FixtureRequest, Fixtures};
+        use crate::{
+            app::{App, TestApp},
+            compose_handler, HTTPResult, NoState, Params,
+        };
+        use http::{Method, Request, Response};
+        use hyper::Body;
+
+        #[derive(serde::Deserialize)]
+        struct Name {
+            name: String,
+        }
+
+        async fn hello(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            params: Params,
+            _app: App<(), NoState>,
+            _state: NoState,
+        ) -> HTTPResult<NoState> {
+            let name = params.get("name").unwrap();
+
+            Ok((
+                req,
+                Some(
+                    Response::builder()
+                        .status(200)
+                        .header("content-type", "text/plain")
+                        .body(Body::from(format!("hello, {}", name)))?,
+                ),
+                NoState {},
+            ))
+        }
+
+        async fn hello_json(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            _state: NoState,
+        ) -> HTTPResult<NoState> {
+            let (parts, body) = req.into_parts();
+            let name: Name = serde_json::from_slice(&hyper::body::to_bytes(body).await?)?;
+            let body = serde_json::json!({
+                "length": name.name.len(),
+                "greeting": format!("hello, {}", name.name),
+            });
+
+            Ok((
+                Request::from_parts(parts, Body::default()),
+                Some(
+                    Response::builder()
+                        .status(200)
+                        .header("content-type", "application/json")
+                        .body(Body::from(body.to_string()))?,
+                ),
+                NoState {},
+            ))
+        }
+
+        let mut app = App::new();
+        app.get("/hello/:name", compose_handler!(hello)).unwrap();
+        app.post("/hello", compose_handler!(hello_json)).unwrap();
+        let test_app = TestApp::new(app);
+
+        let report = test_app
+            .replay_file("testdata/fixtures/greeting.json")
+            .await
+            .unwrap();
+        report.assert_ok();
+        assert_eq!(report.replayed, 3);
+
+        assert!(test_app
+            .replay_file("testdata/fixtures/missing.json")
+            .await
+            .is_err());
+
+        // a recording replays cleanly, including through a file.
+        let mut post = FixtureRequest::new(Method::POST, "/hello");
+        post.json = Some(serde_json::json!({ "name": "scarlett" }));
+        let fixtures = test_app
+            .record(
+                vec![FixtureRequest::new(Method::GET, "/hello/scarlett"), post],
+                &["content-type"],
+            )
+            .await;
+        assert_eq!(
+            fixtures.entries[0].response.body.as_deref(),
+            Some("hello, scarlett")
+        );
+        assert_eq!(
+            fixtures.entries[1].response.json,
+            Some(serde_json::json!({ "greeting": "hello, scarlett", "length": 8 }))
+        );
+
+        let path =
+            std::env::temp_dir().join(format!("davisjr-fixtures-{}.json", std::process::id()));
+        fixtures.save(&path).unwrap();
+        let loaded = Fixtures::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, fixtures);
+        test_app.replay(&loaded).await.assert_ok();
+
+        // mismatches are reported per field.
+        let mut fixtures = loaded;
+        fixtures.entries[0].name = Some("changed".to_string());
+        fixtures.entries[0].response.status = 201;
+        fixtures.entries[0].response.body = Some("goodbye".to_string());
+        fixtures.entries[1].response.headers[0].value = "text/html".to_string();
+
+        let report = test_app.replay(&fixtures).await;
+        assert!(!report.is_ok());
+        let fields: Vec<(usize, &str)> = report
+            .mismatches
+            .iter()
+            .map(|m| (m.entry, m.field.as_str()))
+            .collect();
+        assert_eq!(
+            fields,
+            vec![(0, "status"), (0, "body"), (1, "header content-type")]
+        );
+        assert_eq!(
+            report.mismatches[0].to_string(),
+            "entry 0 (changed): status differs\n  expected: 201\n    actual: 200"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handler_test() {
+        use super::HandlerTest;
+        use crate::{app::App, compose_handler, errors::Error, HTTPResult, Params, TransientState};
+        use http::{HeaderValue, Request, Response, StatusCode};
+        use hyper::Body;
+
+        #[derive(Clone, Debug, PartialEq)]
+        struct Auth {
+            user: Option<String>,
+        }
+
+        impl TransientState for Auth {
+            fn initial() -> Self {
+                Self { user: None }
+            }
+        }
+
+        async fn authenticate(
+            mut req: Request<Body>,
+            resp: Option<Response<Body>>,
+            _params: Params,
+            app: App<String, Auth>,
+            _state: Auth,
+        ) -> HTTPResult<Auth> {
+            let token = app.state().await.unwrap().lock().await.clone();
+            match req.headers().get("x-authtoken") {
+                Some(value) if value == token.as_str() => {
+                    req.headers_mut()
+                        .insert("x-user", HeaderValue::from_static("erik"));
+                    Ok((
+                        req,
+                        resp,
+                        Auth {
+                            user: Some("erik".to_string()),
+                        },
+                    ))
+                }
+                _ => Err(Error::new_status(StatusCode::UNAUTHORIZED, "who are you")),
+            }
+        }
+
+        async fn greet(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            params: Params,
+            _app: App<String, Auth>,
+            state: Auth,
+        ) -> HTTPResult<Auth> {
+            let body = format!("hello, {}", params["name"]);
+            Ok((
+                req,
+                Some(Response::builder().status(200).body(Body::from(body))?),
+                state,
+            ))
+        }
+
+        let app = App::with_state("867-5309".to_string());
+
+        let outcome = HandlerTest::new(compose_handler!(authenticate, greet))
+            .app(app.clone())
+            .request(
+                Request::get("/hello/scarlett")
+                    .header("x-authtoken", "867-5309")
+                    .body(Body::default())
+                    .unwrap(),
+            )
+            .param("name", "scarlett")
+            .run()
+            .await
+            .unwrap();
+
+        outcome.assert_status(StatusCode::OK);
+        assert_eq!(outcome.response().unwrap().text(), "hello, scarlett");
+        assert_eq!(outcome.request().headers()["x-user"], "erik");
+        assert_eq!(outcome.state().user.as_deref(), Some("erik"));
+
+        // a lone middleware leaves the response alone.
+        let outcome = HandlerTest::new(compose_handler!(authenticate))
+            .app(app.clone())
+            .request(
+                Request::get("/")
+                    .header("x-authtoken", "867-5309")
+                    .body(Body::default())
+                    .unwrap(),
+            )
+            .run()
+            .await
+            .unwrap();
+        outcome.assert_no_response();
+
+        match HandlerTest::new(compose_handler!(authenticate, greet))
+            .app(app)
+            .run()
+            .await
+        {
+            Err(Error::StatusCode(status, _)) => assert_eq!(status, StatusCode::UNAUTHORIZED),
+            _ => panic!("expected an error"),
+        }
+
+        // transient state may be provided up front.
+        let (_, resp, state) = HandlerTest::new(compose_handler!(greet))
+            .param("name", "erik")
+            .state(Auth {
+                user: Some("admin".to_string()),
+            })
+            .run()
+            .await
+            .unwrap()
+            .into_parts();
+        assert_eq!(resp.unwrap().text(), "hello, erik");
+        assert_eq!(state.user.as_deref(), Some("admin"));
+    }
+
+    #[tokio::test]
+    async fn test_server() {
+        use super::TestServer;
+        use crate::{
+            app::App,
+            compose_handler,
+            connection::{ConnectionInfo, Transport},
+            HTTPResult, NoState, Params,
+        };
+        use http::{Method, Request, Response, StatusCode};
+        use hyper::Body;
+
+        async fn conn(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            _state: NoState,
+        ) -> HTTPResult<NoState> {
+            let info = ConnectionInfo::from_request(&req).unwrap();
+            let body = Body::from(format!("{:?} {}", info.transport, info.id));
+
+            Ok((
+                req,
+                Some(Response::builder().status(200).body(body)?),
+                NoState {},
+            ))
+        }
+
+        let mut app = App::new();
+        app.get("/conn", compose_handler!(conn)).unwrap();
+
+        async fn check(server: TestServer, transport: Transport) {
+            let client = server.client();
+
+            let first = client.get("/conn").await;
+            first.assert_status(StatusCode::OK);
+            assert!(first.text().starts_with(&format!("{:?} ", transport)));
+
+            // the connection is kept alive between requests.
+            let second = server.request(Method::GET, "/conn").send().await;
+            assert_eq!(first.text(), second.text());
+
+            // the server closes the connection; the client reconnects.
+            let third = client
+                .request(Method::GET, "/conn")
+                .header("connection", "close")
+                .send()
+                .await;
+            assert_eq!(first.text(), third.text());
+            let fourth = client.get("/conn").await;
+            assert_ne!(first.text(), fourth.text());
+
+            client
+                .get("/missing")
+                .await
+                .assert_status(StatusCode::METHOD_NOT_ALLOWED);
+
+            drop(server);
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+            let fresh = super::TestClient::new(client.target.clone());
+            assert!(fresh
+                .send(Request::get("/conn").body(Body::default()).unwrap())
+                .await
+                .is_err());
+        }
+
+        // connections open when the server is dropped are closed.
+        async fn closed_on_drop<S>(server: TestServer, mut stream: S)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+        {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+            stream
+                .write_all(b"GET /conn HTTP/1.1\r\nhost: localhost\r\n\r\n")
+                .await
+                .unwrap();
+            let mut buf = [0; 1024];
+            let read = stream.read(&mut buf).await.unwrap();
+            assert!(buf[..read].starts_with(b"HTTP/1.1 200 OK"));
+
+            drop(server);
+            let read =
+                tokio::time::timeout(std::time::Duration::from_secs(1), stream.read(&mut buf))
+                    .await
+                    .expect("connection was not closed");
+            assert!(matches!(read, Ok(0) | Err(_)));
+        }
+
+        let server = TestServer::start(app.clone()).await.unwrap();
+        let stream = tokio::net::TcpStream::connect(server.addr().unwrap())
+            .await
+            .unwrap();
+        closed_on_drop(server, stream).await;
+
+        let server = TestServer::start(app.clone()).await.unwrap();
+        let addr = server.addr().unwrap();
+        assert!(addr.ip().is_loopback());
+        assert_ne!(addr.port(), 0);
+        assert_eq!(server.url("/conn"), format!("http://{}/conn", addr));
+        check(server, Transport::Tcp).await;
+
+        #[cfg(feature = "tls")]
+        {
+            let server = TestServer::start_tls(app.clone()).await.unwrap();
+            assert!(server.cert_der().is_some());
+            assert!(server.url("/").starts_with("https://localhost:"));
+            check(server, Transport::Tls).await;
+        }
+
+        #[cfg(feature = "unix")]
+        {
+            let server = TestServer::start_unix(app.clone()).await.unwrap();
+            let stream = tokio::net::UnixStream::connect(server.socket_path().unwrap())
+                .await
+                .unwrap();
+            closed_on_drop(server, stream).await;
+
+            let server = TestServer::start_unix(app.clone()).await.unwrap();
+            let path = server.socket_path().unwrap().to_path_buf();
+            assert!(path.exists());
+            assert_eq!(server.addr(), None);
+            check(server, Transport::Unix).await;
+            assert!(!path.exists());
+        }
+    }
+}
@@ -0,0 +1,342 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use http::{Request, StatusCode};
+use hyper::Body;
+use tokio::sync::Notify;
+
+use crate::errors::Error;
+
+#[derive(Debug, Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+/// Cancellation tells handlers that the response to their request is no longer wanted, because
+/// the client disconnected or the request timed out (see [crate::app::App::with_timeout]). It is
+/// inserted in every request's extensions by [crate::app::App::dispatch].
+///
+/// Handlers are dropped at their next `.await` when their request is abandoned, so most need not
+/// check; this is for work which outlives them, such as spawned tasks:
+///
+/// ```ignore
+///     let cancellation = Cancellation::from_request(&req).unwrap();
+///     tokio::spawn(async move {
+///         tokio::select! {
+///             _ = cancellation.cancelled() => {}
+///             _ = expensive_report() => {}
+///         }
+///     });
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Cancellation(Arc<Inner>);
+
+impl Cancellation {
+    /// A cancellation which has not been cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The cancellation of the request.
+    pub fn from_request(req: &Request<Body>) -> Option<Self> {
+        req.extensions().get::<Self>().cloned()
+    }
+
+    /// Whether the request has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Wait until the request is cancelled.
+    pub async fn cancelled(&self) {
+        loop {
+            // register before checking, so that a cancellation in between is not missed.
+            let notified = self.0.notify.notified();
+            if self.is_cancelled() {
+                return;
+            }
+
+            notified.await;
+        }
+    }
+
+    /// Cancel the request.
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::SeqCst);
+        self.0.notify.notify_waiters();
+    }
+
+    // A guard which cancels the request when dropped, unless it is disarmed first.
+    pub(crate) fn guard(&self) -> CancelGuard {
+        CancelGuard(Some(self.clone()))
+    }
+}
+
+pub(crate) struct CancelGuard(Option<Cancellation>);
+
+// TimeoutOverride marks a request whose application timeout has been replaced by a handler's own,
+// set with [crate::handler::Handler::with_timeout]. The router inserts it when the application has
+// a timeout, and stops enforcing that timeout once it is set.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TimeoutOverride(Arc<AtomicBool>);
+
+impl TimeoutOverride {
+    pub(crate) fn set(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn is_set(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+// Cancel the request, if it can be, and produce the error a timed out request is answered with.
+pub(crate) fn timed_out(cancellation: Option<Cancellation>, status: StatusCode) -> Error {
+    if let Some(cancellation) = cancellation {
+        cancellation.cancel();
+    }
+
+    Error::new_status(status, "request timed out")
+}
+
+impl CancelGuard {
+    pub(crate) fn disarm(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        if let Some(cancellation) = self.0.take() {
+            cancellation.cancel();
+        }
+    }
+}
+
+mod tests {
+    #[tokio::test]
+    async fn test_cancellation() {
+        use super::Cancellation;
+        use std::time::Duration;
+
+        let cancellation = Cancellation::new();
+        assert!(!cancellation.is_cancelled());
+
+        let waiter = tokio::spawn({
+            let cancellation = cancellation.clone();
+            async move { cancellation.cancelled().await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!waiter.is_finished());
+
+        cancellation.guard().disarm();
+        assert!(!cancellation.is_cancelled());
+
+        drop(cancellation.guard());
+        assert!(cancellation.is_cancelled());
+        waiter.await.unwrap();
+        // waiting after cancellation returns at once.
+        cancellation.cancelled().await;
+    }
+
+    #[tokio::test]
+    async fn test_timeouts() {
+        use super::Cancellation;
+        use crate::{
+            app::{App, TestApp},
+            auth::BearerAuth,
+            compose_handler, HTTPResult, NoState, Params,
+        };
+        use http::{Method, Request, Response, StatusCode};
+        use hyper::Body;
+        use std::time::Duration;
+        use tokio::sync::mpsc;
+
+        async fn sleep(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            params: Params,
+            app: App<mpsc::UnboundedSender<&'static str>, NoState>,
+            state: NoState,
+        ) -> HTTPResult<NoState> {
+            let cancellation = Cancellation::from_request(&req).unwrap();
+            let tx = app.state().await.unwrap().lock().await.clone();
+            tokio::spawn(async move {
+                cancellation.cancelled().await;
+                tx.send("cancelled").unwrap();
+            });
+
+            let ms: u64 = params["ms"].parse().unwrap();
+            tokio::time::sleep(Duration::from_millis(ms)).await;
+            Ok((
+                req,
+                Some(
+                    Response::builder()
+                        .status(StatusCode::OK)
+                        .body(Body::default())?,
+                ),
+                state,
+            ))
+        }
+
+        async fn pass(
+            req: Request<Body>,
+            resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<mpsc::UnboundedSender<&'static str>, NoState>,
+            state: NoState,
+        ) -> HTTPResult<NoState> {
+            Ok((req, resp, state))
+        }
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut app = App::with_state(tx);
+        app.with_timeout(Duration::from_millis(100));
+        app.get("/sleep/:ms", compose_handler!(sleep)).unwrap();
+        app.get(
+            "/slow/:ms",
+            compose_handler!(sleep).with_timeout(Duration::from_millis(500)),
+        )
+        .unwrap();
+        app.get(
+            "/wrapped/:ms",
+            BearerAuth::with_tokens("test", &[("t0k3n", "test")]).wrap(
+                compose_handler!(pass)
+                    .then(compose_handler!(sleep).with_timeout(Duration::from_millis(500))),
+            ),
+        )
+        .unwrap();
+        let test_app = TestApp::new(app.clone());
+
+        test_app
+            .request(Method::GET, "/sleep/10")
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        assert!(rx.try_recv().is_err());
+
+        test_app
+            .request(Method::GET, "/sleep/5000")
+            .send()
+            .await
+            .assert_status(StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(rx.recv().await, Some("cancelled"));
+
+        // routes may override the timeout.
+        test_app
+            .request(Method::GET, "/slow/200")
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        test_app
+            .request(Method::GET, "/slow/5000")
+            .send()
+            .await
+            .assert_status(StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(rx.recv().await, Some("cancelled"));
+
+        // the override is kept when the chain is wrapped or appended to another.
+        test_app
+            .request(Method::GET, "/wrapped/200")
+            .header("authorization", "Bearer t0k3n")
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        test_app
+            .request(Method::GET, "/wrapped/5000")
+            .header("authorization", "Bearer t0k3n")
+            .send()
+            .await
+            .assert_status(StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(rx.recv().await, Some("cancelled"));
+
+        app.with_timeout_status(StatusCode::GATEWAY_TIMEOUT);
+        TestApp::new(app.clone())
+            .request(Method::GET, "/sleep/5000")
+            .send()
+            .await
+            .assert_status(StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(rx.recv().await, Some("cancelled"));
+
+        // abandoning the request, as a disconnecting client does, cancels it.
+        let abandoned = tokio::time::timeout(
+            Duration::from_millis(20),
+            TestApp::new(app).request(Method::GET, "/slow/5000").send(),
+        )
+        .await;
+        assert!(abandoned.is_err());
+        assert_eq!(rx.recv().await, Some("cancelled"));
+    }
+
+    #[tokio::test]
+    async fn test_disconnect() {
+        use super::Cancellation;
+        use crate::{app::App, compose_handler, testing::TestServer, HTTPResult, NoState, Params};
+        use http::{Request, Response, StatusCode};
+        use hyper::Body;
+        use std::time::Duration;
+        use tokio::{io::AsyncWriteExt, sync::mpsc};
+
+        async fn wait(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            app: App<mpsc::UnboundedSender<&'static str>, NoState>,
+            state: NoState,
+        ) -> HTTPResult<NoState> {
+            let cancellation = Cancellation::from_request(&req).unwrap();
+            let tx = app.state().await.unwrap().lock().await.clone();
+            tx.send("started").unwrap();
+            tokio::spawn(async move {
+                cancellation.cancelled().await;
+                tx.send("cancelled").unwrap();
+            });
+
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            Ok((
+                req,
+                Some(
+                    Response::builder()
+                        .status(StatusCode::OK)
+                        .body(Body::default())?,
+                ),
+                state,
+            ))
+        }
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut app = App::with_state(tx);
+        app.get("/wait", compose_handler!(wait)).unwrap();
+        let server = TestServer::start(app).await.unwrap();
+
+        // a client which closes its connection while the handler runs cancels the request.
+        let mut stream = tokio::net::TcpStream::connect(server.addr().unwrap())
+            .await
+            .unwrap();
+        stream
+            .write_all(b"GET /wait HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        assert_eq!(rx.recv().await, Some("started"));
+        drop(stream);
+
+        let cancelled = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await;
+        assert_eq!(cancelled.unwrap(), Some("cancelled"));
+
+        // a client which waits for its response is not cancelled.
+        let mut stream = tokio::net::TcpStream::connect(server.addr().unwrap())
+            .await
+            .unwrap();
+        stream
+            .write_all(b"GET /wait HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        assert_eq!(rx.recv().await, Some("started"));
+        assert!(tokio::time::timeout(Duration::from_millis(200), rx.recv())
+            .await
+            .is_err());
+    }
+}
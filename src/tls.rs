@@ -0,0 +1,374 @@
+use std::{
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+use ring::signature::{
+    EcdsaKeyPair, Ed25519KeyPair, KeyPair, RsaKeyPair, ECDSA_P256_SHA256_ASN1_SIGNING,
+    ECDSA_P384_SHA384_ASN1_SIGNING,
+};
+use rustls_pemfile::Item;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+
+use crate::errors::ServerError;
+
+// DER encodings of the OIDs we need to re-wrap SEC1 EC keys into PKCS#8.
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const OID_SECP256R1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const OID_SECP384R1: &[u8] = &[0x2b, 0x81, 0x04, 0x00, 0x22];
+
+/// Load a PEM-encoded certificate chain and private key from disk and construct a
+/// [tokio_rustls::rustls::ServerConfig] from them. The certificate file may contain a chain of
+/// certificates, leaf first. The key file may contain a PKCS#8, RSA (PKCS#1) or EC (SEC1) private
+/// key; the key is checked against the leaf certificate before the configuration is returned.
+pub fn load_pem(
+    cert_path: impl AsRef<Path>,
+    key_path: impl AsRef<Path>,
+) -> Result<ServerConfig, ServerError> {
+    let cert_path = cert_path.as_ref().to_path_buf();
+    let key_path = key_path.as_ref().to_path_buf();
+
+    let certs = read_certs(&cert_path)?;
+    let key = read_key(&key_path)?;
+
+    if !key_matches_cert(&key, &certs[0]) {
+        return Err(ServerError(format!(
+            "private key in {} does not match certificate in {}",
+            key_path.display(),
+            cert_path.display()
+        )));
+    }
+
+    server_config(certs, key)
+}
+
+/// Generate a self-signed certificate for the provided subject alternative names (e.g.
+/// `localhost` or `127.0.0.1`) and construct a [tokio_rustls::rustls::ServerConfig] with it.
+/// The DER-encoded certificate is returned alongside the configuration so that clients may be
+/// configured to trust it. This is intended for development and testing.
+pub fn self_signed(names: &[&str]) -> Result<(ServerConfig, Vec<u8>), ServerError> {
+    let cert = rcgen::generate_simple_self_signed(
+        names.iter().map(|s| s.to_string()).collect::<Vec<String>>(),
+    )
+    .map_err(|e| ServerError(format!("could not generate self-signed certificate: {}", e)))?;
+
+    let der = cert
+        .serialize_der()
+        .map_err(|e| ServerError(format!("could not serialize certificate: {}", e)))?;
+    let key = PrivateKey(cert.serialize_private_key_der());
+
+    Ok((server_config(vec![Certificate(der.clone())], key)?, der))
+}
+
+fn server_config(certs: Vec<Certificate>, key: PrivateKey) -> Result<ServerConfig, ServerError> {
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| ServerError(format!("invalid certificate or private key: {}", e)))?;
+
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(config)
+}
+
+fn read_pem(path: &PathBuf) -> Result<Vec<Item>, ServerError> {
+    let f = std::fs::File::open(path)
+        .map_err(|e| ServerError(format!("could not open {}: {}", path.display(), e)))?;
+
+    rustls_pemfile::read_all(&mut BufReader::new(f))
+        .map_err(|e| ServerError(format!("could not parse PEM in {}: {}", path.display(), e)))
+}
+
+fn read_certs(path: &PathBuf) -> Result<Vec<Certificate>, ServerError> {
+    let certs: Vec<Certificate> = read_pem(path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect();
+
+    if certs.is_empty() {
+        return Err(ServerError(format!(
+            "no certificates found in {}",
+            path.display()
+        )));
+    }
+
+    Ok(certs)
+}
+
+fn read_key(path: &PathBuf) -> Result<PrivateKey, ServerError> {
+    for item in read_pem(path)? {
+        match item {
+            Item::PKCS8Key(der) | Item::RSAKey(der) => return Ok(PrivateKey(der)),
+            Item::ECKey(der) => {
+                return sec1_to_pkcs8(&der).map(PrivateKey).ok_or_else(|| {
+                    ServerError(format!(
+                        "unsupported EC private key in {}: only P-256 and P-384 keys which include their public key are supported",
+                        path.display()
+                    ))
+                })
+            }
+            _ => {}
+        }
+    }
+
+    Err(ServerError(format!(
+        "no private key found in {}",
+        path.display()
+    )))
+}
+
+// Derive the public key from the private key and look for it in the certificate's
+// SubjectPublicKeyInfo. The encoded public key is stored verbatim in the certificate for all key
+// types we support, so a byte search is sufficient.
+fn key_matches_cert(key: &PrivateKey, cert: &Certificate) -> bool {
+    let der = key.0.as_slice();
+
+    let public = if let Ok(kp) = RsaKeyPair::from_pkcs8(der).or_else(|_| RsaKeyPair::from_der(der))
+    {
+        kp.public_key().as_ref().to_vec()
+    } else if let Ok(kp) = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, der) {
+        kp.public_key().as_ref().to_vec()
+    } else if let Ok(kp) = EcdsaKeyPair::from_pkcs8(&ECDSA_P384_SHA384_ASN1_SIGNING, der) {
+        kp.public_key().as_ref().to_vec()
+    } else if let Ok(kp) = Ed25519KeyPair::from_pkcs8_maybe_unchecked(der) {
+        kp.public_key().as_ref().to_vec()
+    } else {
+        // let rustls produce the error for keys we cannot interpret.
+        return true;
+    };
+
+    cert.0.windows(public.len()).any(|w| w == public.as_slice())
+}
+
+// Read a single DER TLV from the front of the input, returning the tag, the value and the
+// remaining input.
+fn der_read(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *input.first()?;
+    let first = *input.get(1)? as usize;
+
+    let (len, header) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let count = first & 0x7f;
+        if count == 0 || count > 4 {
+            return None;
+        }
+
+        let mut len = 0usize;
+        for b in input.get(2..2 + count)? {
+            len = (len << 8) | *b as usize;
+        }
+
+        (len, 2 + count)
+    };
+
+    let value = input.get(header..header + len)?;
+    Some((tag, value, &input[header + len..]))
+}
+
+fn der_write(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = value.len();
+
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|b| *b == 0)
+            .collect();
+        out.push(0x80 | bytes.len() as u8);
+        out.extend(bytes);
+    }
+
+    out.extend_from_slice(value);
+    out
+}
+
+// Convert a SEC1 `ECPrivateKey` into the PKCS#8 form rustls accepts. The curve parameters are
+// moved from the inner structure to the algorithm identifier, where PKCS#8 parsers expect them.
+fn sec1_to_pkcs8(sec1: &[u8]) -> Option<Vec<u8>> {
+    let (0x30, body, _) = der_read(sec1)? else {
+        return None;
+    };
+
+    let (0x02, version, rest) = der_read(body)? else {
+        return None;
+    };
+    let (0x04, private, mut rest) = der_read(rest)? else {
+        return None;
+    };
+
+    let mut curve = None;
+    let mut public = None;
+
+    while !rest.is_empty() {
+        let (tag, value, next) = der_read(rest)?;
+        match tag {
+            0xa0 => {
+                let (0x06, oid, _) = der_read(value)? else {
+                    return None;
+                };
+                curve = Some(oid);
+            }
+            0xa1 => public = Some(value),
+            _ => {}
+        }
+        rest = next;
+    }
+
+    let curve = curve?;
+    if curve != OID_SECP256R1 && curve != OID_SECP384R1 {
+        return None;
+    }
+
+    let mut inner = der_write(0x02, version);
+    inner.extend(der_write(0x04, private));
+    inner.extend(der_write(0xa1, public?));
+
+    let mut algorithm = der_write(0x06, OID_EC_PUBLIC_KEY);
+    algorithm.extend(der_write(0x06, curve));
+
+    let mut pkcs8 = der_write(0x02, &[0]);
+    pkcs8.extend(der_write(0x30, &algorithm));
+    pkcs8.extend(der_write(0x04, &der_write(0x30, &inner)));
+
+    Some(der_write(0x30, &pkcs8))
+}
+
+mod tests {
+    #[test]
+    fn test_load_pem() {
+        use super::load_pem;
+
+        for (cert, key) in [
+            ("rsa.crt", "rsa-pkcs8.key"),
+            ("rsa.crt", "rsa-pkcs1.key"),
+            ("ec.crt", "ec.key"),
+        ] {
+            assert!(
+                load_pem(
+                    format!("testdata/tls/{}", cert),
+                    format!("testdata/tls/{}", key)
+                )
+                .is_ok(),
+                "{} / {}",
+                cert,
+                key
+            );
+        }
+
+        let err = load_pem("testdata/tls/rsa.crt", "testdata/tls/ec.key").unwrap_err();
+        assert!(err.0.contains("does not match"), "{}", err);
+
+        let err = load_pem("testdata/tls/ec.crt", "testdata/tls/rsa-pkcs1.key").unwrap_err();
+        assert!(err.0.contains("does not match"), "{}", err);
+
+        let err = load_pem("testdata/tls/missing.crt", "testdata/tls/ec.key").unwrap_err();
+        assert!(err.0.contains("could not open"), "{}", err);
+
+        let err = load_pem("testdata/tls/ec.key", "testdata/tls/ec.key").unwrap_err();
+        assert!(err.0.contains("no certificates found"), "{}", err);
+
+        let err = load_pem("testdata/tls/ec.crt", "testdata/tls/ec.crt").unwrap_err();
+        assert!(err.0.contains("no private key found"), "{}", err);
+    }
+
+    #[test]
+    fn test_self_signed() {
+        let (config, der) = super::self_signed(&["localhost"]).unwrap();
+        assert!(!der.is_empty());
+        assert_eq!(
+            config.alpn_protocols,
+            vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_serve_tls_pem() {
+        use crate::{
+            app::App,
+            compose_handler,
+            connection::{ConnectionInfo, Transport},
+            testing::{exchange, TestServer},
+            HTTPResult, NoState, Params,
+        };
+        use http::{Request, Response};
+        use hyper::Body;
+        use std::{convert::TryFrom, sync::Arc};
+        use tokio_rustls::{
+            rustls::{Certificate, ClientConfig, RootCertStore, ServerName},
+            TlsConnector,
+        };
+
+        async fn hello(
+            req: Request<Body>,
+            _resp: Option<Response<Body>>,
+            _params: Params,
+            _app: App<(), NoState>,
+            _state: NoState,
+        ) -> HTTPResult<NoState> {
+            let info = ConnectionInfo::from_request(&req).unwrap();
+            assert_eq!(info.transport, Transport::Tls);
+            let tls = info.tls.clone().unwrap();
+            assert_eq!(tls.server_name.as_deref(), Some("localhost"));
+            assert!(tls.protocol_version.is_some());
+
+            Ok((
+                req,
+                Some(Response::builder().status(200).body(Body::from("hello"))?),
+                NoState {},
+            ))
+        }
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir().join(format!("davisjr-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("cert.pem"), cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(dir.join("key.pem"), cert.serialize_private_key_pem()).unwrap();
+
+        let mut app = App::new();
+        app.get("/", compose_handler!(hello)).unwrap();
+
+        // serve_tls_pem binds its own address, so load the files as it does and serve them on a
+        // listener which is already bound.
+        let config = super::load_pem(dir.join("cert.pem"), dir.join("key.pem")).unwrap();
+        let server = TestServer::spawn(app, move |app, listener| async move {
+            app.serve_tls_listener(listener, config).await
+        })
+        .await
+        .unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(&Certificate(cert.serialize_der().unwrap()))
+            .unwrap();
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let stream = tokio::net::TcpStream::connect(server.addr().unwrap())
+            .await
+            .unwrap();
+        let mut stream = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+
+        let buf = exchange(
+            &mut stream,
+            b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(buf.starts_with("HTTP/1.1 200 OK"), "{}", buf);
+        assert!(buf.ends_with("hello"), "{}", buf);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}